@@ -16,10 +16,10 @@ echo $username
 near call $CONTRACT_NAME storage_deposit '{"account_id": "leopollum.testnet", "registration_only": false}' --accountId leopollum.testnet --gas 300000000000000 --deposit 4
 
 #### Swaping near to wnear and seending to ref.
-near call $CONTRACT_NAME near_to_wrap '{"account_id": "leopollum.testnet", "receiver_id": "exchange.ref-dev.testnet", "amount": "10000000000000000000000", "msg": ""}' --accountId leopollum.testnet --gas 300000000000000 
+near call $CONTRACT_NAME near_to_wrap '{"receiver_id": "exchange.ref-dev.testnet", "amount": "10000000000000000000000", "msg": ""}' --accountId leopollum.testnet --gas 300000000000000 --deposit 0.000000000000000000000001
 
 #### Swap, add liquidity, save new lp user balance, stake, claim, withdraw
-near call $CONTRACT_NAME add_to_vault '{"account_id": "leopollum.testnet", "vault_contract": "'$CONTRACT_NAME'"}' --accountId leopollum.testnet --gas 300000000000000 --deposit 0.01
+near call $CONTRACT_NAME add_to_vault '{}' --accountId leopollum.testnet --gas 300000000000000 --deposit 0.01
 
 #### Withdraw the farm reward.
 near call $CONTRACT_NAME withdraw_of_reward '{"vault_contract": "'$CONTRACT_NAME'"}' --accountId $CONTRACT_NAME --gas 300000000000000 --deposit 0.000000000000000000000001
@@ -1,7 +1,7 @@
 //! Account deposit is information per user about their balances in the exchange.
 
 use std::collections::HashMap;
-use near_sdk::collections::UnorderedMap;
+use near_sdk::collections::{LookupMap, UnorderedMap};
 use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
@@ -10,6 +10,7 @@ use near_sdk::{
     assert_one_yocto, env, near_bindgen, 
     AccountId, Balance, PromiseResult, StorageUsage,Gas,
 };
+use crate::ledger::{LedgerEntry, LEDGER_ENTRY_STORAGE};
 use crate::token_receiver::{ext_self};
 
 use crate::*;
@@ -28,18 +29,57 @@ const U32_STORAGE: StorageUsage = 4;
 const ACC_ID_STORAGE: StorageUsage = 64;
 /// As a key, 4 bytes length would be added to the head
 const ACC_ID_AS_KEY_STORAGE: StorageUsage = ACC_ID_STORAGE + 4;
-const KEY_PREFIX_ACC: StorageUsage = 64;
 /// As a near_sdk::collection key, 1 byte for prefiex
 const ACC_ID_AS_CLT_KEY_STORAGE: StorageUsage = ACC_ID_AS_KEY_STORAGE + 1;
+/// Every key-value record in the trie costs this many bytes on top of its key and value.
+pub(crate) const STORAGE_RECORD_OVERHEAD: StorageUsage = 40;
+/// Prefix of a collection owned by an account: `StorageKey` variant and the account id.
+const ACC_CLT_PREFIX_STORAGE: StorageUsage = 1 + ACC_ID_AS_KEY_STORAGE;
+/// A collection prefix serialized in its parent, with its 4 bytes length.
+const ACC_CLT_PREFIX_FIELD_STORAGE: StorageUsage = 4 + ACC_CLT_PREFIX_STORAGE;
 
 // ACC_ID: the Contract accounts map key length
 // + VAccount enum: 1 byte
 // + U128_STORAGE: near_amount storage
+// + U128_STORAGE: near_deposit storage
 // + U32_STORAGE: legacy_tokens HashMap length
-// + U32_STORAGE: tokens HashMap length
+// + tokens UnorderedMap: 3 prefixes with their own suffix byte and 2 vector lengths
 // + U64_STORAGE: storage_used
-pub const INIT_ACCOUNT_STORAGE: StorageUsage =
-    ACC_ID_AS_CLT_KEY_STORAGE + 1 + U128_STORAGE + U32_STORAGE + U32_STORAGE + U64_STORAGE;
+// + history LookupMap: its prefix
+// + U64_STORAGE * 2: history bounds
+// + the record overhead
+const ACCOUNT_RECORD_STORAGE: StorageUsage = ACC_ID_AS_CLT_KEY_STORAGE
+    + 1
+    + U128_STORAGE
+    + U128_STORAGE
+    + U32_STORAGE
+    + 3 * (ACC_CLT_PREFIX_FIELD_STORAGE + 1)
+    + 2 * U64_STORAGE
+    + U64_STORAGE
+    + ACC_CLT_PREFIX_FIELD_STORAGE
+    + 2 * U64_STORAGE
+    + STORAGE_RECORD_OVERHEAD;
+
+// Entries the vault keeps for each account outside of its record, keyed by its account id:
+// user_shares: U128_STORAGE
+// user_pnl: 3 * U128_STORAGE + i128
+// pending_deposits: epoch id and U128_STORAGE
+const ACCOUNT_ENTRIES_STORAGE: StorageUsage = (ACC_ID_AS_CLT_KEY_STORAGE + STORAGE_RECORD_OVERHEAD) * 3
+    + U128_STORAGE
+    + 4 * U128_STORAGE
+    + U64_STORAGE + U128_STORAGE;
+
+/// Account record and the entries reserved for its shares, PnL and pending epoch deposit.
+pub const INIT_ACCOUNT_STORAGE: StorageUsage = ACCOUNT_RECORD_STORAGE + ACCOUNT_ENTRIES_STORAGE;
+
+// A token balance is stored in 3 records of the tokens UnorderedMap:
+// key index: prefix + 'i' + token id -> U64_STORAGE
+// keys: prefix + 'k' + U64_STORAGE -> token id
+// values: prefix + 'v' + U64_STORAGE -> U128_STORAGE
+const TOKEN_STORAGE: StorageUsage = (ACC_CLT_PREFIX_STORAGE + 1 + ACC_ID_AS_KEY_STORAGE + U64_STORAGE)
+    + (ACC_CLT_PREFIX_STORAGE + 1 + U64_STORAGE + ACC_ID_AS_KEY_STORAGE)
+    + (ACC_CLT_PREFIX_STORAGE + 1 + U64_STORAGE + U128_STORAGE)
+    + 3 * STORAGE_RECORD_OVERHEAD;
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum VAccount {
@@ -51,7 +91,7 @@ pub enum VAccount {
 
 impl VAccount {
     /// Upgrades from other versions to the currently used version.
-    pub fn into_current(self, account_id: &AccountId) -> Account {
+    pub fn into_current(self, _account_id: &AccountId) -> Account {
         match self {
             VAccount::Current(account) => account,
             //VAccount::V1(account) => account.into_current(account_id),
@@ -70,33 +110,44 @@ impl From<Account> for VAccount {
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct Account {
     /// Native NEAR amount sent to the exchange.
-    /// Used for storage right now, but in future can be used for trading as well.
+    /// Reserved for storage only.
     pub near_amount: Balance,
+    /// Native NEAR the user deposited to invest in the vault.
+    pub near_deposit: Balance,
     /// Amounts of various tokens deposited to this account.
     pub legacy_tokens: HashMap<AccountId, Balance>,
     pub tokens: UnorderedMap<AccountId, Balance>,
     pub storage_used: StorageUsage,
+    /// Ledger of this account's vault operations, by entry id.
+    pub history: LookupMap<u64, LedgerEntry>,
+    /// Id of the oldest entry kept and of the next entry.
+    pub history_start: u64,
+    pub history_end: u64,
 }
 
 impl Account {
     pub fn new(account_id: &AccountId) -> Self {
         Account {
             near_amount: 0,
+            near_deposit: 0,
             legacy_tokens: HashMap::new(),
             tokens: UnorderedMap::new(StorageKey::AccountTokens {
                 account_id: account_id.clone(),
             }),
             storage_used: 0,
+            history: LookupMap::new(StorageKey::AccountHistory {
+                account_id: account_id.clone(),
+            }),
+            history_start: 0,
+            history_end: 0,
         }
     }
 
     pub fn get_balance(&self, token_id: &AccountId) -> Option<Balance> {
         if let Some(token_balance) = self.tokens.get(token_id) {
             Some(token_balance)
-        } else if let Some(legacy_token_balance) = self.legacy_tokens.get(token_id) {
-            Some(*legacy_token_balance)
         } else {
-            None
+            self.legacy_tokens.get(token_id).copied()
         }
     }
 
@@ -112,53 +163,58 @@ impl Account {
 
     /// Deposit amount to the balance of given token,
     /// if given token not register and not enough storage, deposit fails 
-    pub(crate) fn deposit_with_storage_check(&mut self, token: &AccountId, amount: Balance) -> bool { 
+    pub(crate) fn deposit_with_storage_check(&mut self, token: &AccountId, amount: Balance) -> Result<bool, VaultError> { 
         if let Some(balance) = self.tokens.get(token) {
             // token has been registered, just add without storage check, 
-            let new_balance = balance + amount;
+            let new_balance = checked_add(balance, amount)?;
             self.tokens.insert(token, &new_balance);
-            true
+            Ok(true)
         } else if let Some(x) = self.legacy_tokens.get_mut(token) {
             // token has been registered, just add without storage check
-            *x += amount;
-            true
+            *x = checked_add(*x, amount)?;
+            Ok(true)
         } else {
             // check storage after insert, if fail should unregister the token
             self.tokens.insert(token, &(amount));
             if self.storage_usage() <= self.near_amount {
-                true
+                Ok(true)
             } else {
                 self.tokens.remove(token);
-                false
+                Ok(false)
             }
         }
     }
 
     /// Deposit amount to the balance of given token.
-    pub(crate) fn deposit(&mut self, token: &AccountId, amount: Balance) {
-        if let Some(x) = self.legacy_tokens.remove(token) {
-            // need convert to tokens
-            self.tokens.insert(token, &(amount + x));
-        } else if let Some(x) = self.tokens.get(token) {
-            self.tokens.insert(token, &(amount + x));
-        } else {
-            self.tokens.insert(token, &amount);
-        }
+    pub(crate) fn deposit(&mut self, token: &AccountId, amount: Balance) -> Result<(), VaultError> {
+        let balance = checked_add(self.get_balance(token).unwrap_or(0), amount)?;
+        // legacy balances are converted to tokens
+        self.legacy_tokens.remove(token);
+        self.tokens.insert(token, &balance);
+        Ok(())
     }
 
     /// Withdraw amount of `token` from the internal balance.
-    /// Panics if `amount` is bigger than the current balance.
-    pub(crate) fn withdraw(&mut self, token: &AccountId, amount: Balance) {
-        if let Some(x) = self.legacy_tokens.remove(token) {
-            // need convert to 
-            assert!(x >= amount, "{}", "E22: not enough tokens in deposit");
-            self.tokens.insert(token, &(x - amount));
-        } else if let Some(x) = self.tokens.get(token) {
-            assert!(x >= amount, "{}", "E22: not enough tokens in deposit");
-            self.tokens.insert(token, &(x - amount));
-        } else {
-            env::panic("E21: token not registered".as_bytes());
-        }
+    /// Fails if `amount` is bigger than the current balance.
+    pub(crate) fn withdraw(&mut self, token: &AccountId, amount: Balance) -> Result<(), VaultError> {
+        let balance = self.get_balance(token).ok_or(VaultError::TokenNotRegistered)?;
+        let balance = checked_sub(balance, amount).map_err(|_| VaultError::NotEnoughTokens)?;
+        self.legacy_tokens.remove(token);
+        self.tokens.insert(token, &balance);
+        Ok(())
+    }
+
+    /// Adds `amount` of native NEAR to the investable balance.
+    pub(crate) fn deposit_near(&mut self, amount: Balance) -> Result<(), VaultError> {
+        self.near_deposit = checked_add(self.near_deposit, amount)?;
+        Ok(())
+    }
+
+    /// Withdraw `amount` of native NEAR from the investable balance.
+    /// Fails if `amount` is bigger than the current balance.
+    pub(crate) fn withdraw_near(&mut self, amount: Balance) -> Result<(), VaultError> {
+        self.near_deposit = checked_sub(self.near_deposit, amount).map_err(|_| VaultError::NotEnoughNear)?;
+        Ok(())
     }
 
     // [AUDIT_01]
@@ -166,7 +222,8 @@ impl Account {
     pub fn storage_usage(&self) -> Balance {
         (INIT_ACCOUNT_STORAGE + 
             self.legacy_tokens.len() as u64 * (ACC_ID_AS_KEY_STORAGE + U128_STORAGE) + 
-            self.tokens.len() as u64 * (KEY_PREFIX_ACC + ACC_ID_AS_KEY_STORAGE + U128_STORAGE)
+            self.tokens.len() * TOKEN_STORAGE +
+            (self.history_end - self.history_start) * LEDGER_ENTRY_STORAGE
         ) as u128
             * env::storage_byte_cost()
     }
@@ -175,19 +232,14 @@ impl Account {
     pub fn storage_available(&self) -> Balance {
         // [AUDIT_01] avoid math overflow
         let locked = self.storage_usage();
-        if self.near_amount > locked {
-            self.near_amount - locked
-        } else {
-            0
-        }
+        self.near_amount.saturating_sub(locked)
     }
 
     /// Asserts there is sufficient amount of $NEAR to cover storage usage.
     pub fn assert_storage_usage(&self) {
         assert!(
             self.storage_usage() <= self.near_amount,
-            "{}",
-            "E11: insufficient $NEAR storage deposit"
+            "{}", VaultError::InsufficientStorage
         );
     }
 
@@ -207,12 +259,14 @@ impl Account {
     }
 
     /// Unregisters `token_id` from this account balance.
-    /// Panics if the `token_id` balance is not 0.
-    pub(crate) fn unregister(&mut self, token_id: &AccountId) {
-        let amount = self.legacy_tokens.remove(token_id).unwrap_or_default();
-        assert_eq!(amount, 0, "{}", "E24: non-zero token balance");
-        let amount = self.tokens.remove(token_id).unwrap_or_default();
-        assert_eq!(amount, 0, "{}", "E24: non-zero token balance");
+    /// Fails if the `token_id` balance is not 0.
+    pub(crate) fn unregister(&mut self, token_id: &AccountId) -> Result<(), VaultError> {
+        if self.get_balance(token_id).unwrap_or(0) != 0 {
+            return Err(VaultError::NonZeroTokenBalance);
+        }
+        self.legacy_tokens.remove(token_id);
+        self.tokens.remove(token_id);
+        Ok(())
     }
 }
 
@@ -239,7 +293,7 @@ impl Contract {
         let sender_id = env::predecessor_account_id();
         let mut account = self.internal_unwrap_account(&sender_id);
         for token_id in token_ids {
-            account.unregister(token_id.as_ref());
+            account.unregister(token_id.as_ref()).or_panic();
         }
         self.internal_save_account(&sender_id, account);
     }
@@ -258,18 +312,57 @@ impl Contract {
         self.assert_contract_running();
         let token_id: AccountId = token_id.into();
         let amount: u128 = amount.into();
-        assert!(amount > 0, "{}", "E29: Illegal withdraw amount");
+        assert!(amount > 0, "{}", VaultError::IllegalWithdrawAmount);
         let sender_id = env::predecessor_account_id();
         let mut account = self.internal_unwrap_account(&sender_id);
         // Note: subtraction and deregistration will be reverted if the promise fails.
-        account.withdraw(&token_id, amount);
+        account.withdraw(&token_id, amount).or_panic();
         if unregister == Some(true) {
-            account.unregister(&token_id);
+            account.unregister(&token_id).or_panic();
         }
         self.internal_save_account(&sender_id, account);
+        self.internal_track_withdraw(&token_id, amount);
         self.internal_send_tokens(&sender_id, &token_id, amount)
     }
 
+    /// Deposits the attached NEAR to the caller's investable balance.
+    /// This balance is separate from the storage deposit.
+    #[payable]
+    pub fn deposit_near(&mut self) -> U128 {
+        self.assert_contract_running();
+        let amount = env::attached_deposit();
+        assert!(amount > 0, "{}", VaultError::ZeroAmount);
+        let sender_id = env::predecessor_account_id();
+        let mut account = self.internal_get_account(&sender_id).unwrap_or_else(|| VaultError::AccountNotRegistered.panic());
+        account.deposit_near(amount).or_panic();
+        let near_deposit = account.near_deposit;
+        self.internal_save_account(&sender_id, account);
+        U128(near_deposit)
+    }
+
+    /// Withdraws `amount` of the caller's investable NEAR, all of it if not given.
+    #[payable]
+    pub fn withdraw_near(&mut self, amount: Option<U128>) -> Promise {
+        assert_one_yocto();
+        self.assert_contract_running();
+        let sender_id = env::predecessor_account_id();
+        let mut account = self.internal_unwrap_account(&sender_id);
+        let amount = amount.map(|a| a.0).unwrap_or(account.near_deposit);
+        assert!(amount > 0, "{}", VaultError::IllegalWithdrawAmount);
+        account.withdraw_near(amount).or_panic();
+        self.internal_save_account(&sender_id, account);
+        Promise::new(sender_id).transfer(amount)
+    }
+
+    /// Returns the investable NEAR balance of given account.
+    pub fn get_near_deposit(&self, account_id: ValidAccountId) -> U128 {
+        U128(
+            self.internal_get_account(account_id.as_ref())
+                .map(|account| account.near_deposit)
+                .unwrap_or(0),
+        )
+    }
+
     #[private]
     pub fn exchange_callback_post_withdraw(
         &mut self,
@@ -280,46 +373,15 @@ impl Contract {
         assert_eq!(
             env::promise_results_count(),
             1,
-            "{}",
-            "E25: expected 1 promise result from withdraw"
+            "{}", VaultError::CallbackPostWithdrawInvalid
         );
+        self.internal_untrack_in_flight(&token_id, amount.0);
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
             PromiseResult::Successful(_) => {}
             PromiseResult::Failed => {
                 // This reverts the changes from withdraw function.
-                // If account doesn't exit, deposits to the owner's account as lostfound.
-                let mut failed = false;
-                if let Some(mut account) = self.internal_get_account(&sender_id) {
-                    if account.deposit_with_storage_check(&token_id, amount.0) {
-                        // cause storage already checked, here can directly save
-                        self.accounts.insert(&sender_id, &account.into());
-                    } else {
-                        // we can ensure that internal_get_account here would NOT cause a version upgrade, 
-                        // cause it is callback, the account must be the current version or non-exist,
-                        // so, here we can just leave it without insert, won't cause storage collection inconsistency.
-                        env::log(
-                            format!(
-                                "Account {} has not enough storage. Depositing to owner.",
-                                sender_id
-                            )
-                            .as_bytes(),
-                        );
-                        failed = true;
-                    }
-                } else {
-                    env::log(
-                        format!(
-                            "Account {} is not registered. Depositing to owner.",
-                            sender_id
-                        )
-                        .as_bytes(),
-                    );
-                    failed = true;
-                }
-                if failed {
-                    self.internal_lostfound(&token_id, amount.0);
-                }
+                self.internal_credit_or_lostfound(&sender_id, &token_id, amount.0);
             }
         };
     }
@@ -331,50 +393,89 @@ impl Contract {
     /// This should be only place to directly use `self.accounts`.
     pub(crate) fn internal_save_account(&mut self, account_id: &AccountId, account: Account) {
         account.assert_storage_usage();
-        self.accounts.insert(&account_id, &account.into());
+        self.accounts.insert(account_id, &account.into());
     }
 
-    /// save token to owner account as lostfound, no need to care about storage
-    /// only global whitelisted token can be stored in lost-found
-    pub(crate) fn internal_lostfound(&mut self, token_id: &AccountId, amount: u128) {
-        if self.whitelisted_tokens.contains(token_id) {
-            let mut lostfound = self.internal_unwrap_or_default_account(&self.owner_id);
-            lostfound.deposit(token_id, amount);
-            self.accounts.insert(&self.owner_id, &lostfound.into());
+    /// Credits `amount` of `token_id` to the account's deposit.
+    /// If account doesn't exist or lacks storage, deposits to lostfound.
+    pub(crate) fn internal_credit_or_lostfound(&mut self, sender_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let mut failed = false;
+        if let Some(mut account) = self.internal_get_account(sender_id) {
+            if account.deposit_with_storage_check(token_id, amount).or_panic() {
+                // cause storage already checked, here can directly save
+                self.accounts.insert(sender_id, &account.into());
+                self.internal_track_deposit(token_id, amount);
+            } else {
+                // we can ensure that internal_get_account here would NOT cause a version upgrade, 
+                // cause it is callback, the account must be the current version or non-exist,
+                // so, here we can just leave it without insert, won't cause storage collection inconsistency.
+                env::log(
+                    format!(
+                        "Account {} has not enough storage. Depositing to lostfound.",
+                        sender_id
+                    )
+                    .as_bytes(),
+                );
+                failed = true;
+            }
         } else {
-            env::panic("ERR: non-whitelisted token can NOT deposit into lost-found.".as_bytes());
+            env::log(
+                format!(
+                    "Account {} is not registered. Depositing to lostfound.",
+                    sender_id
+                )
+                .as_bytes(),
+            );
+            failed = true;
+        }
+        if failed {
+            self.internal_lostfound(sender_id, token_id, amount);
         }
-        
     }
-    
 
     /// Registers account in deposited amounts with given amount of $NEAR.
     /// If account already exists, adds amount to it.
     /// This should be used when it's known that storage is prepaid.
     pub(crate) fn internal_register_account(&mut self, account_id: &AccountId, amount: Balance) {
-        let mut account = self.internal_unwrap_or_default_account(&account_id);
-        account.near_amount += amount;
-        self.internal_save_account(&account_id, account);
+        let mut account = self.internal_unwrap_or_default_account(account_id);
+        account.near_amount = checked_add(account.near_amount, amount).or_panic();
+        self.internal_save_account(account_id, account);
+    }
+
+    /// Spends `amount` of the account's investable NEAR.
+    pub(crate) fn internal_withdraw_near(&mut self, account_id: &AccountId, amount: Balance) {
+        let mut account = self.internal_unwrap_account(account_id);
+        account.withdraw_near(amount).or_panic();
+        self.internal_save_account(account_id, account);
     }
 
-    pub(crate) fn internal_register_account_sub(&mut self, account_id: &AccountId, amount: Balance) {
-        let mut account = self.internal_unwrap_or_default_account(&account_id);
-        account.near_amount -= amount;
-        self.internal_save_account(&account_id, account);
+    /// Credits `amount` of NEAR back to the account's investable balance.
+    /// If account doesn't exist, registers it with `amount` as storage deposit,
+    /// or keeps `amount` in lostfound if it is below the minimum storage.
+    pub(crate) fn internal_credit_near(&mut self, account_id: &AccountId, amount: Balance) {
+        if let Some(mut account) = self.internal_get_account(account_id) {
+            account.deposit_near(amount).or_panic();
+            self.internal_save_account(account_id, account);
+        } else if amount >= Account::min_storage_usage() {
+            self.internal_register_account(account_id, amount);
+        } else {
+            log!("Account {} is not registered. Keeping {} NEAR in lostfound.", account_id, amount);
+            self.internal_lostfound_near(account_id, amount);
+        }
     }
 
     /// storage withdraw
     pub(crate) fn internal_storage_withdraw(&mut self, account_id: &AccountId, amount: Balance) -> u128 {
-        let mut account = self.internal_unwrap_account(&account_id);
+        let mut account = self.internal_unwrap_account(account_id);
         let available = account.storage_available();
-        assert!(available > 0, "ERR_NO_STORAGE_CAN_WITHDRAW");
+        assert!(available > 0, "{}", VaultError::NoStorageToWithdraw);
         let mut withdraw_amount = amount;
         if amount == 0 {
             withdraw_amount = available;
         }
-        assert!(withdraw_amount <= available, "ERR_STORAGE_WITHDRAW_TOO_MUCH");
-        account.near_amount -= withdraw_amount;
-        self.internal_save_account(&account_id, account);
+        assert!(withdraw_amount <= available, "{}", VaultError::StorageWithdrawTooMuch);
+        account.near_amount = checked_sub(account.near_amount, withdraw_amount).or_panic();
+        self.internal_save_account(account_id, account);
         withdraw_amount
     }
 
@@ -390,11 +491,11 @@ impl Contract {
         assert!(
             self.whitelisted_tokens.contains(token_id) 
                 || account.get_balance(token_id).is_some(),
-            "{}",
-            "E12: token not whitelisted"
+            "{}", VaultError::TokenNotWhitelisted
         );
-        account.deposit(token_id, amount);
-        self.internal_save_account(&sender_id, account);
+        account.deposit(token_id, amount).or_panic();
+        self.internal_save_account(sender_id, account);
+        self.internal_track_deposit(token_id, amount);
     }
 
     pub fn internal_get_account(&self, account_id: &AccountId) -> Option<Account> {
@@ -405,7 +506,7 @@ impl Contract {
 
     pub fn internal_unwrap_account(&self, account_id: &AccountId) -> Account {
         self.internal_get_account(account_id)
-            .expect("E27: attach 1yN to swap tokens not in whitelist")
+            .unwrap_or_else(|| VaultError::AccountNotRegistered.panic())
     }
 
     pub fn internal_unwrap_or_default_account(&self, account_id: &AccountId) -> Account {
@@ -427,11 +528,12 @@ impl Contract {
     /// Sends given amount to given user and if it fails, returns it back to user's balance.
     /// Tokens must already be subtracted from internal balance.
     pub(crate) fn internal_send_tokens(
-        &self,
+        &mut self,
         sender_id: &AccountId,
         token_id: &AccountId,
         amount: Balance,
     ) -> Promise {
+        self.internal_track_in_flight(token_id, amount);
         ext_fungible_token::ft_transfer(
             sender_id.clone(),
             U128(amount),
@@ -0,0 +1,63 @@
+//! Deposit caps.
+//!
+//! `max_total_assets` bounds the LP owned by the vault and `max_user_assets` the LP owned
+//! by a single account, both unlimited by default. Deposit entry points refuse new deposits
+//! once a cap is reached, and a zap that would go over it is refunded when its LP is known.
+//! `deposit_limits` bounds the amount of a token accepted in a single deposit.
+
+use crate::*;
+
+/// Deposit caps as set by the owner, None for unlimited.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct DepositCaps {
+    pub max_total_assets: Option<U128>,
+    pub max_user_assets: Option<U128>,
+    pub deposit_limits: HashMap<AccountId, U128>,
+}
+
+fn cap_to_option(cap: Balance) -> Option<U128> {
+    if cap == Balance::MAX {
+        None
+    } else {
+        Some(U128(cap))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// LP given account may still add to the vault.
+    pub fn max_deposit(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.internal_max_deposit(account_id.as_ref()))
+    }
+
+    pub fn get_deposit_caps(&self) -> DepositCaps {
+        DepositCaps {
+            max_total_assets: cap_to_option(self.max_total_assets),
+            max_user_assets: cap_to_option(self.max_user_assets),
+            deposit_limits: self
+                .deposit_limits
+                .iter()
+                .map(|(token_id, limit)| (token_id, U128(limit)))
+                .collect(),
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_max_deposit(&self, account_id: &AccountId) -> Balance {
+        let user_lp = self.convert_to_assets(self.user_shares.get(account_id).unwrap_or(0));
+        let total_room = self.max_total_assets.saturating_sub(self.total_lp);
+        let user_room = self.max_user_assets.saturating_sub(user_lp);
+        std::cmp::min(total_room, user_room)
+    }
+
+    /// Asserts `account_id` may deposit `amount` of `token_id`.
+    pub(crate) fn assert_deposit_allowed(&self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        if let Some(limit) = self.deposit_limits.get(token_id) {
+            assert!(amount <= limit, "{}", VaultError::DepositLimitExceeded);
+        }
+        assert!(self.internal_max_deposit(account_id) > 0, "{}", VaultError::DepositCapReached);
+    }
+}
@@ -0,0 +1,195 @@
+//! Council proposals.
+//!
+//! Besides the owner, a council of accounts can change the configuration of the vault.
+//! A member proposes an `AdminAction`, which is queued behind the timelock once
+//! `council_threshold` members approved it and one of them calls `execute`. Proposals
+//! expire after `proposal_period`. Upgrading the contract code is only possible through
+//! the council, and once a council is set the owner can only pause or resume the contract.
+//! The proposer pays for the storage of its proposal and gets it back when the proposal
+//! is executed or removed after expiring.
+
+use near_sdk::json_types::U64;
+
+use crate::owner::AdminAction;
+use crate::utils::sec_to_nanos;
+use crate::*;
+
+/// Pending change of the configuration.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Proposal {
+    pub proposer: AccountId,
+    pub action: AdminAction,
+    /// Members that approved, the proposer included.
+    pub approvals: Vec<AccountId>,
+    /// Block timestamp after which the proposal can't be approved or executed.
+    pub expires_at: u64,
+    /// NEAR paid by the proposer for the storage of the proposal.
+    pub storage_deposit: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct ProposalInfo {
+    pub id: u64,
+    pub proposer: AccountId,
+    pub action: AdminAction,
+    pub approvals: Vec<AccountId>,
+    pub expires_at: U64,
+    pub expired: bool,
+    /// Whether enough current members approved for the proposal to be executed.
+    pub approved: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct CouncilInfo {
+    pub members: Vec<AccountId>,
+    pub threshold: u32,
+    /// Lifetime of a proposal, in seconds.
+    pub proposal_period: u64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Replaces the council. `threshold` approvals are needed to execute a proposal,
+    /// which expires `proposal_period_sec` seconds after being made.
+    /// An empty council with a threshold of 0 disables proposals. Goes through the timelock.
+    /// The owner only sets the first council, later ones are proposed by the council.
+    pub fn set_council(&mut self, members: Vec<ValidAccountId>, threshold: u32, proposal_period_sec: u64) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetCouncil { members, threshold, proposal_period_sec })
+    }
+
+    /// Proposes `action` and approves it on behalf of the caller, a council member.
+    /// The attached deposit pays for the storage of the proposal, the rest is refunded.
+    #[payable]
+    pub fn propose(&mut self, action: AdminAction) -> u64 {
+        let account_id = env::predecessor_account_id();
+        self.assert_council_member(&account_id);
+        let prev_storage = env::storage_usage();
+        let id = self.proposal_count;
+        let mut proposal = Proposal {
+            proposer: account_id.clone(),
+            action,
+            approvals: vec![account_id.clone()],
+            expires_at: env::block_timestamp().saturating_add(self.proposal_period),
+            storage_deposit: 0,
+        };
+        self.proposals.insert(&id, &proposal);
+        let storage_deposit = (env::storage_usage() - prev_storage) as Balance * env::storage_byte_cost();
+        assert!(
+            env::attached_deposit() >= storage_deposit,
+            "{}",
+            VaultError::InsufficientStorage
+        );
+        proposal.storage_deposit = storage_deposit;
+        self.proposals.insert(&id, &proposal);
+        self.proposal_count += 1;
+        let refund = env::attached_deposit() - storage_deposit;
+        if refund > 0 {
+            Promise::new(account_id.clone()).transfer(refund);
+        }
+        log!("{} made proposal {}", account_id, id);
+        id
+    }
+
+    pub fn approve(&mut self, id: u64) {
+        let account_id = env::predecessor_account_id();
+        self.assert_council_member(&account_id);
+        let mut proposal = self.proposals.get(&id).unwrap_or_else(|| VaultError::NoProposal.panic());
+        assert!(env::block_timestamp() < proposal.expires_at, "{}", VaultError::ProposalExpired);
+        assert!(!proposal.approvals.contains(&account_id), "{}", VaultError::AlreadyApproved);
+        proposal.approvals.push(account_id.clone());
+        self.proposals.insert(&id, &proposal);
+        log!("{} approved proposal {}", account_id, id);
+    }
+
+    /// Queues the action of an approved proposal behind the timelock and returns the id
+    /// of the queued change, or applies it right away if it pauses or resumes the contract.
+    /// Only callable by a council member.
+    pub fn execute(&mut self, id: u64) -> Option<u64> {
+        let account_id = env::predecessor_account_id();
+        self.assert_council_member(&account_id);
+        let proposal = self.proposals.get(&id).unwrap_or_else(|| VaultError::NoProposal.panic());
+        assert!(env::block_timestamp() < proposal.expires_at, "{}", VaultError::ProposalExpired);
+        assert!(self.internal_is_approved(&proposal), "{}", VaultError::NotEnoughApprovals);
+        let action = self.internal_remove_proposal(id, proposal);
+        log!("{} executed proposal {}", account_id, id);
+        self.internal_submit_action(action, true)
+    }
+
+    /// Removes an expired proposal and refunds its storage to the proposer.
+    pub fn remove_proposal(&mut self, id: u64) {
+        let proposal = self.proposals.get(&id).unwrap_or_else(|| VaultError::NoProposal.panic());
+        assert!(env::block_timestamp() >= proposal.expires_at, "{}", VaultError::ProposalNotExpired);
+        self.internal_remove_proposal(id, proposal);
+    }
+
+    pub fn get_council(&self) -> CouncilInfo {
+        CouncilInfo {
+            members: self.council.to_vec(),
+            threshold: self.council_threshold,
+            proposal_period: self.proposal_period / 1_000_000_000,
+        }
+    }
+
+    pub fn get_proposal(&self, id: u64) -> Option<ProposalInfo> {
+        self.proposals.get(&id).map(|proposal| self.internal_proposal_info(id, proposal))
+    }
+
+    /// Proposals not yet executed or removed, with ids in `[from_index, from_index + limit)`.
+    pub fn get_proposals(&self, from_index: u64, limit: u64) -> Vec<ProposalInfo> {
+        (from_index..std::cmp::min(from_index.saturating_add(limit), self.proposal_count))
+            .filter_map(|id| self.get_proposal(id))
+            .collect()
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_council(&mut self, members: Vec<ValidAccountId>, threshold: u32, proposal_period_sec: u64) {
+        assert!(threshold as usize <= members.len(), "{}", VaultError::ThresholdTooHigh);
+        assert!(threshold > 0 || members.is_empty(), "{}", VaultError::ThresholdZero);
+        self.council.clear();
+        for member in members {
+            self.council.insert(member.as_ref());
+        }
+        self.council_threshold = threshold;
+        self.proposal_period = sec_to_nanos(proposal_period_sec).or_panic();
+    }
+
+    fn assert_council_member(&self, account_id: &AccountId) {
+        assert!(self.council.contains(account_id), "{}", VaultError::NotCouncilMember);
+    }
+
+    /// Approvals of members that are still in the council reach the threshold.
+    fn internal_is_approved(&self, proposal: &Proposal) -> bool {
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|account_id| self.council.contains(account_id))
+            .count();
+        self.council_threshold > 0 && approvals >= self.council_threshold as usize
+    }
+
+    fn internal_remove_proposal(&mut self, id: u64, proposal: Proposal) -> AdminAction {
+        self.proposals.remove(&id);
+        if proposal.storage_deposit > 0 {
+            Promise::new(proposal.proposer).transfer(proposal.storage_deposit);
+        }
+        proposal.action
+    }
+
+    fn internal_proposal_info(&self, id: u64, proposal: Proposal) -> ProposalInfo {
+        ProposalInfo {
+            id,
+            approved: self.internal_is_approved(&proposal),
+            expired: env::block_timestamp() >= proposal.expires_at,
+            expires_at: U64(proposal.expires_at),
+            proposer: proposal.proposer,
+            action: proposal.action,
+            approvals: proposal.approvals,
+        }
+    }
+}
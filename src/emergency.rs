@@ -0,0 +1,404 @@
+//! Emergency shutdown.
+//!
+//! A guardian can call `emergency_exit` if the exchange or the farm misbehaves. The vault
+//! then unstakes all its LP, removes the liquidity and withdraws the pool tokens from the
+//! exchange into its own wallet, along with the harvested rewards and the zap dust it keeps
+//! there. From that point deposits, withdrawals and harvesting are disabled. Once every
+//! token reached the vault, each depositor claims, with `claim_emergency`, its pro rata
+//! share of them into its vault balance, to be withdrawn with `withdraw`.
+//! Calling `emergency_exit` again retries whatever failed, such as a token withdraw.
+//! Withdrawals still waiting in the withdraw queue get their shares back to claim like
+//! everyone else, a batch per `emergency_exit` call. Deposits waiting for their epoch to
+//! settle are given back as wNEAR with `refund_epoch_deposit`.
+
+use std::collections::HashMap;
+
+use near_sdk::Gas;
+
+use crate::harvest::{REWARD_TOKEN, SEED_ID};
+use crate::ledger::LedgerKind;
+use crate::utils::{mul_div, promise_result_as};
+use crate::zap::{POOL_ID, POOL_TOKENS};
+use crate::*;
+
+const GAS_FOR_LIST_SEEDS: Gas = 10_000_000_000_000;
+const GAS_FOR_EMERGENCY_UNSTAKE: Gas = 270_000_000_000_000;
+const GAS_FOR_WITHDRAW_SEED: Gas = 60_000_000_000_000;
+const GAS_FOR_EMERGENCY_UNSTAKED: Gas = 200_000_000_000_000;
+const GAS_FOR_GET_POOL_SHARES: Gas = 5_000_000_000_000;
+const GAS_FOR_EMERGENCY_REMOVE: Gas = 190_000_000_000_000;
+const GAS_FOR_REMOVE_LIQUIDITY: Gas = 10_000_000_000_000;
+const GAS_FOR_EMERGENCY_WITHDRAW: Gas = 165_000_000_000_000;
+const GAS_FOR_EXCHANGE_WITHDRAW: Gas = 25_000_000_000_000;
+const GAS_FOR_EMERGENCY_RECEIVED: Gas = 10_000_000_000_000;
+
+/// Queued withdrawals given their shares back per `emergency_exit` call.
+const MAX_REQUEUE_PER_CALL: u64 = 50;
+
+/// Tokens held by the vault after an emergency exit and the shares that can claim them.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct EmergencyState {
+    /// Block timestamp of the first `emergency_exit` call.
+    pub started_at: u64,
+    /// Tokens handed out to the depositors: the pool tokens, then the reward token.
+    pub tokens: Vec<AccountId>,
+    /// Tokens in the vault's wallet not yet claimed, in the order of `tokens`.
+    pub amounts: Vec<Balance>,
+    /// Tokens still to withdraw from the exchange.
+    pub on_exchange: Vec<Balance>,
+    /// Tokens whose withdraw from the exchange is in flight.
+    pub withdrawing: Vec<Balance>,
+    /// Whether a `remove_liquidity` of the exit is in flight.
+    pub removing: bool,
+    /// Whether the vault's LP was removed from the strategy pool.
+    pub lp_removed: bool,
+    /// Vault shares not yet claimed.
+    pub shares: Balance,
+}
+
+impl EmergencyState {
+    /// Whether every token of the exit reached the vault's wallet.
+    pub fn is_complete(&self) -> bool {
+        self.lp_removed
+            && !self.removing
+            && self.on_exchange.iter().chain(self.withdrawing.iter()).all(|amount| *amount == 0)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct EmergencyInfo {
+    pub started_at: u64,
+    pub amounts: HashMap<AccountId, U128>,
+    /// Tokens not yet withdrawn from the exchange, including withdraws in flight.
+    pub on_exchange: HashMap<AccountId, U128>,
+    pub shares: U128,
+    /// Whether the depositors can claim their share.
+    pub claimable: bool,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Unstakes all LP, removes the liquidity and withdraws the pool tokens, the harvested
+    /// rewards and the zap dust into the vault. Only callable by a guardian or the owner.
+    /// Can be called again to retry a step that failed.
+    pub fn emergency_exit(&mut self) -> Promise {
+        self.assert_guardian();
+        if self.emergency.is_none() {
+            log!("Emergency exit, {} shares can claim the pool tokens", self.total_shares);
+            let tokens: Vec<AccountId> = POOL_TOKENS
+                .iter()
+                .chain([REWARD_TOKEN].iter().filter(|token| !POOL_TOKENS.contains(token)))
+                .map(|token| token.to_string())
+                .collect();
+            self.emergency = Some(EmergencyState {
+                started_at: env::block_timestamp(),
+                amounts: vec![0; tokens.len()],
+                on_exchange: vec![0; tokens.len()],
+                withdrawing: vec![0; tokens.len()],
+                tokens,
+                removing: false,
+                lp_removed: false,
+                shares: self.total_shares,
+            });
+        }
+        self.internal_requeue_withdrawals();
+        self.internal_collect_leftovers();
+        ext_farm::list_user_seeds(
+            env::current_account_id().try_into().unwrap(),
+            &CONTRACT_ID_FARM,
+            0,
+            GAS_FOR_LIST_SEEDS,
+        )
+        .then(ext_self::callback_emergency_unstake(
+            &env::current_account_id(),
+            0,
+            GAS_FOR_EMERGENCY_UNSTAKE,
+        ))
+    }
+
+    /// Moves the caller's pro rata share of the tokens held since the emergency exit
+    /// into its vault balance. Only available once every token reached the vault.
+    pub fn claim_emergency(&mut self) -> HashMap<AccountId, U128> {
+        assert!(self.emergency.is_some(), "{}", VaultError::NotInEmergency);
+        assert!(self.internal_emergency_claimable(), "{}", VaultError::EmergencyNotComplete);
+        let account_id = env::predecessor_account_id();
+        self.internal_claim_pending(&account_id);
+        let shares = self.user_shares.get(&account_id).unwrap_or(0);
+        assert!(shares > 0, "{}", VaultError::NoShares);
+        let lp = self.convert_to_assets(shares);
+        self.internal_record_burn(&account_id, shares, shares, self.internal_lp_value(lp));
+        self.internal_append_history(&account_id, LedgerKind::EmergencyClaim, shares, lp, None);
+        let mut account = self.internal_get_account(&account_id).unwrap_or_else(|| VaultError::AccountNotRegistered.panic());
+        let emergency = self.emergency.as_mut().unwrap();
+        let mut claimed = HashMap::new();
+        for (index, token_id) in emergency.tokens.iter().enumerate() {
+            let amount = mul_div(emergency.amounts[index], shares, emergency.shares);
+            emergency.amounts[index] = checked_sub(emergency.amounts[index], amount).or_panic();
+            account.deposit(token_id, amount).or_panic();
+            claimed.insert(token_id.clone(), U128(amount));
+        }
+        emergency.shares = checked_sub(emergency.shares, shares).or_panic();
+        self.user_shares.insert(&account_id, &0);
+        self.total_shares = checked_sub(self.total_shares, shares).or_panic();
+        self.internal_save_account(&account_id, account);
+        for (token_id, amount) in claimed.iter() {
+            self.internal_track_deposit(token_id, amount.0);
+        }
+        log!("{} claimed {} shares of the emergency exit", account_id, shares);
+        claimed
+    }
+
+    /// Gives the caller's deposit still waiting for its epoch to settle back as wNEAR
+    /// in its vault balance. Epochs are no longer settled once an emergency exit started.
+    /// A deposit of an aborted settlement gets its part of the wNEAR left unswapped.
+    pub fn refund_epoch_deposit(&mut self) -> Promise {
+        assert!(self.emergency.is_some(), "{}", VaultError::NotInEmergency);
+        let account_id = env::predecessor_account_id();
+        let pending = self
+            .pending_deposits
+            .get(&account_id)
+            .filter(|pending| self.internal_pending_shares(pending).is_none())
+            .unwrap_or_else(|| VaultError::NoPendingDeposit.panic());
+        let mut refund = pending.amount;
+        if pending.epoch_id == self.epoch.epoch_id {
+            self.epoch.pending_amount = checked_sub(self.epoch.pending_amount, pending.amount).or_panic();
+        } else {
+            // the settlement zap of its epoch may still mint the shares
+            assert!(self.epoch.settle_aborted, "{}", VaultError::EpochSettling);
+            refund = mul_div(pending.amount, self.epoch.retry_amount, self.epoch.settling_amount);
+            self.epoch.settling_amount = checked_sub(self.epoch.settling_amount, pending.amount).or_panic();
+            self.epoch.retry_amount = checked_sub(self.epoch.retry_amount, refund).or_panic();
+        }
+        self.pending_deposits.remove(&account_id);
+        log!("Refunding {} wNEAR of the deposit of {} in epoch {}", refund, account_id, pending.epoch_id);
+        let recipient = ShareRecipient::Account { account_id, min_shares: U128(0) };
+        self.internal_abort_zap(&TOKEN_WRAP.to_string(), refund, recipient).unwrap()
+    }
+
+    pub fn get_emergency_info(&self) -> Option<EmergencyInfo> {
+        self.emergency.as_ref().map(|emergency| EmergencyInfo {
+            started_at: emergency.started_at,
+            amounts: emergency
+                .tokens
+                .iter()
+                .zip(emergency.amounts.iter())
+                .map(|(token_id, amount)| (token_id.clone(), U128(*amount)))
+                .collect(),
+            on_exchange: emergency
+                .tokens
+                .iter()
+                .enumerate()
+                .map(|(index, token_id)| (token_id.clone(), U128(emergency.on_exchange[index] + emergency.withdrawing[index])))
+                .collect(),
+            shares: U128(emergency.shares),
+            claimable: self.internal_emergency_claimable(),
+        })
+    }
+
+    /// Unstakes the vault's seed, then reads its LP on the exchange.
+    #[private]
+    pub fn callback_emergency_unstake(&mut self) -> Promise {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let seeds: HashMap<String, U128> = promise_result_as(0);
+        let staked = seeds.get(SEED_ID).map(|amount| amount.0).unwrap_or(0);
+        if staked == 0 {
+            return self.internal_emergency_get_pool_shares();
+        }
+        log!("Unstaking {} LP", staked);
+        ext_farm::withdraw_seed(
+            SEED_ID.to_string(),
+            U128(staked),
+            "".to_string(),
+            &CONTRACT_ID_FARM,
+            1,
+            GAS_FOR_WITHDRAW_SEED,
+        )
+        .then(ext_self::callback_emergency_unstaked(
+            &env::current_account_id(),
+            0,
+            GAS_FOR_EMERGENCY_UNSTAKED,
+        ))
+    }
+
+    /// Reads the vault's LP on the exchange once its seed was unstaked. A failed unstake
+    /// is retried by the next `emergency_exit`, the LP still staked isn't removed.
+    #[private]
+    pub fn callback_emergency_unstaked(&mut self) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => Some(self.internal_emergency_get_pool_shares()),
+            PromiseResult::Failed => {
+                log!("Unstaking the LP failed");
+                None
+            }
+        }
+    }
+
+    /// Removes all the vault's LP from the strategy pool, then withdraws the tokens
+    /// left on the exchange.
+    #[private]
+    pub fn callback_emergency_remove(&mut self) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let shares: U128 = promise_result_as(0);
+        let emergency = self.emergency.as_mut().unwrap_or_else(|| VaultError::NotInEmergency.panic());
+        if shares.0 == 0 {
+            if !emergency.removing {
+                emergency.lp_removed = true;
+            }
+            return self.internal_emergency_withdraw();
+        }
+        emergency.removing = true;
+        self.vault_shares = 0;
+        Some(
+            ext_exchange::remove_liquidity(
+                POOL_ID,
+                shares,
+                vec![U128(0); POOL_TOKENS.len()],
+                &CONTRACT_ID,
+                1,
+                GAS_FOR_REMOVE_LIQUIDITY,
+            )
+            .then(ext_self::callback_emergency_withdraw(
+                &env::current_account_id(),
+                0,
+                GAS_FOR_EMERGENCY_WITHDRAW,
+            )),
+        )
+    }
+
+    /// Records the pool tokens returned by `remove_liquidity` and withdraws the tokens
+    /// left on the exchange into the vault.
+    #[private]
+    pub fn callback_emergency_withdraw(&mut self) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let emergency = self.emergency.as_mut().unwrap_or_else(|| VaultError::NotInEmergency.panic());
+        emergency.removing = false;
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                let amounts: Vec<U128> =
+                    near_sdk::serde_json::from_slice(&value).unwrap_or_else(|_| VaultError::WrongValueReceived.panic());
+                for (index, amount) in amounts.iter().enumerate() {
+                    emergency.on_exchange[index] = checked_add(emergency.on_exchange[index], amount.0).or_panic();
+                }
+                emergency.lp_removed = true;
+            }
+            PromiseResult::Failed => {
+                log!("Removing the liquidity failed");
+            }
+        };
+        self.internal_emergency_withdraw()
+    }
+
+    /// Makes the tokens that reached the vault claimable. A token whose withdraw failed
+    /// is withdrawn again by the next `emergency_exit`.
+    #[private]
+    pub fn callback_emergency_received(&mut self, token_id: AccountId, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let emergency = self.emergency.as_mut().unwrap_or_else(|| VaultError::NotInEmergency.panic());
+        let index = emergency.tokens.iter().position(|token| *token == token_id).unwrap();
+        emergency.withdrawing[index] = checked_sub(emergency.withdrawing[index], amount.0).or_panic();
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                emergency.amounts[index] = checked_add(emergency.amounts[index], amount.0).or_panic();
+            }
+            PromiseResult::Failed => {
+                log!("Withdraw of {} {} from the exchange failed", amount.0, token_id);
+                emergency.on_exchange[index] = checked_add(emergency.on_exchange[index], amount.0).or_panic();
+            }
+        };
+    }
+}
+
+impl Contract {
+    pub(crate) fn assert_not_emergency(&self) {
+        assert!(self.emergency.is_none(), "{}", VaultError::EmergencyExit);
+    }
+
+    /// Whether the depositors can claim the tokens of the emergency exit: every token
+    /// reached the vault and every queued withdrawal got its shares back.
+    fn internal_emergency_claimable(&self) -> bool {
+        self.emergency.as_ref().map(|emergency| emergency.is_complete()).unwrap_or(false)
+            && self.withdraw_limiter.queue_len() == 0
+    }
+
+    /// Reads the vault's LP on the exchange, then removes it. Only called once no LP is
+    /// staked on the farm, so that no LP is left behind when `lp_removed` is set.
+    fn internal_emergency_get_pool_shares(&self) -> Promise {
+        ext_exchange::get_pool_shares(
+            POOL_ID,
+            env::current_account_id(),
+            &CONTRACT_ID,
+            0,
+            GAS_FOR_GET_POOL_SHARES,
+        )
+        .then(ext_self::callback_emergency_remove(
+            &env::current_account_id(),
+            0,
+            GAS_FOR_EMERGENCY_REMOVE,
+        ))
+    }
+
+    /// Gives the shares of up to `MAX_REQUEUE_PER_CALL` queued withdrawals back to their
+    /// senders, so they can claim the pool tokens like other depositors.
+    fn internal_requeue_withdrawals(&mut self) {
+        let total_shares = self.total_shares;
+        let end = std::cmp::min(self.withdraw_limiter.queue_tail, self.withdraw_limiter.queue_head + MAX_REQUEUE_PER_CALL);
+        while self.withdraw_limiter.queue_head < end {
+            let id = self.withdraw_limiter.queue_head;
+            let queued = self.withdraw_queue.remove(&id).unwrap();
+            self.withdraw_limiter.queue_head += 1;
+            self.internal_refund_queued(queued);
+        }
+        let refunded = checked_sub(self.total_shares, total_shares).or_panic();
+        let emergency = self.emergency.as_mut().unwrap();
+        emergency.shares = checked_add(emergency.shares, refunded).or_panic();
+    }
+
+    /// Adds the harvested rewards and the zap dust the vault keeps on the exchange
+    /// to the tokens to withdraw, and the idle rewards to the tokens to claim.
+    fn internal_collect_leftovers(&mut self) {
+        let emergency = self.emergency.as_mut().unwrap();
+        for (index, token_id) in emergency.tokens.iter().enumerate() {
+            let mut amount = self.zap_dust.remove(token_id).unwrap_or(0);
+            if token_id == REWARD_TOKEN {
+                amount = checked_add(amount, self.pending_reward).or_panic();
+                self.pending_reward = 0;
+                emergency.amounts[index] = checked_add(emergency.amounts[index], self.idle_reward).or_panic();
+                self.idle_reward = 0;
+            }
+            emergency.on_exchange[index] = checked_add(emergency.on_exchange[index], amount).or_panic();
+        }
+    }
+
+    /// Withdraws the tokens of the exit still on the exchange into the vault.
+    fn internal_emergency_withdraw(&mut self) -> Option<Promise> {
+        let emergency = self.emergency.as_mut().unwrap();
+        let mut withdraws: Option<Promise> = None;
+        for (index, token_id) in emergency.tokens.iter().enumerate() {
+            let amount = emergency.on_exchange[index];
+            if amount == 0 {
+                continue;
+            }
+            emergency.on_exchange[index] = 0;
+            emergency.withdrawing[index] = checked_add(emergency.withdrawing[index], amount).or_panic();
+            let withdraw = ext_exchange::withdraw(token_id.clone(), U128(amount), Some(false), &CONTRACT_ID, 1, GAS_FOR_EXCHANGE_WITHDRAW)
+            .then(ext_self::callback_emergency_received(
+                token_id.clone(),
+                U128(amount),
+                &env::current_account_id(),
+                0,
+                GAS_FOR_EMERGENCY_RECEIVED,
+            ));
+            withdraws = Some(match withdraws {
+                Some(previous) => previous.and(withdraw),
+                None => withdraw,
+            });
+        }
+        withdraws
+    }
+}
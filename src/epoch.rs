@@ -0,0 +1,343 @@
+//! Epoch-batched deposits.
+//!
+//! In epoch mode `add_to_vault` only wraps the user's NEAR, deposits the wNEAR to the
+//! exchange and, once the exchange accepted it, records it as a pending deposit.
+//! Anyone can then settle the epoch once `epoch_duration` passed: all pending wNEAR goes
+//! through a single swap / add liquidity / stake sequence and the shares minted for it are
+//! claimed pro rata by the depositors of that epoch.
+//! A settlement whose swaps or liquidity fail is aborted and can be retried by the next
+//! `settle_epoch`. A guardian can abort one that never completed with `reset_settlement`.
+
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::json_types::U64;
+use near_sdk::Gas;
+
+use crate::ledger::LedgerKind;
+use crate::*;
+
+const GAS_FOR_NEAR_DEPOSIT: Gas = 3_000_000_000_000;
+const GAS_FOR_TOKEN_DEPOSIT: Gas = 35_000_000_000_000;
+const GAS_FOR_EPOCH_WRAP: Gas = 70_000_000_000_000;
+const GAS_FOR_QUEUE_DEPOSIT: Gas = 20_000_000_000_000;
+/// Time after which a settlement that neither completed nor aborted can be reset.
+const SETTLEMENT_TIMEOUT: u64 = 3_600 * 1_000_000_000;
+
+/// State of the epoch currently accepting deposits and of the one being settled.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct EpochState {
+    pub epoch_id: u64,
+    /// Block timestamp at which this epoch started accepting deposits.
+    pub started_at: u64,
+    /// Total wNEAR queued in this epoch.
+    pub pending_amount: Balance,
+    /// Epoch whose settlement zap is in flight, if any.
+    pub settling_epoch: Option<u64>,
+    /// Total wNEAR of the settling epoch.
+    pub settling_amount: Balance,
+    /// Block timestamp at which the settlement zap, or its last retry, started.
+    pub settling_since: u64,
+    /// Whether the settlement zap was aborted and can be retried.
+    pub settle_aborted: bool,
+    /// wNEAR the retry zaps. Less than `settling_amount` if the aborted zap swapped part of
+    /// it, that part is kept as tracked dust and added back by the retry.
+    pub retry_amount: Balance,
+}
+
+impl EpochState {
+    pub fn new(epoch_id: u64) -> Self {
+        Self {
+            epoch_id,
+            started_at: env::block_timestamp(),
+            pending_amount: 0,
+            settling_epoch: None,
+            settling_amount: 0,
+            settling_since: 0,
+            settle_aborted: false,
+            retry_amount: 0,
+        }
+    }
+}
+
+/// Deposit of an account waiting for the settlement of its epoch.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PendingDeposit {
+    pub epoch_id: u64,
+    pub amount: Balance,
+}
+
+/// Result of a settled epoch, used to mint shares to its depositors.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct EpochSettlement {
+    /// Total wNEAR deposited in the epoch.
+    pub amount: Balance,
+    /// Vault shares minted for the whole epoch.
+    pub shares: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct EpochInfo {
+    pub epoch_mode: bool,
+    pub epoch_id: u64,
+    pub started_at: U64,
+    /// Earliest timestamp at which the current epoch can be settled.
+    pub settle_after: U64,
+    pub pending_amount: U128,
+    pub settling_epoch: Option<u64>,
+    pub settling_amount: U128,
+    pub settling_since: U64,
+    pub settle_aborted: bool,
+    pub retry_amount: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct PendingDepositInfo {
+    pub epoch_id: u64,
+    pub amount: U128,
+    pub settled: bool,
+    /// Shares claimable for this deposit, 0 until the epoch is settled.
+    pub shares: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Settles the current epoch: swaps all its pending wNEAR, adds liquidity, stakes
+    /// the LP and mints the epoch's shares. Can be called by any keeper.
+    /// If the previous settlement was aborted, retries it instead.
+    pub fn settle_epoch(&mut self) -> Promise {
+        self.assert_contract_running();
+        self.assert_not_emergency();
+        if let Some(epoch_id) = self.epoch.settling_epoch {
+            assert!(self.epoch.settle_aborted, "{}", VaultError::EpochSettling);
+            self.epoch.settle_aborted = false;
+            self.epoch.settling_since = env::block_timestamp();
+            log!("Retrying settlement of epoch {}", epoch_id);
+            return self.internal_zap_in(TOKEN_WRAP, self.epoch.retry_amount, ShareRecipient::Epoch(epoch_id));
+        }
+        assert!(self.epoch.pending_amount > 0, "{}", VaultError::EpochEmpty);
+        assert!(
+            env::block_timestamp() >= self.epoch.started_at + self.epoch_duration,
+            "{}",
+            VaultError::EpochNotFinished
+        );
+
+        let epoch_id = self.epoch.epoch_id;
+        let amount = self.epoch.pending_amount;
+        self.epoch = EpochState {
+            settling_epoch: Some(epoch_id),
+            settling_amount: amount,
+            settling_since: env::block_timestamp(),
+            ..EpochState::new(epoch_id + 1)
+        };
+        log!("Settling epoch {} with {} wNEAR", epoch_id, amount);
+
+        self.internal_zap_in(TOKEN_WRAP, amount, ShareRecipient::Epoch(epoch_id))
+    }
+
+    /// Aborts a settlement whose zap didn't complete within an hour, e.g. because one of
+    /// its callbacks failed, so that it can be retried. The retry zaps `retry_amount` of
+    /// wNEAR, at most the settling amount, and the tracked dust. Only callable by a guardian.
+    pub fn reset_settlement(&mut self, retry_amount: U128) {
+        self.assert_guardian();
+        let epoch_id = self
+            .epoch
+            .settling_epoch
+            .filter(|_| !self.epoch.settle_aborted)
+            .unwrap_or_else(|| VaultError::EpochNotSettling.panic());
+        assert!(
+            env::block_timestamp() >= self.epoch.settling_since.saturating_add(SETTLEMENT_TIMEOUT),
+            "{}",
+            VaultError::SettlementNotTimedOut
+        );
+        let retry_amount = std::cmp::min(retry_amount.0, self.epoch.settling_amount);
+        log!("Resetting settlement of epoch {} to retry with {} wNEAR", epoch_id, retry_amount);
+        self.internal_abort_settlement(epoch_id, retry_amount);
+    }
+
+    /// Moves the caller's shares from a settled epoch into their vault balance.
+    pub fn claim_epoch_shares(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        U128(self.internal_claim_pending(&account_id))
+    }
+
+    /// Deposits the wNEAR wrapped for `account_id` to the exchange, or gives the NEAR back
+    /// to its investable balance if wrapping failed.
+    #[private]
+    pub fn callback_epoch_wrap(&mut self, account_id: AccountId, amount: U128) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("Wrapping {} NEAR for {} failed, refunding", amount.0, account_id);
+            self.internal_untrack_in_flight(&TOKEN_WRAP.to_string(), amount.0);
+            self.internal_credit_near(&account_id, amount.0);
+            return None;
+        }
+        Some(
+            ext_fungible_token::ft_transfer_call(
+                CONTRACT_ID.to_string(),
+                amount,
+                None,
+                "".to_string(),
+                &TOKEN_WRAP.to_string(),
+                1,
+                GAS_FOR_TOKEN_DEPOSIT,
+            )
+            .then(ext_self::callback_queue_deposit(
+                account_id,
+                amount,
+                &env::current_account_id(),
+                0,
+                GAS_FOR_QUEUE_DEPOSIT,
+            )),
+        )
+    }
+
+    /// Queues the wNEAR the exchange accepted as the account's deposit in the current epoch.
+    /// wNEAR the exchange refunded, or that can't be queued because the account's deposit
+    /// of a previous epoch is still settling, is credited to the account's balance.
+    #[private]
+    pub fn callback_queue_deposit(&mut self, account_id: AccountId, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        self.internal_untrack_in_flight(&TOKEN_WRAP.to_string(), amount.0);
+        let used = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<U128>(&value).map(|used| used.0).unwrap_or(0)
+            }
+            PromiseResult::Failed => 0,
+        };
+        if used < amount.0 {
+            self.internal_credit_or_lostfound(&account_id, &TOKEN_WRAP.to_string(), amount.0 - used);
+        }
+        if used == 0 {
+            return;
+        }
+        if self.internal_can_queue_deposit(&account_id) {
+            self.internal_queue_deposit(&account_id, used);
+        } else {
+            log!("Deposit of {} still settling, returning {} wNEAR", account_id, used);
+            let recipient = ShareRecipient::Account { account_id, min_shares: U128(0) };
+            self.internal_abort_zap(&TOKEN_WRAP.to_string(), used, recipient);
+        }
+    }
+
+    pub fn get_epoch_info(&self) -> EpochInfo {
+        EpochInfo {
+            epoch_mode: self.epoch_mode,
+            epoch_id: self.epoch.epoch_id,
+            started_at: U64(self.epoch.started_at),
+            settle_after: U64(self.epoch.started_at + self.epoch_duration),
+            pending_amount: U128(self.epoch.pending_amount),
+            settling_epoch: self.epoch.settling_epoch,
+            settling_amount: U128(self.epoch.settling_amount),
+            settling_since: U64(self.epoch.settling_since),
+            settle_aborted: self.epoch.settle_aborted,
+            retry_amount: U128(self.epoch.retry_amount),
+        }
+    }
+
+    pub fn get_pending_deposit(&self, account_id: ValidAccountId) -> Option<PendingDepositInfo> {
+        self.pending_deposits.get(account_id.as_ref()).map(|pending| {
+            let shares = self.internal_pending_shares(&pending);
+            PendingDepositInfo {
+                epoch_id: pending.epoch_id,
+                amount: U128(pending.amount),
+                settled: shares.is_some(),
+                shares: U128(shares.unwrap_or(0)),
+            }
+        })
+    }
+}
+
+impl Contract {
+    /// Wraps `amount` of NEAR already taken from the account's investable balance and
+    /// queues it in the current epoch once the exchange accepted the wNEAR.
+    pub(crate) fn internal_epoch_deposit(&mut self, account_id: AccountId, amount: Balance) -> Promise {
+        assert!(self.internal_can_queue_deposit(&account_id), "{}", VaultError::PreviousEpochSettling);
+        self.internal_track_in_flight(&TOKEN_WRAP.to_string(), amount);
+        ext_wrap::near_deposit(&CONTRACT_ID_WRAP, amount, GAS_FOR_NEAR_DEPOSIT).then(ext_self::callback_epoch_wrap(
+            account_id,
+            U128(amount),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_EPOCH_WRAP,
+        ))
+    }
+
+    /// Whether a deposit of the account can be queued in the current epoch, that is
+    /// whether the account has no deposit of a previous epoch that is not settled yet.
+    pub(crate) fn internal_can_queue_deposit(&self, account_id: &AccountId) -> bool {
+        match self.pending_deposits.get(account_id) {
+            Some(pending) => pending.epoch_id == self.epoch.epoch_id || self.internal_pending_shares(&pending).is_some(),
+            None => true,
+        }
+    }
+
+    /// Adds `amount` of wNEAR to the account's deposit in the current epoch.
+    /// A deposit left from a previous epoch is claimed first.
+    pub(crate) fn internal_queue_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        assert!(self.internal_can_queue_deposit(account_id), "{}", VaultError::PreviousEpochSettling);
+        let epoch_id = self.epoch.epoch_id;
+        let mut queued = amount;
+        if let Some(pending) = self.pending_deposits.get(account_id) {
+            if pending.epoch_id == epoch_id {
+                queued = checked_add(queued, pending.amount).or_panic();
+            } else {
+                self.internal_claim_pending(account_id);
+            }
+        }
+        self.pending_deposits.insert(
+            account_id,
+            &PendingDeposit { epoch_id, amount: queued },
+        );
+        self.epoch.pending_amount = checked_add(self.epoch.pending_amount, amount).or_panic();
+    }
+
+    /// Records the shares minted by the settlement zap of `epoch_id`.
+    pub(crate) fn internal_record_settlement(&mut self, epoch_id: u64, shares: Balance) {
+        assert_eq!(self.epoch.settling_epoch, Some(epoch_id), "{}", VaultError::EpochNotSettling);
+        self.epoch_settlements.insert(
+            &epoch_id,
+            &EpochSettlement { amount: self.epoch.settling_amount, shares },
+        );
+        self.epoch.settling_epoch = None;
+        self.epoch.settling_amount = 0;
+        log!("Epoch {} settled with {} shares", epoch_id, shares);
+    }
+
+    /// Lets the settlement of `epoch_id` be retried with the `amount` of wNEAR its aborted
+    /// zap left unswapped.
+    pub(crate) fn internal_abort_settlement(&mut self, epoch_id: u64, amount: Balance) {
+        assert_eq!(self.epoch.settling_epoch, Some(epoch_id), "{}", VaultError::EpochNotSettling);
+        self.epoch.settle_aborted = true;
+        self.epoch.retry_amount = amount;
+    }
+
+    /// Shares owed for a pending deposit, or None while its epoch is not settled.
+    pub(crate) fn internal_pending_shares(&self, pending: &PendingDeposit) -> Option<Balance> {
+        self.epoch_settlements
+            .get(&pending.epoch_id)
+            .map(|settlement| mul_div(settlement.shares, pending.amount, settlement.amount))
+    }
+
+    /// Credits the account with the shares of its settled pending deposit.
+    /// Returns the amount of shares claimed.
+    pub(crate) fn internal_claim_pending(&mut self, account_id: &AccountId) -> Balance {
+        let (shares, amount) = match self.pending_deposits.get(account_id) {
+            Some(pending) => match self.internal_pending_shares(&pending) {
+                Some(shares) => (shares, pending.amount),
+                None => return 0,
+            },
+            None => return 0,
+        };
+        self.pending_deposits.remove(account_id);
+        let user_shares = self.user_shares.get(account_id).unwrap_or(0);
+        self.user_shares.insert(account_id, &checked_add(user_shares, shares).or_panic());
+        self.internal_record_mint(account_id, amount);
+        let lp = self.convert_to_assets(shares);
+        self.internal_append_history(account_id, LedgerKind::Deposit, shares, lp, Some((TOKEN_WRAP.to_string(), amount)));
+        shares
+    }
+}
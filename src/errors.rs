@@ -1,35 +1,178 @@
-// Storage errors.
-
-pub const ERR10_ACC_NOT_REGISTERED: &str = "E10: account not registered";
-pub const ERR11_INSUFFICIENT_STORAGE: &str = "E11: insufficient $NEAR storage deposit";
-pub const ERR12_TOKEN_NOT_WHITELISTED: &str = "E12: token not whitelisted";
-pub const ERR13_LP_NOT_REGISTERED: &str = "E13: LP not registered";
-pub const ERR14_LP_ALREADY_REGISTERED: &str = "E14: LP already registered";
-
-// Accounts.
-
-pub const ERR21_TOKEN_NOT_REG: &str = "E21: token not registered";
-pub const ERR22_NOT_ENOUGH_TOKENS: &str = "E22: not enough tokens in deposit";
-// pub const ERR23_NOT_ENOUGH_NEAR: &str = "E23: not enough NEAR in deposit";
-pub const ERR24_NON_ZERO_TOKEN_BALANCE: &str = "E24: non-zero token balance";
-pub const ERR25_CALLBACK_POST_WITHDRAW_INVALID: &str =
-    "E25: expected 1 promise result from withdraw";
-// [AUDIT_05]
-// pub const ERR26_ACCESS_KEY_NOT_ALLOWED: &str = "E26: access key not allowed";
-pub const ERR27_DEPOSIT_NEEDED: &str = 
-    "E27: attach 1yN to swap tokens not in whitelist";
-pub const ERR28_WRONG_MSG_FORMAT: &str = "E28: Illegal msg in ft_transfer_call";
-pub const ERR29_ILLEGAL_WITHDRAW_AMOUNT: &str = "E29: Illegal withdraw amount";
-
-// Liquidity operations.
-
-pub const ERR31_ZERO_AMOUNT: &str = "E31: adding zero amount";
-pub const ERR32_ZERO_SHARES: &str = "E32: minting zero shares";
-// [AUDIT_07]
-pub const ERR33_TRANSFER_TO_SELF: &str = "E33: transfer to self";
-// Action result.
-
-pub const ERR41_WRONG_ACTION_RESULT: &str = "E41: wrong action result type";
-
-// Contract Level
-pub const ERR51_CONTRACT_PAUSED: &str = "E51: contract paused";
\ No newline at end of file
+//! Errors of the vault.
+//!
+//! Every assertion and callback of the contract fails with a `VaultError`, whose message
+//! starts with its code, e.g. "E10: account not registered". Codes are stable: a code is
+//! never reused for another error, retired codes (E13, E14, E26, E27, E28, E32, E33, E41,
+//! E44, E45, E46) stay unused. Errors are listed in the order of their codes, which
+//! `get_error_codes` keeps.
+
+use crate::*;
+
+macro_rules! vault_errors {
+    ($($variant:ident = $code:literal: $message:literal,)*) => {
+        #[derive(Clone, Copy, PartialEq)]
+        #[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+        pub enum VaultError {
+            $($variant,)*
+        }
+
+        impl VaultError {
+            pub const ALL: &'static [VaultError] = &[$(VaultError::$variant,)*];
+
+            /// Code and message, as the contract panics with.
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(VaultError::$variant => concat!("E", $code, ": ", $message),)*
+                }
+            }
+        }
+    };
+}
+
+vault_errors! {
+    // Storage.
+    AccountNotRegistered = 10: "account not registered",
+    InsufficientStorage = 11: "insufficient $NEAR storage deposit",
+    TokenNotWhitelisted = 12: "token not whitelisted",
+    DepositLessThanMinStorage = 15: "deposit less than the minimum storage",
+    NoStorageToWithdraw = 16: "no storage can be withdrawn",
+    StorageWithdrawTooMuch = 17: "storage withdraw exceeds the available storage",
+    UnregisterTokensNotEmpty = 18: "account still holds tokens",
+    UnregisterSharesNotEmpty = 19: "account still holds shares",
+    AccountRegistered = 20: "account already registered",
+
+    // Accounts.
+    TokenNotRegistered = 21: "token not registered",
+    NotEnoughTokens = 22: "not enough tokens in deposit",
+    NotEnoughNear = 23: "not enough NEAR in deposit",
+    NonZeroTokenBalance = 24: "non-zero token balance",
+    CallbackPostWithdrawInvalid = 25: "expected 1 promise result from withdraw",
+    IllegalWithdrawAmount = 29: "Illegal withdraw amount",
+
+    // Deposits and withdrawals.
+    NoStranded = 30: "no stranded tokens",
+    ZeroAmount = 31: "adding zero amount",
+    NoLpAdded = 34: "no LP added",
+    NoShares = 35: "no shares",
+    NotEnoughShares = 36: "not enough shares",
+    DepositLimitExceeded = 37: "deposit above the limit of the token",
+    DepositCapReached = 38: "deposit cap reached",
+    PendingDeposit = 39: "deposit waiting for its epoch",
+
+    // Arithmetic.
+    Overflow = 40: "arithmetic overflow",
+    Underflow = 42: "arithmetic underflow",
+    DivisionByZero = 43: "division by zero",
+
+    // Contract level.
+    ContractPaused = 51: "contract paused",
+    EmergencyExit = 52: "emergency exit in progress",
+    NotInEmergency = 53: "no emergency exit in progress",
+    NoLpToRemove = 54: "no LP to remove",
+    WithdrawLimitReached = 55: "withdraw limit reached",
+    WithdrawQueueEmpty = 56: "withdraw queue empty",
+    BoostTooLong = 57: "withdraw limit boost too long",
+    ZeroWindow = 58: "withdraw limit window must not be 0",
+    EmergencyNotComplete = 59: "emergency exit not complete",
+
+    // Administration.
+    NotAllowed = 60: "not allowed",
+    NotPendingOwner = 61: "caller is not the pending owner",
+    NoPendingOwner = 62: "no pending owner",
+    NotCouncilMember = 63: "caller is not a council member",
+    NoProposal = 64: "proposal not found",
+    ProposalExpired = 65: "proposal expired",
+    ProposalNotExpired = 66: "proposal not expired",
+    AlreadyApproved = 67: "proposal already approved by caller",
+    NotEnoughApprovals = 68: "not enough approvals",
+    ThresholdTooHigh = 69: "threshold above the council size",
+    ThresholdZero = 70: "threshold of a council must not be 0",
+    NoChange = 71: "queued change not found",
+    TimelockNotExpired = 72: "timelock not expired",
+    BountyTooHigh = 73: "harvest bounty too high",
+    EmptyRoute = 74: "empty route",
+    RouteWrongTokenOut = 75: "route doesn't end with its output token",
+    NoRoute = 76: "no route",
+    TokenHasDeposits = 77: "accounts still hold the token",
+    TokenInLostfound = 78: "lostfound still holds the token",
+    NoLostfound = 79: "nothing in lostfound",
+
+    // Epochs and harvests.
+    EpochSettling = 80: "epoch settling",
+    EpochEmpty = 81: "epoch empty",
+    EpochNotFinished = 82: "epoch not finished",
+    EpochNotSettling = 83: "epoch not settling",
+    PreviousEpochSettling = 84: "previous epoch settling",
+    NoPendingDeposit = 85: "no unsettled pending deposit",
+    HarvestTooSoon = 86: "harvest too soon",
+    HarvestTooSmall = 87: "harvest too small",
+    NoReward = 88: "no reward",
+    SettlementNotTimedOut = 89: "settlement not timed out",
+
+    // Price checks.
+    OracleUnavailable = 90: "oracle unavailable",
+    OracleStale = 91: "oracle data stale",
+    NoOraclePrice = 92: "no oracle price",
+    PriceDeviation = 93: "pool price deviates from the oracle",
+
+    // Cross-contract calls.
+    TooManyResults = 95: "unexpected number of promise results",
+    WrongValueReceived = 96: "wrong value received",
+    CallFailed = 97: "call failed",
+    ExchangeWithdrawFailed = 98: "withdraw from the exchange failed",
+    NearWithdrawFailed = 99: "unwrapping wNEAR failed",
+
+    // Governance.
+    CouncilOnly = 100: "only the council can queue this change",
+    CouncilChange = 101: "changes approved by the council can't be cancelled",
+    TimelockTooShort = 102: "timelock delay below the minimum",
+
+    // Whitelist.
+    TokenInUse = 110: "token used by the strategy or a route",
+    TokenInTransit = 111: "token stranded or in flight",
+}
+
+impl VaultError {
+    pub fn panic(self) -> ! {
+        env::panic(self.as_str().as_bytes())
+    }
+}
+
+/// Unwraps results of the checked helpers of `utils`, panicking with their error.
+pub trait OrPanic<T> {
+    fn or_panic(self) -> T;
+}
+
+impl<T> OrPanic<T> for Result<T, VaultError> {
+    fn or_panic(self) -> T {
+        self.unwrap_or_else(|error| error.panic())
+    }
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct ErrorCodeInfo {
+    pub code: String,
+    pub message: String,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Every error the contract can fail with.
+    pub fn get_error_codes(&self) -> Vec<ErrorCodeInfo> {
+        VaultError::ALL
+            .iter()
+            .map(|error| {
+                let (code, message) = error.as_str().split_at(error.as_str().find(": ").unwrap());
+                ErrorCodeInfo { code: code.to_string(), message: message[2..].to_string() }
+            })
+            .collect()
+    }
+}
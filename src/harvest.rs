@@ -0,0 +1,198 @@
+//! Harvesting and compounding of the farm rewards.
+//!
+//! `harvest` claims the farm rewards, withdraws them to the vault and deposits them on
+//! the exchange. Anyone can call it once `harvest_interval` has passed since the last
+//! harvest, as long as at least `min_harvest_reward` is claimable, and the caller is paid
+//! `harvest_bounty_bps` of the harvested reward tokens. The interval counts from the last
+//! harvest that withdrew rewards, so a failed harvest can be retried right away. Rewards
+//! whose deposit on the exchange failed are kept as idle reward and deposited by the next
+//! harvest. `compound_rewards` then zaps them
+//! into the strategy pool along the registered routes and stakes the LP without minting
+//! shares. That LP is locked and released linearly into the share price over
+//! `profit_unlock_period`, so depositing right before a compounding and withdrawing
+//! right after doesn't capture it.
+
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::Gas;
+
+use crate::ledger::LedgerKind;
+use crate::utils::{checked_mul_div, mul_div, promise_result_as};
+use crate::*;
+
+const GAS_FOR_GET_REWARD: Gas = 215_000_000_000_000;
+const GAS_FOR_WITHDRAW_REWARD: Gas = 60_000_000_000_000;
+const GAS_FOR_POST_WITHDRAW_REWARD: Gas = 140_000_000_000_000;
+const GAS_FOR_REWARD_DEPOSIT: Gas = 60_000_000_000_000;
+const GAS_FOR_POST_REWARD_DEPOSIT: Gas = 10_000_000_000_000;
+
+/// Upper bound of the harvest bounty, 10%.
+pub(crate) const MAX_HARVEST_BOUNTY_BPS: u32 = 1_000;
+const BPS_DIVISOR: u128 = 10_000;
+
+pub(crate) const REWARD_TOKEN: &str = "ref.fakes.testnet";
+pub(crate) const SEED_ID: &str = "exchange.ref-dev.testnet@193";
+
+#[near_bindgen]
+impl Contract {
+    /// Reward tokens deposited on the exchange waiting to be compounded.
+    pub fn get_pending_reward(&self) -> U128 {
+        U128(self.pending_reward)
+    }
+
+    /// Harvest parameters: (bounty in bps, minimum interval in seconds, minimum reward).
+    pub fn get_harvest_config(&self) -> (u32, u64, U128) {
+        (self.harvest_bounty_bps, self.harvest_interval / 1_000_000_000, U128(self.min_harvest_reward))
+    }
+
+    /// Harvested reward tokens in the vault's wallet waiting to be deposited on the exchange.
+    pub fn get_idle_reward(&self) -> U128 {
+        U128(self.idle_reward)
+    }
+
+    /// Timestamp of the last harvest that withdrew rewards from the farm, in nanoseconds.
+    pub fn get_last_harvest(&self) -> u64 {
+        self.last_harvest
+    }
+
+    /// Claims the farm rewards and deposits them on the exchange for compounding.
+    /// The caller receives the harvest bounty in reward tokens.
+    pub fn harvest(&mut self) -> Promise {
+        self.assert_contract_running();
+        self.assert_not_emergency();
+        assert!(
+            env::block_timestamp() >= self.last_harvest.saturating_add(self.harvest_interval),
+            "{}",
+            VaultError::HarvestTooSoon
+        );
+        self.call_claim(SEED_ID.to_string())
+            .then(self.call_get_reward(
+                env::current_account_id().try_into().unwrap(),
+                REWARD_TOKEN.try_into().unwrap(),
+            ))
+            .then(ext_self::callback_get_reward(
+                env::predecessor_account_id(),
+                &env::current_account_id(),
+                0,
+                GAS_FOR_GET_REWARD,
+            ))
+    }
+
+    /// Withdraws the claimed rewards from the farm if they are worth harvesting.
+    #[private]
+    pub fn callback_get_reward(&mut self, harvester_id: AccountId) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let reward: U128 = promise_result_as(0);
+        assert!(reward.0 > 0 && reward.0 >= self.min_harvest_reward, "{}", VaultError::HarvestTooSmall);
+        self.internal_track_in_flight(&REWARD_TOKEN.to_string(), reward.0);
+        ext_farm::withdraw_reward(
+            REWARD_TOKEN.to_string(),
+            reward,
+            "false".to_string(),
+            &CONTRACT_ID_FARM,
+            1,
+            GAS_FOR_WITHDRAW_REWARD,
+        )
+        .then(ext_self::callback_post_withdraw_reward(
+            harvester_id,
+            reward,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_POST_WITHDRAW_REWARD,
+        ));
+        reward
+    }
+
+    /// Zaps the harvested rewards into the strategy pool and stakes the LP.
+    pub fn compound_rewards(&mut self) -> Promise {
+        self.assert_contract_running();
+        self.assert_not_emergency();
+        assert!(self.pending_reward > 0, "{}", VaultError::NoReward);
+        assert!(self.total_shares > 0, "{}", VaultError::NoShares);
+        let amount = self.pending_reward;
+        self.pending_reward = 0;
+        self.internal_zap_in(REWARD_TOKEN, amount, ShareRecipient::Compound)
+    }
+
+    /// Pays the harvest bounty and deposits the rest of the rewards withdrawn from the
+    /// farm on the exchange, along with the rewards of failed deposits. Concurrent
+    /// harvests withdraw the same rewards at most once, so only one is paid the bounty.
+    #[private]
+    pub fn callback_post_withdraw_reward(&mut self, harvester_id: AccountId, reward: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let token_id = REWARD_TOKEN.to_string();
+        self.internal_untrack_in_flight(&token_id, reward.0);
+        let amount = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<U128>(&value).map(|amount| amount.0).unwrap_or(0)
+            }
+            PromiseResult::Failed => 0,
+        };
+        if amount == 0 {
+            log!("No reward withdrawn");
+            return;
+        }
+        self.last_harvest = env::block_timestamp();
+        let amount = U128(amount);
+        let bounty = checked_mul_div(amount.0, self.harvest_bounty_bps as u128, BPS_DIVISOR).or_panic();
+        if bounty > 0 {
+            log!("Paying {} {} harvest bounty to {}", bounty, token_id, harvester_id);
+            self.internal_send_tokens(&harvester_id, &token_id, bounty);
+            self.internal_append_history(&harvester_id, LedgerKind::HarvestBounty, 0, 0, Some((token_id.clone(), bounty)));
+        }
+        let deposit = checked_add(checked_sub(amount.0, bounty).or_panic(), self.idle_reward).or_panic();
+        self.idle_reward = 0;
+        self.internal_track_in_flight(&token_id, deposit);
+        ext_fungible_token::ft_transfer_call(
+            CONTRACT_ID.to_string(),
+            U128(deposit),
+            None,
+            "".to_string(),
+            &token_id,
+            1,
+            GAS_FOR_REWARD_DEPOSIT,
+        )
+        .then(ext_self::callback_post_reward_deposit(U128(deposit), &env::current_account_id(), 0, GAS_FOR_POST_REWARD_DEPOSIT));
+    }
+
+    /// Records the rewards the exchange accepted out of the `amount` deposited as pending
+    /// for compounding. The rest stayed in the vault's wallet and is kept as idle reward.
+    #[private]
+    pub fn callback_post_reward_deposit(&mut self, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        self.internal_untrack_in_flight(&REWARD_TOKEN.to_string(), amount.0);
+        let used = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map(|used| std::cmp::min(used.0, amount.0))
+                .unwrap_or_else(|_| VaultError::WrongValueReceived.panic()),
+            PromiseResult::Failed => 0,
+        };
+        if used < amount.0 {
+            log!("Deposit of {} reward on the exchange failed, kept as idle reward", amount.0 - used);
+            self.idle_reward = checked_add(self.idle_reward, amount.0 - used).or_panic();
+        }
+        self.pending_reward = checked_add(self.pending_reward, used).or_panic();
+        log!("{} reward pending to compound", self.pending_reward);
+        self.internal_record_price();
+    }
+}
+
+impl Contract {
+    /// Part of the compounded LP not yet released into the share price.
+    pub(crate) fn internal_locked_profit(&self) -> Balance {
+        let elapsed = env::block_timestamp().saturating_sub(self.locked_profit_at);
+        if elapsed >= self.profit_unlock_period {
+            0
+        } else {
+            let remaining = (self.profit_unlock_period - elapsed) as u128;
+            mul_div(self.locked_profit, remaining, self.profit_unlock_period as u128)
+        }
+    }
+
+    /// Locks `lp` compounded LP on top of the profit still locked and restarts the release.
+    pub(crate) fn internal_lock_profit(&mut self, lp: Balance) {
+        self.locked_profit = checked_add(self.internal_locked_profit(), lp).or_panic();
+        self.locked_profit_at = env::block_timestamp();
+    }
+}
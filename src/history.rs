@@ -0,0 +1,96 @@
+//! Share price history.
+//!
+//! A snapshot of the vault's assets and shares is recorded at each harvest, compounding
+//! and epoch settlement, in a ring buffer of the last `PRICE_HISTORY_SIZE` snapshots.
+
+use near_sdk::json_types::U64;
+
+use crate::utils::{sec_to_nanos, U256};
+use crate::*;
+
+pub(crate) const PRICE_HISTORY_SIZE: u64 = 720;
+const YEAR: u64 = 365 * 24 * 3600 * 1_000_000_000;
+const BPS_DIVISOR: u128 = 10_000;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PriceSnapshot {
+    pub timestamp: u64,
+    /// LP backing the shares, without locked profit.
+    pub total_assets: Balance,
+    pub total_shares: Balance,
+}
+
+impl PriceSnapshot {
+    /// Ratio of the share price of `self` to the share price of `earlier`, in bps.
+    fn growth_bps(&self, earlier: &PriceSnapshot) -> i128 {
+        let now = U256::from(self.total_assets + VIRTUAL_LP) * U256::from(earlier.total_shares + VIRTUAL_SHARES);
+        let before = U256::from(earlier.total_assets + VIRTUAL_LP) * U256::from(self.total_shares + VIRTUAL_SHARES);
+        (now * U256::from(BPS_DIVISOR) / before).as_u128() as i128 - BPS_DIVISOR as i128
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct PriceSnapshotInfo {
+    pub timestamp: U64,
+    pub total_assets: U128,
+    pub total_shares: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Recorded snapshots from oldest to newest, `from_index` 0 being the oldest kept.
+    pub fn get_price_history(&self, from_index: u64, limit: u64) -> Vec<PriceSnapshotInfo> {
+        let len = self.price_history.len();
+        let first = self.price_history_count - len;
+        (from_index..std::cmp::min(from_index.saturating_add(limit), len))
+            .map(|index| {
+                let snapshot = self.price_history.get((first + index) % PRICE_HISTORY_SIZE).unwrap();
+                PriceSnapshotInfo {
+                    timestamp: U64(snapshot.timestamp),
+                    total_assets: U128(snapshot.total_assets),
+                    total_shares: U128(snapshot.total_shares),
+                }
+            })
+            .collect()
+    }
+
+    /// Annualized growth of the share price, in bps, from the newest snapshot at least
+    /// `window_sec` seconds old to the latest one. None if there is no such snapshot.
+    pub fn get_apy(&self, window_sec: u64) -> Option<i64> {
+        let len = self.price_history.len();
+        if len < 2 {
+            return None;
+        }
+        let first = self.price_history_count - len;
+        let latest = self.price_history.get((self.price_history_count - 1) % PRICE_HISTORY_SIZE).unwrap();
+        let since = latest.timestamp.checked_sub(sec_to_nanos(window_sec).ok()?)?;
+        let earlier = (first..self.price_history_count - 1)
+            .rev()
+            .map(|index| self.price_history.get(index % PRICE_HISTORY_SIZE).unwrap())
+            .find(|snapshot| snapshot.timestamp <= since)?;
+        let elapsed = latest.timestamp - earlier.timestamp;
+        if elapsed == 0 {
+            return None;
+        }
+        Some((latest.growth_bps(&earlier) * YEAR as i128 / elapsed as i128) as i64)
+    }
+}
+
+impl Contract {
+    /// Records the current share price, overwriting the oldest snapshot once full.
+    pub(crate) fn internal_record_price(&mut self) {
+        let snapshot = PriceSnapshot {
+            timestamp: env::block_timestamp(),
+            total_assets: self.free_lp(),
+            total_shares: self.total_shares,
+        };
+        if self.price_history.len() < PRICE_HISTORY_SIZE {
+            self.price_history.push(&snapshot);
+        } else {
+            self.price_history.replace(self.price_history_count % PRICE_HISTORY_SIZE, &snapshot);
+        }
+        self.price_history_count += 1;
+    }
+}
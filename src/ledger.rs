@@ -0,0 +1,148 @@
+//! Per-account ledger of vault operations.
+//!
+//! Every deposit, withdrawal, emergency claim and harvest bounty of an account is appended
+//! to its ledger with the share price at that time. Entries are paid by the account's
+//! storage deposit: when it doesn't cover a new entry, the entry is dropped. Accounts can
+//! prune their oldest entries with `prune_history` and reclaim the storage with
+//! `storage_withdraw`.
+
+use near_sdk::json_types::U64;
+use near_sdk::StorageUsage;
+
+use crate::account_deposit::STORAGE_RECORD_OVERHEAD;
+use crate::*;
+
+/// Storage of one entry: collection key with the account id and the entry id,
+/// the entry itself and the storage record overhead.
+pub(crate) const LEDGER_ENTRY_STORAGE: StorageUsage = 77 + 142 + STORAGE_RECORD_OVERHEAD;
+/// Shares the recorded share price is expressed for.
+const SHARE_PRICE_UNIT: Balance = 1_000_000_000_000_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum LedgerKind {
+    /// Shares minted for a direct deposit or a claimed epoch deposit.
+    Deposit,
+    /// Shares burned by a withdrawal.
+    Withdraw,
+    /// Burned shares given back after an aborted withdrawal.
+    Refund,
+    /// Shares exchanged for the pool tokens after an emergency exit.
+    EmergencyClaim,
+    /// Reward tokens received for harvesting.
+    HarvestBounty,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LedgerEntry {
+    pub kind: LedgerKind,
+    pub timestamp: u64,
+    /// Vault shares minted or burned.
+    pub shares: Balance,
+    /// LP the shares are worth.
+    pub lp: Balance,
+    /// LP per 10^24 shares at the time of the operation.
+    pub share_price: Balance,
+    /// Token paid or received with its amount, if any.
+    pub token_id: Option<AccountId>,
+    pub amount: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct LedgerEntryInfo {
+    pub id: u64,
+    pub kind: LedgerKind,
+    pub timestamp: U64,
+    pub shares: U128,
+    pub lp: U128,
+    pub share_price: U128,
+    pub token_id: Option<AccountId>,
+    pub amount: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Ledger entries of given account from the oldest kept, `from_index` 0 being the oldest.
+    pub fn get_user_history(&self, account_id: ValidAccountId, from_index: u64, limit: u64) -> Vec<LedgerEntryInfo> {
+        let account = match self.internal_get_account(account_id.as_ref()) {
+            Some(account) => account,
+            None => return vec![],
+        };
+        let start = account.history_start + from_index;
+        let end = std::cmp::min(start.saturating_add(limit), account.history_end);
+        (start..end)
+            .map(|id| {
+                let entry = account.history.get(&id).unwrap();
+                LedgerEntryInfo {
+                    id,
+                    kind: entry.kind,
+                    timestamp: U64(entry.timestamp),
+                    shares: U128(entry.shares),
+                    lp: U128(entry.lp),
+                    share_price: U128(entry.share_price),
+                    token_id: entry.token_id,
+                    amount: U128(entry.amount),
+                }
+            })
+            .collect()
+    }
+
+    /// Removes the caller's `count` oldest ledger entries, freeing their storage.
+    /// Returns the number of entries removed.
+    #[payable]
+    pub fn prune_history(&mut self, count: u64) -> u64 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self.internal_unwrap_account(&account_id);
+        let end = std::cmp::min(account.history_start + count, account.history_end);
+        for id in account.history_start..end {
+            account.history.remove(&id);
+        }
+        let removed = end - account.history_start;
+        account.history_start = end;
+        self.internal_save_account(&account_id, account);
+        removed
+    }
+}
+
+impl Contract {
+    /// Appends an entry to the ledger of `account_id`, dropped if the account
+    /// is not registered or its storage deposit doesn't cover it.
+    pub(crate) fn internal_append_history(
+        &mut self,
+        account_id: &AccountId,
+        kind: LedgerKind,
+        shares: Balance,
+        lp: Balance,
+        token: Option<(AccountId, Balance)>,
+    ) {
+        let mut account = match self.internal_get_account(account_id) {
+            Some(account) => account,
+            None => return,
+        };
+        let (token_id, amount) = match token {
+            Some((token_id, amount)) => (Some(token_id), amount),
+            None => (None, 0),
+        };
+        let entry = LedgerEntry {
+            kind,
+            timestamp: env::block_timestamp(),
+            shares,
+            lp,
+            share_price: self.convert_to_assets(SHARE_PRICE_UNIT),
+            token_id,
+            amount,
+        };
+        let id = account.history_end;
+        account.history_end += 1;
+        if account.storage_usage() > account.near_amount {
+            log!("Account {} has not enough storage. Ledger entry dropped.", account_id);
+            return;
+        }
+        account.history.insert(&id, &entry);
+        self.internal_save_account(account_id, account);
+    }
+}
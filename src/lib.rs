@@ -7,18 +7,55 @@ use near_contract_standards::storage_management::{
 };
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedSet};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
 use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{assert_one_yocto, env, log, near_bindgen, PromiseResult, Balance, AccountId, PanicOnDefault, Promise, ext_contract,BorshStorageKey
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, PromiseResult, Balance, AccountId, PanicOnDefault, Promise, PromiseOrValue, ext_contract,BorshStorageKey
 };
 
 
 
 use crate::account_deposit::{VAccount, Account};
+use crate::council::Proposal;
+use crate::emergency::EmergencyState;
+use crate::errors::{OrPanic, VaultError};
+use crate::epoch::{EpochState, EpochSettlement, PendingDeposit};
+use crate::history::PriceSnapshot;
+use crate::lostfound::LostfoundAccount;
+use crate::ledger::LedgerKind;
+use crate::utils::{checked_add, checked_sub, mul_div};
+use crate::oracle::OracleConfig;
+pub use crate::owner::AdminAction;
+pub use crate::pnl::BurnRecord;
+use crate::pnl::UserPnl;
+pub use crate::routes::RouteHop;
+use crate::timelock::{QueuedChange, MIN_TIMELOCK_DELAY_SEC};
+pub use crate::unwind::Unwind;
+use crate::whitelist::TokenMetadata;
+use crate::withdraw_limit::{QueuedWithdraw, WithdrawLimiter};
+pub use crate::zap::optimal_split;
+use crate::zap::{POOL_ID, POOL_TOKENS, TOKEN_WRAP};
 mod account_deposit;
-//mod owner;
+mod caps;
+mod council;
+mod emergency;
+mod epoch;
+mod errors;
+mod harvest;
+mod ledger;
+mod lostfound;
+mod history;
+mod oracle;
+mod owner;
+mod pnl;
+mod routes;
 mod storage_impl;
+mod timelock;
 mod token_receiver;
+mod unwind;
+mod utils;
+mod whitelist;
+mod withdraw_limit;
+mod zap;
 
 
 /// Single swap action.
@@ -49,9 +86,30 @@ near_sdk::setup_alloc!();
 pub(crate) enum StorageKey {
     Accounts,
     UserShares,
+    #[allow(dead_code)]
     VaultShares,
     Whitelist,
     AccountTokens {account_id: AccountId},
+    AccountHistory {account_id: AccountId},
+    PendingDeposits,
+    EpochSettlements,
+    ZapDust,
+    Routes,
+    DepositLimits,
+    WithdrawQueue,
+    Guardians,
+    PriceHistory,
+    UserPnl,
+    Lostfound,
+    Council,
+    Proposals,
+    PendingChanges,
+    TokenDeposits,
+    TokenMetadata,
+    LostfoundAccounts,
+    Stranded,
+    InFlight,
+    StrandedTotals,
 }
 
 
@@ -66,6 +124,23 @@ pub struct RefStorageState {
 
 
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct VaultInfo {
+    pub total_shares: U128,
+    /// LP owned by the vault, including locked profit.
+    pub total_lp: U128,
+    /// LP backing the shares at the current share price.
+    pub free_lp: U128,
+    /// Compounded LP still being released.
+    pub locked_profit: U128,
+    /// Time over which compounded LP is released, in seconds.
+    pub profit_unlock_period: u64,
+}
+
+
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Eq, PartialEq, Clone)]
 #[serde(crate = "near_sdk::serde")]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
@@ -75,6 +150,28 @@ pub enum RunningState {
 
 
 
+/// Receiver of the vault shares minted for the LP added by a zap.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum ShareRecipient {
+    /// Direct deposit of a single account, refunded if it mints less than `min_shares`.
+    Account { account_id: AccountId, min_shares: U128 },
+    /// Batched deposits of an epoch, claimed pro rata by its depositors.
+    Epoch(u64),
+    /// Compounded rewards, no shares are minted.
+    Compound,
+}
+
+impl ShareRecipient {
+    /// Whether dust left by this zap is kept by the vault instead of sent to an account.
+    pub fn tracks_dust(&self) -> bool {
+        !matches!(self, ShareRecipient::Account { .. })
+    }
+}
+
+
+
 impl fmt::Display for RunningState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -91,14 +188,108 @@ impl fmt::Display for RunningState {
 pub struct Contract {
     owner_id: AccountId,
     user_shares: LookupMap<AccountId, u128>,
+    /// LP shares of the strategy pool held idle by the vault on the exchange.
     vault_shares: u128,
+    /// Total vault shares owned by depositors, including unclaimed epoch shares.
+    total_shares: u128,
+    /// LP shares of the strategy pool owned by the vault, staked or idle.
+    total_lp: u128,
     accounts: LookupMap<AccountId, VAccount>,
     whitelisted_tokens: UnorderedSet<AccountId>,
     state: RunningState,
+    /// If set, `add_to_vault` queues deposits for the next epoch settlement.
+    epoch_mode: bool,
+    /// Minimum epoch length in nanoseconds before it can be settled.
+    epoch_duration: u64,
+    epoch: EpochState,
+    pending_deposits: LookupMap<AccountId, PendingDeposit>,
+    epoch_settlements: LookupMap<u64, EpochSettlement>,
+    /// Pool tokens left on the exchange by epoch settlements and compounding.
+    zap_dust: UnorderedMap<AccountId, Balance>,
+    /// Swap routes keyed by (token_in, token_out).
+    routes: UnorderedMap<(AccountId, AccountId), Vec<RouteHop>>,
+    /// Harvested reward tokens on the exchange waiting to be compounded.
+    pending_reward: Balance,
+    /// Harvested reward tokens in the vault's wallet whose deposit on the exchange
+    /// failed, deposited again by the next harvest.
+    idle_reward: Balance,
+    /// Share of each harvest paid to its caller, in basis points.
+    harvest_bounty_bps: u32,
+    /// Minimum time between two harvests in nanoseconds.
+    harvest_interval: u64,
+    /// Minimum claimable reward for a harvest to go through.
+    min_harvest_reward: Balance,
+    /// Timestamp of the last successful harvest.
+    last_harvest: u64,
+    /// Compounded LP not yet counted in the share price, as of `locked_profit_at`.
+    locked_profit: Balance,
+    locked_profit_at: u64,
+    /// Time in nanoseconds over which compounded LP is released into the share price.
+    profit_unlock_period: u64,
+    /// Minimum LP a single deposit must add, smaller deposits are refunded.
+    min_deposit_lp: Balance,
+    /// Maximum LP owned by the vault, `Balance::MAX` if unlimited.
+    max_total_assets: Balance,
+    /// Maximum LP owned by a single account, `Balance::MAX` if unlimited.
+    max_user_assets: Balance,
+    /// Maximum amount of a token accepted in a single deposit.
+    deposit_limits: UnorderedMap<AccountId, Balance>,
+    withdraw_limiter: WithdrawLimiter,
+    /// Withdrawals waiting for room in the withdraw window, by queue id.
+    withdraw_queue: LookupMap<u64, QueuedWithdraw>,
+    /// Accounts allowed to raise the withdraw limit temporarily and to trigger an emergency exit.
+    guardians: UnorderedSet<AccountId>,
+    /// Set once an emergency exit started.
+    emergency: Option<EmergencyState>,
+    /// Price oracle checked before swapping, None to disable the check.
+    oracle: Option<OracleConfig>,
+    /// Ring buffer of share price snapshots.
+    price_history: Vector<PriceSnapshot>,
+    /// Number of snapshots ever recorded.
+    price_history_count: u64,
+    /// Value in yoctoNEAR of 10^24 LP, as of the last zap.
+    lp_price_near: Balance,
+    /// Cost basis and gains of each account, in NEAR.
+    user_pnl: LookupMap<AccountId, UserPnl>,
+    /// Account proposed as the next owner, until it accepts.
+    pending_owner: Option<AccountId>,
+    /// Total of each token kept in lostfound.
+    lostfound: UnorderedMap<AccountId, Balance>,
+    /// Tokens kept in lostfound for each account.
+    lostfound_accounts: LookupMap<AccountId, LostfoundAccount>,
+    /// Accounts that can make and approve proposals.
+    council: UnorderedSet<AccountId>,
+    /// Approvals needed to execute a proposal.
+    council_threshold: u32,
+    /// Lifetime of a proposal, in nanoseconds.
+    proposal_period: u64,
+    proposals: LookupMap<u64, Proposal>,
+    /// Number of proposals ever made, also the id of the next one.
+    proposal_count: u64,
+    /// Delay between queueing a configuration change and executing it, in nanoseconds.
+    timelock_delay: u64,
+    pending_changes: UnorderedMap<u64, QueuedChange>,
+    /// Number of changes ever queued, also the id of the next one.
+    change_count: u64,
+    /// Total of each token held in account balances.
+    token_deposits: LookupMap<AccountId, Balance>,
+    /// Cached metadata of the whitelisted tokens.
+    token_metadata: LookupMap<AccountId, TokenMetadata>,
+    /// Tokens of failed unwinds and zap refunds left on the exchange for each account.
+    stranded: LookupMap<AccountId, HashMap<AccountId, Balance>>,
+    /// Total of each token stranded, over all accounts.
+    stranded_totals: LookupMap<AccountId, Balance>,
+    /// Tokens on their way into or out of the vault's wallet, owed to accounts or the vault.
+    in_flight: LookupMap<AccountId, Balance>,
 }
 
 
 
+/// Virtual shares and LP added to the share math, so that donating LP to a nearly
+/// empty vault costs the donor far more than it can take from the next depositor.
+const VIRTUAL_SHARES: u128 = 1_000;
+const VIRTUAL_LP: u128 = 1;
+
 const CONTRACT_ID: &str = "exchange.ref-dev.testnet";
 const CONTRACT_ID_WRAP: &str = "wrap.testnet";
 const CONTRACT_ID_FARM: &str = "farm110.ref-dev.testnet";
@@ -118,6 +309,10 @@ pub trait RefExchange {
         pool_id: u64,
         account_id: AccountId,
     );
+    fn get_pool(
+        &mut self,
+        pool_id: u64,
+    );
     fn metadata(&mut self);
     fn storage_deposit(
         &mut self, 
@@ -127,6 +322,7 @@ pub trait RefExchange {
         &mut self, 
         account_id: ValidAccountId,
     );
+    /// Returns the LP shares added.
     fn add_liquidity(
         &mut self,
         pool_id: u64,
@@ -193,6 +389,10 @@ pub trait RefFarming {
         token_id: ValidAccountId
 
     );
+    fn list_user_seeds(
+        &self,
+        account_id: ValidAccountId
+    );
 
 }
 
@@ -221,11 +421,34 @@ pub trait Wrapnear {
 
 #[ext_contract(ext_self)]
 pub trait VaultContract {
-    fn callback_get_deposits(&mut self, account_id: ValidAccountId, vault_contract: ValidAccountId) ->Vec<U128>;
-    fn callback_get_pool_shares(&mut self, account_id: ValidAccountId) -> String;
+    fn callback_zap_quote(&mut self, token_in: AccountId, amount: U128, recipient: ShareRecipient, pool_ids: Vec<u64>) -> Option<Promise>;
+    fn callback_zap_swaps(&mut self, recipient: ShareRecipient, token_in: AccountId, amounts: Vec<U128>, swapped: Vec<U128>) -> Option<Vec<U128>>;
+    fn callback_zap_dust(&mut self, recipient: ShareRecipient, amounts: Vec<U128>, lp: U128, removed: bool);
+    fn callback_post_dust_withdraw(&mut self, account_id: AccountId, token_id: AccountId, amount: U128);
+    fn callback_post_token_deposit(&mut self, token_id: AccountId, amount: U128, recipient: ShareRecipient);
+    fn callback_epoch_wrap(&mut self, account_id: AccountId, amount: U128) -> Option<Promise>;
+    fn callback_queue_deposit(&mut self, account_id: AccountId, amount: U128);
+    fn callback_get_pool_shares(&mut self, recipient: ShareRecipient, amounts: Vec<U128>) -> U128;
+    fn callback_post_stake(&mut self, amount: U128);
     fn call_get_pool_shares(&mut self, pool_id: u64, account_id: AccountId) -> String;
-    fn callback_get_reward(&mut self, token_id: String) -> String;
-    fn swap_to_withdraw_all(&mut self);
+    fn callback_get_reward(&mut self, harvester_id: AccountId) -> U128;
+    fn callback_post_withdraw_reward(&mut self, harvester_id: AccountId, reward: U128);
+    fn callback_post_reward_deposit(&mut self, amount: U128);
+    fn callback_unwind_check(&mut self, lp: U128, unwind: Unwind, pool_ids: Vec<u64>) -> Option<Promise>;
+    fn callback_unwind_swaps(&mut self, lp: U128, unwind: Unwind, min_amounts_out: Vec<U128>) -> Promise;
+    fn callback_unwind_restake(&mut self, lp: U128) -> Option<Promise>;
+    fn callback_unwind_withdraw(&mut self, unwind: Unwind, direct: U128, swapped: Vec<(AccountId, U128)>) -> Option<Promise>;
+    fn callback_unwind_deliver(&mut self, unwind: Unwind, amount: U128) -> Option<Promise>;
+    fn callback_post_near_withdraw(&mut self, unwind: Unwind, amount: U128) -> Option<Promise>;
+    fn callback_post_near_transfer(&mut self, unwind: Unwind, amount: U128);
+    fn callback_post_stranded_withdraw(&mut self, account_id: AccountId, token_id: AccountId, amount: U128);
+    fn callback_emergency_unstake(&mut self) -> Promise;
+    fn callback_emergency_unstaked(&mut self) -> Option<Promise>;
+    fn callback_emergency_remove(&mut self) -> Option<Promise>;
+    fn callback_emergency_withdraw(&mut self) -> Option<Promise>;
+    fn callback_emergency_received(&mut self, token_id: AccountId, amount: U128);
+    fn callback_ft_metadata(&mut self, token_id: AccountId);
+    fn callback_sweep(&mut self, token_id: AccountId) -> Option<Promise>;
 }
 
 
@@ -234,22 +457,87 @@ pub trait VaultContract {
 impl Contract {
     #[init]
     pub fn new(owner_id: ValidAccountId, vault_shares: u128/*, exchange_fee: u32, referral_fee: u32*/) -> Self {
-        Self {
+        let mut this = Self {
             owner_id: owner_id.as_ref().clone(),
             user_shares: LookupMap::new(StorageKey::UserShares),
             vault_shares,
+            total_shares: 0,
+            total_lp: 0,
             accounts: LookupMap::new(StorageKey::Accounts),
             whitelisted_tokens: UnorderedSet::new(StorageKey::Whitelist),
             state: RunningState::Running,
+            epoch_mode: false,
+            epoch_duration: 0,
+            epoch: EpochState::new(0),
+            pending_deposits: LookupMap::new(StorageKey::PendingDeposits),
+            epoch_settlements: LookupMap::new(StorageKey::EpochSettlements),
+            zap_dust: UnorderedMap::new(StorageKey::ZapDust),
+            routes: UnorderedMap::new(StorageKey::Routes),
+            pending_reward: 0,
+            idle_reward: 0,
+            harvest_bounty_bps: 0,
+            harvest_interval: 0,
+            min_harvest_reward: 0,
+            last_harvest: 0,
+            locked_profit: 0,
+            locked_profit_at: 0,
+            profit_unlock_period: 0,
+            min_deposit_lp: 0,
+            max_total_assets: Balance::MAX,
+            max_user_assets: Balance::MAX,
+            deposit_limits: UnorderedMap::new(StorageKey::DepositLimits),
+            withdraw_limiter: WithdrawLimiter::new(),
+            withdraw_queue: LookupMap::new(StorageKey::WithdrawQueue),
+            guardians: UnorderedSet::new(StorageKey::Guardians),
+            emergency: None,
+            oracle: None,
+            price_history: Vector::new(StorageKey::PriceHistory),
+            price_history_count: 0,
+            lp_price_near: 0,
+            user_pnl: LookupMap::new(StorageKey::UserPnl),
+            pending_owner: None,
+            lostfound: UnorderedMap::new(StorageKey::Lostfound),
+            lostfound_accounts: LookupMap::new(StorageKey::LostfoundAccounts),
+            council: UnorderedSet::new(StorageKey::Council),
+            council_threshold: 0,
+            proposal_period: 0,
+            proposals: LookupMap::new(StorageKey::Proposals),
+            proposal_count: 0,
+            timelock_delay: MIN_TIMELOCK_DELAY_SEC * 1_000_000_000,
+            pending_changes: UnorderedMap::new(StorageKey::PendingChanges),
+            change_count: 0,
+            token_deposits: LookupMap::new(StorageKey::TokenDeposits),
+            token_metadata: LookupMap::new(StorageKey::TokenMetadata),
+            stranded: LookupMap::new(StorageKey::Stranded),
+            stranded_totals: LookupMap::new(StorageKey::StrandedTotals),
+            in_flight: LookupMap::new(StorageKey::InFlight),
+        };
+        // direct pools between wNEAR and the strategy pool tokens
+        for (pool_id, token_id) in [(84, POOL_TOKENS[0]), (83, POOL_TOKENS[1])] {
+            this.routes.insert(
+                &(TOKEN_WRAP.to_string(), token_id.to_string()),
+                &vec![RouteHop { pool_id, token_out: token_id.to_string() }],
+            );
+            this.routes.insert(
+                &(token_id.to_string(), TOKEN_WRAP.to_string()),
+                &vec![RouteHop { pool_id, token_out: TOKEN_WRAP.to_string() }],
+            );
         }
+        this
     }
 
-    
-    #[payable]
-    pub fn extend_whitelisted_tokens(&mut self, tokens: Vec<ValidAccountId>) {
-        for token in tokens {
-            self.whitelisted_tokens.insert(token.as_ref());
-        }
+    /// Queues the whitelisting of `tokens`, whose metadata is then fetched and cached.
+    /// Only callable by the owner.
+    pub fn extend_whitelisted_tokens(&mut self, tokens: Vec<ValidAccountId>) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::ExtendWhitelistedTokens { tokens })
+    }
+
+    /// Queues the removal of `tokens` from the whitelist. The change fails if an account
+    /// or lostfound still holds any of them. Only callable by the owner.
+    pub fn remove_whitelisted_tokens(&mut self, tokens: Vec<ValidAccountId>) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::RemoveWhitelistedTokens { tokens })
     }
 
 
@@ -259,19 +547,40 @@ impl Contract {
     }
 
 
+    /// Vault shares owned by given account, not counting unclaimed epoch shares.
+    pub fn get_user_shares(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.user_shares.get(account_id.as_ref()).unwrap_or(0))
+    }
+
+    /// Vault shares that depositing `lp` LP would mint at the current share price.
+    pub fn preview_deposit(&self, lp: U128) -> U128 {
+        U128(self.convert_to_shares(lp.0))
+    }
+
+    /// LP that `shares` vault shares are worth at the current share price.
+    pub fn preview_redeem(&self, shares: U128) -> U128 {
+        U128(self.convert_to_assets(shares.0))
+    }
+
+    /// Share accounting of the vault, with the profit still locked.
+    pub fn get_vault_info(&self) -> VaultInfo {
+        VaultInfo {
+            total_shares: U128(self.total_shares),
+            total_lp: U128(self.total_lp),
+            free_lp: U128(self.free_lp()),
+            locked_profit: U128(self.internal_locked_profit()),
+            profit_unlock_period: self.profit_unlock_period / 1_000_000_000,
+        }
+    }
+
+
     /// Get user's storage deposit and needed in the account of current version
     pub fn get_user_storage_state(&self, account_id: ValidAccountId) -> Option<RefStorageState> {
         let acc = self.internal_get_account(account_id.as_ref());
-        if let Some(account) = acc {
-            Some(
-                RefStorageState {
-                    deposit: U128(account.near_amount),
-                    usage: U128(account.storage_usage()),
-                }
-            )           
-        } else {
-            None
-        }
+        acc.map(|account| RefStorageState {
+            deposit: U128(account.near_amount),
+            usage: U128(account.storage_usage()),
+        })
     }
 
 
@@ -309,64 +618,15 @@ impl Contract {
     }
 
 
-    fn call_get_deposits(&self, account_id: ValidAccountId) -> Promise {
-        ext_exchange::get_deposits(
-        account_id,    
-        &CONTRACT_ID, // contract account id
-        1, // yocto NEAR to attach
-        30_000_000_000_000 // gas to attach
-        )
-    }
-
-
+    /// Wraps `amount` of the caller's investable NEAR and sends the wNEAR to `receiver_id`
+    /// with `ft_transfer_call`.
     #[payable]
-    //Para trocar near em wnear
-    pub fn near_to_wrap(&mut self, account_id: ValidAccountId, receiver_id: AccountId, amount: String, msg: String) {
-        //Registro de usuário
-        log!("Entrei no near_to_wrap");
-        let acc = self.internal_get_account(account_id.as_ref());
-        let mut x: u128 = 0; 
-        if let Some(account) = acc {
-            Some(
-                x = account.storage_available()
-            )     
-        } else {
-            None
-        };
-
-        let mut k = true;
-
-        let quantity =  amount.parse::<u128>().unwrap();
-        if x < quantity {k = false};
-        assert!(k, "ERROR 1: User doesnt have balance.");
-
-        self.internal_register_account_sub(&account_id.to_string(), quantity);
-        let amount:u128 = amount.parse::<u128>().unwrap();
-
-        /*
-        ext_wrap::storage_deposit(
-            &CONTRACT_ID_WRAP, // contract account id
-            1250000000000000000000, // yocto NEAR to attach
-            35_000_000_000_000 // gas to attach
-        )
-        .then(*/
-            ext_wrap::near_deposit(
-                &CONTRACT_ID_WRAP, // contract account id
-                amount.to_string().parse().unwrap(), // yocto NEAR to attach
-                3_000_000_000_000 // gas to attach
-            )
-        //)
-        .then(
-            ext_wrap::ft_transfer_call(
-                receiver_id,//receiver_id,
-                amount.to_string(),
-                msg,
-                &CONTRACT_ID_WRAP, // contract account id
-                1, // yocto NEAR to attach
-                35_000_000_000_000 // gas to attach                
-            )
-        );
-
+    pub fn near_to_wrap(&mut self, receiver_id: ValidAccountId, amount: U128, msg: String) -> Promise {
+        assert_one_yocto();
+        self.assert_contract_running();
+        assert!(amount.0 > 0, "{}", VaultError::ZeroAmount);
+        self.internal_withdraw_near(&env::predecessor_account_id(), amount.0);
+        self.internal_wrap_near(receiver_id.into(), amount.0, msg)
     }
 
 
@@ -469,199 +729,128 @@ impl Contract {
     }
 
 
-    #[payable]
-    pub fn withdraw_of_reward(&mut self,vault_contract: ValidAccountId) {
-
-        let token_id = "ref.fakes.testnet".to_string();
-        let seed_id = "exchange.ref-dev.testnet@193".to_string();
-
-        self.call_claim(seed_id.clone())  
-        .then(self.call_get_reward(vault_contract.clone(), "ref.fakes.testnet".try_into().unwrap()))
-        
-        //self.call_get_reward(vault_contract.clone(), "ref.fakes.testnet".try_into().unwrap())
-        .then(ext_self::callback_get_reward(token_id, &env::current_account_id(), 1, 190_000_000_000_000));//passar exatamente 190
-    }
-
-
+    /// Mints vault shares for the LP that `add_liquidity` returned for the zap of `amounts`
+    /// and stakes that LP in the farm. If `add_liquidity` failed, `amounts` are given back
+    /// to the recipient.
     #[private]
-    #[payable]
-    pub fn callback_get_deposits(&mut self, account_id: ValidAccountId, vault_contract: ValidAccountId) -> Vec<U128> {
-
-        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
-        let is_tokens = match env::promise_result(0) {
+    pub fn callback_get_pool_shares(&mut self, recipient: ShareRecipient, amounts: Vec<U128>) -> U128 {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let lp_added = match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(tokens) => {
-                if let Ok(is_tokens) = near_sdk::serde_json::from_slice::<HashMap<AccountId, U128>>(&tokens) {
-                    is_tokens
-                } else {
-                    env::panic(b"ERR_WRONG_VAL_RECEIVED")
-                }
-            },
-            PromiseResult::Failed => env::panic(b"ERR_CALL_FAILED"),
-        };   
-        let pool_id_to_add_liquidity = 193;
-        let token_out1 = "eth.fakes.testnet".to_string();
-        let token_out2 = "dai.fakes.testnet".to_string();
-        let mut quantity_of_token1 = U128(0);
-        let mut quantity_of_token2 = U128(0);
-
-        for (key, val) in is_tokens.iter() {
-            if key.to_string() == token_out1 {quantity_of_token1 = *val};
-            if key.to_string() == token_out2 {quantity_of_token2 = *val};
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .unwrap_or_else(|_| VaultError::WrongValueReceived.panic())
+                .0,
+            PromiseResult::Failed => {
+                log!("Adding liquidity failed, giving the zap back");
+                self.internal_abort_liquidity(recipient, amounts);
+                return U128(0);
+            }
+        };
+        assert!(lp_added > 0, "{}", VaultError::NoLpAdded);
+        let minted = match recipient {
+            ShareRecipient::Compound => 0,
+            _ => self.convert_to_shares(lp_added),
+        };
+        if let ShareRecipient::Account { account_id, min_shares } = &recipient {
+            if lp_added > self.internal_max_deposit(account_id) {
+                log!("Added {} LP, more than the deposit cap allows. Refunding.", lp_added);
+                self.internal_refund_zap(recipient, amounts, lp_added);
+                return U128(0);
+            }
+            if lp_added < self.min_deposit_lp {
+                log!("Added {} LP, less than the minimum deposit {}. Refunding.", lp_added, self.min_deposit_lp);
+                self.internal_refund_zap(recipient, amounts, lp_added);
+                return U128(0);
+            }
+            if minted < min_shares.0 {
+                log!("Minted {} shares, less than the minimum {}. Refunding.", minted, min_shares.0);
+                self.internal_refund_zap(recipient, amounts, lp_added);
+                return U128(0);
+            }
         }
-       
-        let pool_id: u64 = 193;
-        let seed_id = "exchange.ref-dev.testnet@193".to_string();
-
 
-        self.call_add_liquidity(pool_id_to_add_liquidity, vec![quantity_of_token2, quantity_of_token1], None)
-        .then(ext_self::call_get_pool_shares(pool_id.clone(), vault_contract.clone().to_string(),&env::current_account_id(), 0, 18_000_000_000_000))
-        .then(ext_self::callback_get_pool_shares(account_id.clone(), &env::current_account_id(), 0, 5_000_000_000_000))
-        .then(self.call_stake(CONTRACT_ID_FARM.to_string(), ":193".to_string(), U128(self.user_shares.get(&account_id.to_string()).expect("sla oq")), "".to_string()));
-        //.then(self.call_claim(seed_id.clone()))       
-        //.then(self.call_get_reward(vault_contract.clone(), "ref.fakes.testnet".try_into().unwrap()))
-        
+        if let ShareRecipient::Compound = recipient {
+            self.internal_lock_profit(lp_added);
+        }
+        self.total_shares = checked_add(self.total_shares, minted).or_panic();
+        self.total_lp = checked_add(self.total_lp, lp_added).or_panic();
+        self.vault_shares = checked_add(self.vault_shares, lp_added).or_panic();
+        match &recipient {
+            ShareRecipient::Account { account_id, .. } => {
+                let user_shares = self.user_shares.get(account_id).unwrap_or(0);
+                self.user_shares.insert(account_id, &checked_add(user_shares, minted).or_panic());
+                self.internal_record_mint(account_id, self.internal_lp_value(lp_added));
+                self.internal_append_history(account_id, LedgerKind::Deposit, minted, lp_added, None);
+            }
+            ShareRecipient::Epoch(epoch_id) => {
+                self.internal_record_settlement(*epoch_id, minted);
+                self.internal_record_price();
+            }
+            ShareRecipient::Compound => {
+                self.internal_record_price();
+            }
+        }
+        log!("Minted {} shares for {} LP", minted, lp_added);
 
-        let x = vec![quantity_of_token2, quantity_of_token1];
-        x
+        self.call_stake(CONTRACT_ID_FARM.to_string(), format!(":{}", POOL_ID), U128(lp_added), "".to_string())
+        .then(ext_self::callback_post_stake(U128(lp_added), &env::current_account_id(), 0, 5_000_000_000_000));
+        self.internal_collect_zap_dust(recipient, amounts, lp_added);
 
+        U128(minted)
     }
 
 
-    //Change the user_balance and the vault balance of lps/shares
+    /// Moves the staked LP out of the idle balance once the farm accepted it.
     #[private]
-    pub fn callback_get_pool_shares(&mut self, account_id: ValidAccountId ) -> String {
-        
-        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
-        let shares = match env::promise_result(0) {
+    pub fn callback_post_stake(&mut self, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(tokens) => {
-                if let Ok(shares) = near_sdk::serde_json::from_slice::<String>(&tokens) {
-                    shares
-                } else {
-                    env::panic(b"ERR_WRONG_VAL_RECEIVED")
-                }
-            },
-            PromiseResult::Failed => env::panic(b"ERR_CALL_FAILED"),
-        };  
-
-        let x = shares.parse::<u128>().unwrap() - self.vault_shares;
-        let y = self.user_shares.get(&account_id.to_string());
-        let mut k: u128 = 0;
-
-        if shares.parse::<u128>().unwrap() > self.vault_shares {
-            if let Some(yy) = y {
-                Some(
-                    k = yy + x
-                )     
-            } else {
-                None
-            };
-            self.user_shares.insert(&account_id.to_string(), &k);
-            
+            PromiseResult::Successful(_) => {
+                self.vault_shares = self.vault_shares.saturating_sub(amount.0);
+            }
+            PromiseResult::Failed => {
+                log!("Stake of {} LP failed, keeping it idle", amount.0);
+            }
         };
-        self.vault_shares = shares.parse::<u128>().unwrap();
-
-        shares
-    }
-
-
-    #[payable]
-    #[private]
-    pub fn callback_get_reward(&mut self, token_id: String) -> U128 {
-        
-        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
-        let shares = match env::promise_result(0) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(tokens) => {
-                if let Ok(shares) = near_sdk::serde_json::from_slice::<U128>(&tokens) {
-                    ext_farm::withdraw_reward(
-                        token_id,
-                        shares,
-                        "false".to_string(),
-                        &CONTRACT_ID_FARM, // contract account id
-                        1, // yocto NEAR to attach
-                        180_000_000_000_000 // gas to attach
-                    );
-                    shares
-                } else {
-                    env::panic(b"ERR_WRONG_VAL_RECEIVED")
-                }
-            },
-            PromiseResult::Failed => env::panic(b"ERR_CALL_FAILED"),
-        };  
-        
-        shares
     }
 
 
     //Main vault function
+    /// Invests all of the caller's investable NEAR in the vault, the attached deposit included.
     #[payable]
-    pub fn add_to_vault(&mut self, account_id: ValidAccountId, vault_contract: ValidAccountId /*, amount: String, msg: String*/) -> String  {
+    pub fn add_to_vault(&mut self) -> String  {
+        self.assert_contract_running();
+        let account_id = env::predecessor_account_id();
+        if env::attached_deposit() > 0 {
+            let mut account = self.internal_unwrap_account(&account_id);
+            account.deposit_near(env::attached_deposit()).or_panic();
+            self.internal_save_account(&account_id, account);
+        }
 
-        
         //Getting user's near deposits.
-        let acc = self.internal_get_account(account_id.as_ref());
-        let mut x: u128 = 0; 
-        if let Some(account) = acc {
-            Some(
-                x = account.storage_available()
-            )     
-        } else {
-            None
-        };
-
-        log!("x é igual a: {}", x);
+        let acc = self.internal_get_account(&account_id);
+        let x: u128 = acc.map(|account| account.near_deposit).unwrap_or(0);
 
-        let mut k = true;
-        if x == 0 {k = false};
-        assert!(k, "ERROR 1: User doesnt have balance.");
+        assert!(x > 0, "{}", VaultError::NotEnoughNear);
 
         let amount:u128 = x;
+        self.assert_not_emergency();
+        self.assert_deposit_allowed(&account_id, &TOKEN_WRAP.to_string(), amount);
+
+        if self.epoch_mode {
+            ///////////////Queueing the deposit for the epoch settlement///////////////
+            self.internal_withdraw_near(&account_id, amount);
+            self.internal_epoch_deposit(account_id, amount);
+            return "QUEUED".to_string();
+        }
 
-        ///////////////Quantity of shares///////////////
-        let pool_id: u64 = 193;
-        self.call_get_pool_shares(pool_id.clone(), vault_contract.clone().to_string())
-        .then(ext_self::callback_get_pool_shares(account_id.clone(), &env::current_account_id(), 0, 3_000_000_000_000));
-
-        ///////////////Swapping Near to others///////////////
-        let pool_id_to_swap1 = 83;
-        let pool_id_to_swap2 = 84;
-        let token_in1 = "wrap.testnet".to_string();
-        let token_in2 = "wrap.testnet".to_string();
-        let token_out1 = "eth.fakes.testnet".to_string();
-        let token_out2 = "dai.fakes.testnet".to_string();
-        let min_amount_out = U128(0);
-        let amount_in = Some(U128(amount/2));
-
-        let actions = vec![SwapAction {
-            pool_id: pool_id_to_swap1,//Todo
-            token_in: token_in1,
-            token_out: token_out1,
-            amount_in: amount_in,
-            min_amount_out: min_amount_out,
-        }];
-        self.call_swap(actions, None);
-
-        let actions2 = vec![SwapAction {
-            pool_id: pool_id_to_swap2,//Todo
-            token_in: token_in2,
-            token_out: token_out2,
-            amount_in: amount_in,
-            min_amount_out: min_amount_out,
-        }];
-        self.call_swap(actions2, None);
-
-        
-        ///////////////Adding liquidity, staking and claiming rewards///////////////
-       
-        self.call_get_deposits(vault_contract.clone())
-        .then(ext_self::callback_get_deposits(account_id.clone(),vault_contract.clone(), &env::current_account_id(), 970000000000000000000, 200_000_000_000_000));//Passar 70 sem o stake rola.
-        
-     
-        ///////////////Updating the user balance of tokens, near and lp///////////////
-        //subtracting the near used of users account
-        //self.internal_register_account_sub(&account_id.to_string(), amount);
+        ///////////////Wrapping, swapping, adding liquidity and staking///////////////
+        self.internal_withdraw_near(&account_id, amount);
+        self.internal_zap_near(
+            amount,
+            ShareRecipient::Account { account_id, min_shares: U128(0) },
+        );
 
 
         "OK!".to_string()
@@ -669,125 +858,66 @@ impl Contract {
     }
 
 
-    pub fn withdraw_all(&mut self, seed_id: String, amount: String, msg: String, vault_contract: ValidAccountId, account_id: ValidAccountId) /*-> Promise*/ {
-
-        let user_lps = self.user_shares.get(&account_id.to_string());
-        let mut x: u128 = 0; 
-        if let Some(temp) = user_lps {
-            Some(
-                x = temp
-            )     
-        } else {
-            None
-        };
-
-        let mut k = true;
-        let quantity = amount.parse::<u128>().unwrap();
-        if x < quantity {k = false};
-        assert!(k, "ERROR 2: User doesn't have lps for this.");
-        let value = x - quantity;
-        self.user_shares.insert(&account_id.to_string(), &value);
-
-
-        //Registro de usuário
-        //let vault_contract
-        let pool_id: u64 = 193;
-        let min_amounts:Vec<U128> = vec![U128(1000),U128(1000)];
-        let amount: u128 = amount.parse().unwrap();
-
-        //Fazendo unstake do lp
-        ext_farm::withdraw_seed(
-            seed_id,
-            U128(quantity).clone(),
-            msg,
-            &CONTRACT_ID_FARM, // contract account id
-            1, // yocto NEAR to attach
-            110_000_000_000_000 // gas to attach
+    /// Zaps `amount` of `token_id` from the caller's deposited balance into the vault.
+    /// If less than `min_shares` are minted, the liquidity is removed and the pool tokens
+    /// are credited to the caller's balance instead.
+    #[payable]
+    pub fn deposit_from_balance(&mut self, token_id: ValidAccountId, amount: U128, min_shares: U128) -> Promise {
+        assert_one_yocto();
+        self.assert_contract_running();
+        let token_id: AccountId = token_id.into();
+        self.assert_not_emergency();
+        assert!(amount.0 > 0, "{}", VaultError::ZeroAmount);
+        assert!(self.whitelisted_tokens.contains(&token_id), "{}", VaultError::TokenNotWhitelisted);
+        // the zap needs a route to both pool tokens
+        assert!(self.internal_find_zap_routes(&token_id).is_some(), "{}", VaultError::NoRoute);
+        let sender_id = env::predecessor_account_id();
+        self.assert_deposit_allowed(&sender_id, &token_id, amount.0);
+        let mut account = self.internal_unwrap_account(&sender_id);
+        account.withdraw(&token_id, amount.0).or_panic();
+        self.internal_save_account(&sender_id, account);
+        self.internal_track_withdraw(&token_id, amount.0);
+
+        self.internal_deposit_to_exchange(
+            &token_id,
+            amount.0,
+            ShareRecipient::Account { account_id: sender_id, min_shares },
         )
-        .then(
-        //Taking off the liquidity
-        ext_exchange::remove_liquidity(
-            pool_id,
-            U128(quantity),
-            min_amounts,
-            &CONTRACT_ID, // contract account id
-            1, // yocto NEAR to attach
-            7_000_000_000_000 // gas to attach
-        ))
-        .then(self.call_get_deposits(vault_contract.clone()))
-        .then(ext_self::swap_to_withdraw_all(&env::current_account_id(), 0, 32_000_000_000_000))
-        .then(ext_exchange::withdraw("wrap.testnet".to_string(), U128(amount), Some(false),  &CONTRACT_ID, 1, 70_000_000_000_000))
-        .then(
-            ext_wrap::near_withdraw(
-                U128(amount),
-                &CONTRACT_ID_WRAP, // contract account id
-                1, // yocto NEAR to attach
-                3_000_000_000_000 // gas to attach
-            )
-        );
-        self.internal_register_account(&account_id.to_string(), amount);
-
-    } 
-
+    }
 
-    #[private]
+    /// Burns `amount` of the caller's vault shares and withdraws the LP they are worth,
+    /// delivered to `receiver_id` (the caller by default) in `output_token`.
+    /// `output_token` can be any whitelisted token routable from the pool tokens,
+    /// or None for native NEAR.
+    /// If the withdraw limit is reached, the withdrawal is queued and its queue id returned;
+    /// the attached deposit then pays for the storage of the queue entry.
     #[payable]
-    pub fn swap_to_withdraw_all(&mut self) /*-> Promise*/ {
-
-        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
-        let is_tokens = match env::promise_result(0) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(tokens) => {
-                if let Ok(is_tokens) = near_sdk::serde_json::from_slice::<HashMap<AccountId, U128>>(&tokens) {
-                    is_tokens
-                } else {
-                    env::panic(b"ERR_WRONG_VAL_RECEIVED")
-                }
-            },
-            PromiseResult::Failed => env::panic(b"ERR_CALL_FAILED"),
-        };   
-
-        let token_out1 = "eth.fakes.testnet".to_string();
-        let token_out2 = "dai.fakes.testnet".to_string();
-        let mut quantity_of_token1 = U128(0);
-        let mut quantity_of_token2 = U128(0);
-
-        for (key, val) in is_tokens.iter() {
-            if key.to_string() == token_out1 {quantity_of_token1 = *val};
-            if key.to_string() == token_out2 {quantity_of_token2 = *val};
-        }
-
-        ///////////////Swapping Near to others///////////////
-        let pool_id_to_swap1 = 83;
-        let pool_id_to_swap2 = 84;
-        let token_out1 = "wrap.testnet".to_string();
-        let token_out2 = "wrap.testnet".to_string();
-        let token_in1 = "eth.fakes.testnet".to_string();
-        let token_in2 = "dai.fakes.testnet".to_string();
-        let min_amount_out = U128(0);
-        let amount_in1 = Some(quantity_of_token1);
-        let amount_in2 = Some(quantity_of_token2);
-
-        log!("Fazendo swap");
-
-        let actions = vec![SwapAction {
-            pool_id: pool_id_to_swap1,//Todo
-            token_in: token_in1,
-            token_out: token_out1,
-            amount_in: amount_in1,
-            min_amount_out: min_amount_out,
-        }];
-        self.call_swap(actions, None);
-
-        let actions2 = vec![SwapAction {
-            pool_id: pool_id_to_swap2,//Todo
-            token_in: token_in2,
-            token_out: token_out2,
-            amount_in: amount_in2,
-            min_amount_out: min_amount_out,
-        }];
-        self.call_swap(actions2, None);
-    }
+    pub fn withdraw_all(&mut self, amount: U128, output_token: Option<ValidAccountId>, receiver_id: Option<ValidAccountId>) -> PromiseOrValue<Option<u64>> {
+        self.assert_contract_running();
+        self.assert_not_emergency();
+        let account_id = env::predecessor_account_id();
+        self.internal_claim_pending(&account_id);
+        let x: u128 = self.user_shares.get(&account_id).unwrap_or(0);
+
+        let quantity = amount.0;
+        assert!(quantity > 0, "{}", VaultError::IllegalWithdrawAmount);
+        let value = checked_sub(x, quantity).map_err(|_| VaultError::NotEnoughShares).or_panic();
+        let lp = self.convert_to_assets(quantity);
+        self.user_shares.insert(&account_id, &value);
+        let burn = self.internal_record_burn(&account_id, quantity, x, self.internal_lp_value(lp));
+        self.internal_append_history(&account_id, LedgerKind::Withdraw, quantity, lp, None);
+        self.total_shares = checked_sub(self.total_shares, quantity).or_panic();
+        self.total_lp = checked_sub(self.total_lp, lp).or_panic();
+
+        let receiver_id = receiver_id.map(|a| a.into()).unwrap_or_else(|| account_id.clone());
+        self.internal_rate_limited_unwind(lp, Unwind {
+            sender_id: account_id,
+            receiver_id,
+            output_token: output_token.map(|a| a.into()),
+            shares: U128(quantity),
+            burn,
+        })
+    } 
 
 
 
@@ -799,8 +929,54 @@ impl Contract {
     fn assert_contract_running(&self) {
         match self.state {
             RunningState::Running => (),
-            _ => env::panic("E51: contract paused".as_bytes()),
+            _ => VaultError::ContractPaused.panic(),
         };
     }
 
+    /// Wraps `amount` of the contract's NEAR and sends the wNEAR to `receiver_id`.
+    pub(crate) fn internal_wrap_near(&self, receiver_id: AccountId, amount: Balance, msg: String) -> Promise {
+        /*
+        ext_wrap::storage_deposit(
+            &CONTRACT_ID_WRAP, // contract account id
+            1250000000000000000000, // yocto NEAR to attach
+            35_000_000_000_000 // gas to attach
+        )
+        .then(*/
+            ext_wrap::near_deposit(
+                &CONTRACT_ID_WRAP, // contract account id
+                amount, // yocto NEAR to attach
+                3_000_000_000_000 // gas to attach
+            )
+        //)
+        .then(
+            ext_wrap::ft_transfer_call(
+                receiver_id,//receiver_id,
+                amount.to_string(),
+                msg,
+                &CONTRACT_ID_WRAP, // contract account id
+                1, // yocto NEAR to attach
+                35_000_000_000_000 // gas to attach                
+            )
+        )
+    }
+
+    /// Vault shares worth `assets` LP at the current share price.
+    pub(crate) fn convert_to_shares(&self, assets: Balance) -> Balance {
+        let total_shares = checked_add(self.total_shares, VIRTUAL_SHARES).or_panic();
+        let total_assets = checked_add(self.free_lp(), VIRTUAL_LP).or_panic();
+        mul_div(assets, total_shares, total_assets)
+    }
+
+    /// LP owned by `shares` vault shares at the current share price.
+    pub(crate) fn convert_to_assets(&self, shares: Balance) -> Balance {
+        let total_shares = checked_add(self.total_shares, VIRTUAL_SHARES).or_panic();
+        let total_assets = checked_add(self.free_lp(), VIRTUAL_LP).or_panic();
+        mul_div(shares, total_assets, total_shares)
+    }
+
+    /// LP owned by the vault without the profit still locked.
+    pub(crate) fn free_lp(&self) -> Balance {
+        self.total_lp.saturating_sub(self.internal_locked_profit())
+    }
+
 }
@@ -0,0 +1,229 @@
+//! Lostfound.
+//!
+//! Tokens that can't be credited to an account, because it is not registered or lacks
+//! storage, are kept in lostfound under the account they belong to, and so is NEAR given
+//! back to an unregistered account that is too little to register it, and the shares of an
+//! account that force-unregistered. The account claims them
+//! into its balance with `claim_lostfound` once it registered or topped up its storage,
+//! paying back the storage the vault paid for its lostfound entry.
+//! Tokens sent to the vault that are tracked nowhere can be swept by the owner. Tokens
+//! moving through the vault's wallet, between a transfer or withdraw and its callback,
+//! are tracked as in flight so that they can't be swept.
+
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::Gas;
+
+use crate::harvest::REWARD_TOKEN;
+use crate::utils::promise_result_as;
+use crate::*;
+
+const GAS_FOR_FT_BALANCE_OF: Gas = 10_000_000_000_000;
+const GAS_FOR_SWEEP_CALLBACK: Gas = 50_000_000_000_000;
+
+/// Tokens kept in lostfound for an account.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct LostfoundAccount {
+    pub tokens: HashMap<AccountId, Balance>,
+    /// NEAR to credit to the investable balance.
+    pub near: Balance,
+    /// Vault shares of an account that force-unregistered.
+    pub shares: Balance,
+    /// Cost basis of `shares`, for the PnL of the account.
+    pub share_cost: Balance,
+    /// Cost of the storage of the entry, paid by the vault until the account claims it.
+    pub storage_cost: Balance,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Moves all the tokens, NEAR and shares kept in lostfound for the caller into its balance.
+    /// The attached deposit pays back the storage of the lostfound entry, the rest is refunded.
+    /// Fails if the caller lacks the storage for the tokens.
+    #[payable]
+    pub fn claim_lostfound(&mut self) -> HashMap<AccountId, U128> {
+        self.assert_contract_running();
+        let account_id = env::predecessor_account_id();
+        let entry = self.lostfound_accounts.remove(&account_id).unwrap_or_else(|| VaultError::NoLostfound.panic());
+        assert!(
+            env::attached_deposit() >= entry.storage_cost,
+            "{}",
+            VaultError::InsufficientStorage
+        );
+        let stranded = entry.tokens;
+        let mut account = self.internal_unwrap_account(&account_id);
+        for (token_id, amount) in stranded.iter() {
+            account.deposit(token_id, *amount).or_panic();
+        }
+        if entry.near > 0 {
+            account.deposit_near(entry.near).or_panic();
+        }
+        self.internal_save_account(&account_id, account);
+        if entry.shares > 0 {
+            let shares = self.user_shares.get(&account_id).unwrap_or(0);
+            self.user_shares.insert(&account_id, &checked_add(shares, entry.shares).or_panic());
+            let mut pnl = self.user_pnl.get(&account_id).unwrap_or_default();
+            pnl.cost_basis = checked_add(pnl.cost_basis, entry.share_cost).or_panic();
+            self.user_pnl.insert(&account_id, &pnl);
+        }
+        for (token_id, amount) in stranded.iter() {
+            let total = self.lostfound.get(token_id).unwrap_or(0);
+            self.lostfound.insert(token_id, &checked_sub(total, *amount).or_panic());
+            self.internal_track_deposit(token_id, *amount);
+        }
+        let refund = env::attached_deposit() - entry.storage_cost;
+        if refund > 0 {
+            Promise::new(account_id.clone()).transfer(refund);
+        }
+        log!("{} claimed its lostfound", account_id);
+        stranded.into_iter().map(|(token_id, amount)| (token_id, U128(amount))).collect()
+    }
+
+    /// Sends to the owner the part of the vault's `token_id` balance that is not held
+    /// by accounts, lostfound or the emergency exit, nor in flight.
+    pub fn sweep(&mut self, token_id: ValidAccountId) -> Promise {
+        self.assert_owner();
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            token_id.as_ref(),
+            0,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+        .then(ext_self::callback_sweep(
+            token_id.into(),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_SWEEP_CALLBACK,
+        ))
+    }
+
+    /// Total of each token kept in lostfound.
+    pub fn get_lostfound(&self) -> HashMap<AccountId, U128> {
+        self.lostfound
+            .iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+
+    /// Tokens kept in lostfound for `account_id`.
+    pub fn get_account_lostfound(&self, account_id: ValidAccountId) -> HashMap<AccountId, U128> {
+        self.lostfound_accounts
+            .get(account_id.as_ref())
+            .unwrap_or_default()
+            .tokens
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+
+    /// NEAR kept in lostfound for `account_id`.
+    pub fn get_lostfound_near(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.lostfound_accounts.get(account_id.as_ref()).map_or(0, |entry| entry.near))
+    }
+
+    /// Vault shares kept in lostfound for `account_id`.
+    pub fn get_lostfound_shares(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.lostfound_accounts.get(account_id.as_ref()).map_or(0, |entry| entry.shares))
+    }
+
+    /// Deposit `account_id` needs to attach to `claim_lostfound`.
+    pub fn get_lostfound_storage_cost(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.lostfound_accounts.get(account_id.as_ref()).map_or(0, |entry| entry.storage_cost))
+    }
+
+    /// Amount of `token_id` in flight through the vault's wallet.
+    pub fn get_in_flight(&self, token_id: ValidAccountId) -> U128 {
+        U128(self.in_flight.get(token_id.as_ref()).unwrap_or(0))
+    }
+
+    #[private]
+    pub fn callback_sweep(&mut self, token_id: AccountId) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let balance: U128 = promise_result_as(0);
+        let untracked = balance.0.saturating_sub(self.internal_tracked_balance(&token_id));
+        if untracked == 0 {
+            log!("Nothing to sweep for {}", token_id);
+            return None;
+        }
+        let owner_id = self.owner_id.clone();
+        log!("Sweeping {} {} to {}", untracked, token_id, owner_id);
+        Some(self.internal_send_tokens(&owner_id, &token_id, untracked))
+    }
+}
+
+impl Contract {
+    /// Keeps `amount` of `token_id` in lostfound for `account_id`. The vault pays for the
+    /// storage of the entry and the account pays it back when it claims.
+    pub(crate) fn internal_lostfound(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        self.internal_update_lostfound(account_id, |entry| {
+            let stranded_amount = entry.tokens.entry(token_id.clone()).or_insert(0);
+            *stranded_amount = checked_add(*stranded_amount, amount).or_panic();
+        });
+        let total = self.lostfound.get(token_id).unwrap_or(0);
+        self.lostfound.insert(token_id, &checked_add(total, amount).or_panic());
+    }
+
+    /// Keeps `amount` of NEAR in lostfound for `account_id`, like `internal_lostfound`.
+    pub(crate) fn internal_lostfound_near(&mut self, account_id: &AccountId, amount: Balance) {
+        self.internal_update_lostfound(account_id, |entry| {
+            entry.near = checked_add(entry.near, amount).or_panic();
+        });
+    }
+
+    /// Keeps `shares` costing `cost` in lostfound for `account_id`, like
+    /// `internal_lostfound`. They still count in `total_shares`, so their LP stays theirs.
+    pub(crate) fn internal_lostfound_shares(&mut self, account_id: &AccountId, shares: Balance, cost: Balance) {
+        self.internal_update_lostfound(account_id, |entry| {
+            entry.shares = checked_add(entry.shares, shares).or_panic();
+            entry.share_cost = checked_add(entry.share_cost, cost).or_panic();
+        });
+    }
+
+    /// Applies `update` to the lostfound entry of `account_id` and adds the storage
+    /// it grew by to the cost of the entry.
+    fn internal_update_lostfound(&mut self, account_id: &AccountId, update: impl FnOnce(&mut LostfoundAccount)) {
+        let prev_storage = env::storage_usage();
+        let mut entry = self.lostfound_accounts.get(account_id).unwrap_or_default();
+        update(&mut entry);
+        self.lostfound_accounts.insert(account_id, &entry);
+        let storage_cost = env::storage_usage().saturating_sub(prev_storage) as Balance * env::storage_byte_cost();
+        if storage_cost > 0 {
+            entry.storage_cost = checked_add(entry.storage_cost, storage_cost).or_panic();
+            self.lostfound_accounts.insert(account_id, &entry);
+        }
+    }
+
+    /// Counts `amount` of `token_id` sent into or out of the vault's wallet as in flight
+    /// until the callback of the transfer calls `internal_untrack_in_flight`.
+    pub(crate) fn internal_track_in_flight(&mut self, token_id: &AccountId, amount: Balance) {
+        let total = self.in_flight.get(token_id).unwrap_or(0);
+        self.in_flight.insert(token_id, &checked_add(total, amount).or_panic());
+    }
+
+    /// Stops counting `amount` of `token_id` as in flight.
+    pub(crate) fn internal_untrack_in_flight(&mut self, token_id: &AccountId, amount: Balance) {
+        let total = self.in_flight.get(token_id).unwrap_or(0);
+        self.in_flight.insert(token_id, &total.saturating_sub(amount));
+    }
+
+    /// Part of the vault's `token_id` balance owed to someone.
+    fn internal_tracked_balance(&self, token_id: &AccountId) -> Balance {
+        let mut tracked = [
+            self.token_deposits.get(token_id).unwrap_or(0),
+            self.lostfound.get(token_id).unwrap_or(0),
+            self.in_flight.get(token_id).unwrap_or(0),
+        ]
+        .iter()
+        .try_fold(0, |total: Balance, amount| checked_add(total, *amount))
+        .or_panic();
+        if token_id == REWARD_TOKEN {
+            tracked = checked_add(tracked, self.idle_reward).or_panic();
+        }
+        if let Some(emergency) = &self.emergency {
+            if let Some(index) = emergency.tokens.iter().position(|token| token == token_id) {
+                tracked = checked_add(tracked, emergency.amounts[index]).or_panic();
+                tracked = checked_add(tracked, emergency.withdrawing[index]).or_panic();
+            }
+        }
+        tracked
+    }
+}
@@ -0,0 +1,165 @@
+//! Price oracle circuit breaker.
+//!
+//! When an oracle is configured, every zap and unwind reads the price data of the oracle
+//! together with the pools it is about to swap through. If the oracle data is older than
+//! `max_age`, or the spot price of one of these pools deviates from the oracle prices by
+//! more than `max_deviation_bps`, the zap or unwind is aborted and the funds go back to
+//! where they came from. The oracle follows the NEAR price oracle interface.
+
+use std::collections::HashMap;
+
+use near_sdk::json_types::U64;
+use near_sdk::Gas;
+
+use crate::utils::{promise_result_as, U256};
+use crate::zap::PoolInfo;
+use crate::*;
+
+const GAS_FOR_GET_PRICE_DATA: Gas = 5_000_000_000_000;
+const GAS_FOR_GET_POOL: Gas = 5_000_000_000_000;
+const BPS_DIVISOR: u128 = 10_000;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OracleConfig {
+    pub oracle_id: AccountId,
+    /// Largest deviation allowed between pool spot prices and oracle prices, in bps.
+    pub max_deviation_bps: u32,
+    /// Oldest oracle data accepted, in nanoseconds.
+    pub max_age: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct OracleConfigInfo {
+    pub oracle_id: AccountId,
+    pub max_deviation_bps: u32,
+    pub max_age_sec: u64,
+}
+
+/// Price of the smallest unit of an asset, `multiplier / 10^decimals`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Price {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct AssetOptionalPrice {
+    pub asset_id: AccountId,
+    pub price: Option<Price>,
+}
+
+/// Price data as returned by the oracle `get_price_data`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct PriceData {
+    pub timestamp: U64,
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+#[ext_contract(ext_oracle)]
+pub trait PriceOracle {
+    fn get_price_data(&self, asset_ids: Option<Vec<AccountId>>) -> PriceData;
+}
+
+/// Checks the spot price of every token of `pool` against its first token.
+fn check_pool_price(pool: &PoolInfo, prices: &HashMap<AccountId, Price>, max_deviation_bps: u32) -> Result<(), String> {
+    let token_a = &pool.token_account_ids[0];
+    let price_a = prices.get(token_a).ok_or_else(|| format!("{}: {}", VaultError::NoOraclePrice, token_a))?;
+    for (index, token_b) in pool.token_account_ids.iter().enumerate().skip(1) {
+        let price_b = prices.get(token_b).ok_or_else(|| format!("{}: {}", VaultError::NoOraclePrice, token_b))?;
+        // spot: reserve_b / reserve_a, oracle: price_a / price_b
+        let spot = U256::from(pool.amounts[index].0)
+            * U256::from(price_b.multiplier.0)
+            * U256::exp10(price_a.decimals as usize);
+        let oracle = U256::from(pool.amounts[0].0)
+            * U256::from(price_a.multiplier.0)
+            * U256::exp10(price_b.decimals as usize);
+        if oracle.is_zero() {
+            return Err(VaultError::NoOraclePrice.to_string());
+        }
+        let difference = if spot > oracle { spot - oracle } else { oracle - spot };
+        if difference * U256::from(BPS_DIVISOR) > oracle * U256::from(max_deviation_bps) {
+            return Err(format!("{}: {} / {}", VaultError::PriceDeviation, token_a, token_b));
+        }
+    }
+    Ok(())
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_oracle_config(&self) -> Option<OracleConfigInfo> {
+        self.oracle.as_ref().map(|config| OracleConfigInfo {
+            oracle_id: config.oracle_id.clone(),
+            max_deviation_bps: config.max_deviation_bps,
+            max_age_sec: config.max_age / 1_000_000_000,
+        })
+    }
+}
+
+impl Contract {
+    /// Reads `pool_ids` from the exchange and, if an oracle is configured, the prices
+    /// of their tokens. Callbacks read them with `internal_read_pools`.
+    pub(crate) fn internal_get_pools(&self, pool_ids: &[u64]) -> Promise {
+        let mut get_pools = ext_exchange::get_pool(pool_ids[0], &CONTRACT_ID, 0, GAS_FOR_GET_POOL);
+        for pool_id in pool_ids.iter().skip(1) {
+            get_pools = get_pools.and(ext_exchange::get_pool(*pool_id, &CONTRACT_ID, 0, GAS_FOR_GET_POOL));
+        }
+        match &self.oracle {
+            Some(config) => get_pools.and(ext_oracle::get_price_data(None, &config.oracle_id, 0, GAS_FOR_GET_PRICE_DATA)),
+            None => get_pools,
+        }
+    }
+
+    /// Reads the pools requested by `internal_get_pools` and checks their spot prices
+    /// against the oracle, if one is configured.
+    pub(crate) fn internal_read_pools(&self, pool_ids: &[u64]) -> (HashMap<u64, PoolInfo>, Result<(), String>) {
+        let count = env::promise_results_count();
+        assert!(
+            count == pool_ids.len() as u64 || count == pool_ids.len() as u64 + 1,
+            "{}",
+            VaultError::TooManyResults
+        );
+        let pools: HashMap<u64, PoolInfo> = pool_ids
+            .iter()
+            .enumerate()
+            .map(|(index, pool_id)| (*pool_id, promise_result_as(index as u64)))
+            .collect();
+        let config = match &self.oracle {
+            Some(config) => config,
+            None => return (pools, Ok(())),
+        };
+        if count == pool_ids.len() as u64 {
+            return (pools, Err(VaultError::OracleUnavailable.to_string()));
+        }
+        let data = match env::promise_result(count - 1) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<PriceData>(&value).ok(),
+            _ => None,
+        };
+        let data = match data {
+            Some(data) => data,
+            None => return (pools, Err(VaultError::OracleUnavailable.to_string())),
+        };
+        if data.timestamp.0 + config.max_age < env::block_timestamp() {
+            return (pools, Err(VaultError::OracleStale.to_string()));
+        }
+        let prices: HashMap<AccountId, Price> = data
+            .prices
+            .into_iter()
+            .filter_map(|asset| asset.price.map(|price| (asset.asset_id, price)))
+            .collect();
+        for pool in pools.values() {
+            if let Err(err) = check_pool_price(pool, &prices, config.max_deviation_bps) {
+                return (pools, Err(err));
+            }
+        }
+        (pools, Ok(()))
+    }
+}
@@ -0,0 +1,235 @@
+//! Implement all the relevant logic for owner of this contract.
+
+use near_sdk::json_types::Base64VecU8;
+
+use crate::harvest::MAX_HARVEST_BOUNTY_BPS;
+use crate::oracle::OracleConfig;
+use crate::utils::sec_to_nanos;
+use crate::*;
+
+/// Configuration change, made by the owner or through a council proposal.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum AdminAction {
+    SetEpochMode { epoch_mode: bool },
+    SetEpochDuration { duration_sec: u64 },
+    SetHarvestConfig { bounty_bps: u32, interval_sec: u64, min_reward: U128 },
+    SetProfitUnlockPeriod { period_sec: u64 },
+    SetMinDeposit { min_deposit_lp: U128 },
+    SetDepositCaps { max_total_assets: Option<U128>, max_user_assets: Option<U128> },
+    SetDepositLimit { token_id: ValidAccountId, limit: Option<U128> },
+    SetWithdrawLimit { limit: Option<U128>, window_sec: u64 },
+    SetOracle { oracle_id: Option<ValidAccountId>, max_deviation_bps: u32, max_age_sec: u64 },
+    SetRoute { token_in: ValidAccountId, token_out: ValidAccountId, hops: Vec<RouteHop> },
+    RemoveRoute { token_in: ValidAccountId, token_out: ValidAccountId },
+    ExtendWhitelistedTokens { tokens: Vec<ValidAccountId> },
+    RemoveWhitelistedTokens { tokens: Vec<ValidAccountId> },
+    AddGuardian { account_id: ValidAccountId },
+    RemoveGuardian { account_id: ValidAccountId },
+    ChangeState { state: RunningState },
+    SetTimelockDelay { delay_sec: u64 },
+    SetCouncil { members: Vec<ValidAccountId>, threshold: u32, proposal_period_sec: u64 },
+    /// Deploys `code` on the vault account. Only available through the council.
+    Upgrade { code: Base64VecU8 },
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Get the owner of this account.
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Account proposed as the next owner, if any.
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    /// Proposes `new_owner` as the next owner. Ownership only moves once it calls `accept_owner`.
+    pub fn propose_owner(&mut self, new_owner: ValidAccountId) {
+        self.assert_owner();
+        log!("Proposed {} as the next owner", new_owner.as_ref());
+        self.pending_owner = Some(new_owner.into());
+    }
+
+    /// Makes the pending owner the owner. Only callable by the pending owner.
+    pub fn accept_owner(&mut self) {
+        let account_id = env::predecessor_account_id();
+        assert_eq!(self.pending_owner.as_ref(), Some(&account_id), "{}", VaultError::NotPendingOwner);
+        log!("Ownership moved from {} to {}", self.owner_id, account_id);
+        self.owner_id = account_id;
+        self.pending_owner = None;
+    }
+
+    pub fn cancel_owner_transfer(&mut self) {
+        self.assert_owner();
+        assert!(self.pending_owner.is_some(), "{}", VaultError::NoPendingOwner);
+        self.pending_owner = None;
+    }
+
+    // The setters below queue their change behind the timelock and return its id.
+
+    /// Switches `add_to_vault` between direct zaps and epoch-batched deposits.
+    pub fn set_epoch_mode(&mut self, epoch_mode: bool) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetEpochMode { epoch_mode })
+    }
+
+    /// Sets the minimum epoch length, in seconds, before it can be settled.
+    pub fn set_epoch_duration(&mut self, duration_sec: u64) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetEpochDuration { duration_sec })
+    }
+
+    /// Sets the harvest bounty in bps, the minimum interval between harvests in
+    /// seconds and the minimum claimable reward for a harvest.
+    pub fn set_harvest_config(&mut self, bounty_bps: u32, interval_sec: u64, min_reward: U128) -> u64 {
+        self.assert_owner();
+        assert!(bounty_bps <= MAX_HARVEST_BOUNTY_BPS, "{}", VaultError::BountyTooHigh);
+        self.internal_queue_change(AdminAction::SetHarvestConfig { bounty_bps, interval_sec, min_reward })
+    }
+
+    /// Sets the time, in seconds, over which compounded LP is released into the share price.
+    pub fn set_profit_unlock_period(&mut self, period_sec: u64) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetProfitUnlockPeriod { period_sec })
+    }
+
+    /// Sets the minimum LP a single deposit must add to the vault.
+    pub fn set_min_deposit(&mut self, min_deposit_lp: U128) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetMinDeposit { min_deposit_lp })
+    }
+
+    /// Sets the maximum LP owned by the vault and by a single account, None for unlimited.
+    pub fn set_deposit_caps(&mut self, max_total_assets: Option<U128>, max_user_assets: Option<U128>) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetDepositCaps { max_total_assets, max_user_assets })
+    }
+
+    /// Sets the maximum amount of `token_id` accepted in a single deposit, None to remove it.
+    pub fn set_deposit_limit(&mut self, token_id: ValidAccountId, limit: Option<U128>) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetDepositLimit { token_id, limit })
+    }
+
+    /// Sets the LP that can be withdrawn per rolling window of `window_sec` seconds,
+    /// None for unlimited.
+    pub fn set_withdraw_limit(&mut self, limit: Option<U128>, window_sec: u64) -> u64 {
+        self.assert_owner();
+        assert!(limit.is_none() || window_sec > 0, "{}", VaultError::ZeroWindow);
+        self.internal_queue_change(AdminAction::SetWithdrawLimit { limit, window_sec })
+    }
+
+    /// Sets the price oracle checked before swapping, None to disable the check.
+    /// `max_deviation_bps` bounds the deviation of pool spot prices from oracle prices
+    /// and `max_age_sec` the age of the oracle data.
+    pub fn set_oracle(&mut self, oracle_id: Option<ValidAccountId>, max_deviation_bps: u32, max_age_sec: u64) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetOracle { oracle_id, max_deviation_bps, max_age_sec })
+    }
+
+    /// Pauses or resumes the contract.
+    pub fn change_state(&mut self, state: RunningState) {
+        self.assert_owner();
+        self.internal_apply_action(AdminAction::ChangeState { state });
+    }
+
+    pub fn add_guardian(&mut self, account_id: ValidAccountId) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::AddGuardian { account_id })
+    }
+
+    pub fn remove_guardian(&mut self, account_id: ValidAccountId) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::RemoveGuardian { account_id })
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_apply_action(&mut self, action: AdminAction) {
+        match action {
+            AdminAction::SetEpochMode { epoch_mode } => self.epoch_mode = epoch_mode,
+            AdminAction::SetEpochDuration { duration_sec } => {
+                self.epoch_duration = sec_to_nanos(duration_sec).or_panic();
+            }
+            AdminAction::SetHarvestConfig { bounty_bps, interval_sec, min_reward } => {
+                assert!(bounty_bps <= MAX_HARVEST_BOUNTY_BPS, "{}", VaultError::BountyTooHigh);
+                self.harvest_bounty_bps = bounty_bps;
+                self.harvest_interval = sec_to_nanos(interval_sec).or_panic();
+                self.min_harvest_reward = min_reward.0;
+            }
+            AdminAction::SetProfitUnlockPeriod { period_sec } => {
+                // keep what has been released so far under the previous period
+                self.locked_profit = self.internal_locked_profit();
+                self.locked_profit_at = env::block_timestamp();
+                self.profit_unlock_period = sec_to_nanos(period_sec).or_panic();
+            }
+            AdminAction::SetMinDeposit { min_deposit_lp } => self.min_deposit_lp = min_deposit_lp.0,
+            AdminAction::SetDepositCaps { max_total_assets, max_user_assets } => {
+                self.max_total_assets = max_total_assets.map(|cap| cap.0).unwrap_or(Balance::MAX);
+                self.max_user_assets = max_user_assets.map(|cap| cap.0).unwrap_or(Balance::MAX);
+            }
+            AdminAction::SetDepositLimit { token_id, limit } => {
+                match limit {
+                    Some(limit) => self.deposit_limits.insert(token_id.as_ref(), &limit.0),
+                    None => self.deposit_limits.remove(token_id.as_ref()),
+                };
+            }
+            AdminAction::SetWithdrawLimit { limit, window_sec } => {
+                assert!(limit.is_none() || window_sec > 0, "{}", VaultError::ZeroWindow);
+                let limiter = &mut self.withdraw_limiter;
+                limiter.used = limiter.current_used();
+                limiter.updated_at = env::block_timestamp();
+                limiter.limit = limit.map(|limit| limit.0).unwrap_or(Balance::MAX);
+                limiter.window = sec_to_nanos(window_sec).or_panic();
+            }
+            AdminAction::SetOracle { oracle_id, max_deviation_bps, max_age_sec } => {
+                self.oracle = oracle_id.map(|oracle_id| OracleConfig {
+                    oracle_id: oracle_id.into(),
+                    max_deviation_bps,
+                    max_age: sec_to_nanos(max_age_sec).or_panic(),
+                });
+            }
+            AdminAction::SetRoute { token_in, token_out, hops } => {
+                self.internal_set_route(token_in.into(), token_out.into(), hops);
+            }
+            AdminAction::RemoveRoute { token_in, token_out } => {
+                self.routes.remove(&(token_in.into(), token_out.into()));
+            }
+            AdminAction::ExtendWhitelistedTokens { tokens } => self.internal_whitelist_tokens(tokens),
+            AdminAction::RemoveWhitelistedTokens { tokens } => self.internal_remove_whitelisted_tokens(tokens),
+            AdminAction::AddGuardian { account_id } => {
+                self.guardians.insert(account_id.as_ref());
+            }
+            AdminAction::RemoveGuardian { account_id } => {
+                self.guardians.remove(account_id.as_ref());
+            }
+            AdminAction::ChangeState { state } => {
+                log!("Contract state changed from {} to {}", self.state, state);
+                self.state = state;
+            }
+            AdminAction::SetTimelockDelay { delay_sec } => {
+                assert!(delay_sec >= MIN_TIMELOCK_DELAY_SEC, "{}", VaultError::TimelockTooShort);
+                self.timelock_delay = sec_to_nanos(delay_sec).or_panic();
+            }
+            AdminAction::SetCouncil { members, threshold, proposal_period_sec } => {
+                self.internal_set_council(members, threshold, proposal_period_sec);
+            }
+            AdminAction::Upgrade { code } => {
+                log!("Deploying {} bytes of new code", code.0.len());
+                Promise::new(env::current_account_id()).deploy_contract(code.0);
+            }
+        }
+    }
+
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "{}",
+            VaultError::NotAllowed
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Per-account cost basis and profit and loss, in NEAR.
+//!
+//! LP is valued at `lp_price_near`, the spot value of one LP in wNEAR, refreshed by every
+//! zap whose quote includes the pools of the routes from the pool tokens to wNEAR. Minting
+//! adds the value of the new shares to the account's cost basis. Epoch deposits add the
+//! NEAR actually deposited. Burning realizes the difference between the value withdrawn
+//! and the average cost of the burned shares. Shares given back because their withdrawal
+//! failed reverse the burn.
+
+use std::collections::HashMap;
+
+use near_sdk::json_types::I128;
+
+use crate::utils::{checked_mul_div, mul_div};
+use crate::zap::{PoolInfo, POOL_ID, POOL_TOKENS, TOKEN_WRAP};
+use crate::*;
+
+/// Scale of `lp_price_near`: yoctoNEAR per 10^24 LP.
+const LP_PRICE_SCALE: u128 = 1_000_000_000_000_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct UserPnl {
+    /// Cumulative value deposited.
+    pub deposited: Balance,
+    /// Cumulative value withdrawn.
+    pub withdrawn: Balance,
+    /// Cost of the shares currently held.
+    pub cost_basis: Balance,
+    /// Gains realized by withdrawals.
+    pub realized: i128,
+}
+
+/// Value withdrawn and cost basis removed by a burn, kept with the withdrawal to reverse
+/// the burn if its shares are given back.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct BurnRecord {
+    pub value: U128,
+    pub cost: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct UserPnlInfo {
+    pub deposited: U128,
+    pub withdrawn: U128,
+    pub cost_basis: U128,
+    /// Cost of one share, scaled by 10^24.
+    pub average_cost: U128,
+    /// Current value of the shares held.
+    pub value: U128,
+    pub realized: I128,
+    pub unrealized: I128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Value in yoctoNEAR of 10^24 LP, as of the last zap.
+    pub fn get_lp_price_near(&self) -> U128 {
+        U128(self.lp_price_near)
+    }
+
+    /// Deposits, withdrawals and gains of given account, valued in NEAR.
+    pub fn get_user_pnl(&self, account_id: ValidAccountId) -> UserPnlInfo {
+        let pnl = self.user_pnl.get(account_id.as_ref()).unwrap_or_default();
+        let shares = self.user_shares.get(account_id.as_ref()).unwrap_or(0);
+        let value = self.internal_lp_value(self.convert_to_assets(shares));
+        UserPnlInfo {
+            deposited: U128(pnl.deposited),
+            withdrawn: U128(pnl.withdrawn),
+            cost_basis: U128(pnl.cost_basis),
+            average_cost: U128(if shares == 0 { 0 } else { mul_div(pnl.cost_basis, LP_PRICE_SCALE, shares) }),
+            value: U128(value),
+            realized: I128(pnl.realized),
+            unrealized: I128(signed_sub(value, pnl.cost_basis).or_panic()),
+        }
+    }
+}
+
+/// Returns `a - b` as a signed amount, or `Overflow` if it doesn't fit in 128 bits.
+fn signed_sub(a: Balance, b: Balance) -> Result<i128, VaultError> {
+    let a = i128::try_from(a).map_err(|_| VaultError::Overflow)?;
+    let b = i128::try_from(b).map_err(|_| VaultError::Overflow)?;
+    a.checked_sub(b).ok_or(VaultError::Overflow)
+}
+
+impl Contract {
+    /// Value of `lp` in yoctoNEAR at the last known LP price.
+    pub(crate) fn internal_lp_value(&self, lp: Balance) -> Balance {
+        mul_div(lp, self.lp_price_near, LP_PRICE_SCALE)
+    }
+
+    /// Refreshes the LP price from the pools of a zap quote, if it has all the pools
+    /// on the way from the pool tokens to wNEAR.
+    pub(crate) fn internal_update_lp_price(&mut self, pools: &HashMap<u64, PoolInfo>) {
+        let pool = &pools[&POOL_ID];
+        if pool.shares_total_supply.0 == 0 {
+            return;
+        }
+        let mut price = 0;
+        for token_id in POOL_TOKENS.iter() {
+            // pool tokens in 10^24 LP
+            let mut amount = mul_div(pool.reserve(token_id), LP_PRICE_SCALE, pool.shares_total_supply.0);
+            let mut token = token_id.to_string();
+            let hops = match self.routes.get(&(token.clone(), TOKEN_WRAP.to_string())) {
+                Some(hops) => hops,
+                None => return,
+            };
+            for hop in hops {
+                let hop_pool = match pools.get(&hop.pool_id) {
+                    Some(hop_pool) => hop_pool,
+                    None => return,
+                };
+                let reserve_in = hop_pool.reserve(&token);
+                if reserve_in == 0 {
+                    return;
+                }
+                amount = mul_div(amount, hop_pool.reserve(&hop.token_out), reserve_in);
+                token = hop.token_out;
+            }
+            price = checked_add(price, amount).or_panic();
+        }
+        self.lp_price_near = price;
+    }
+
+    /// Adds `value` to the cost basis of `account_id` for newly minted shares.
+    pub(crate) fn internal_record_mint(&mut self, account_id: &AccountId, value: Balance) {
+        let mut pnl = self.user_pnl.get(account_id).unwrap_or_default();
+        pnl.deposited = checked_add(pnl.deposited, value).or_panic();
+        pnl.cost_basis = checked_add(pnl.cost_basis, value).or_panic();
+        self.user_pnl.insert(account_id, &pnl);
+    }
+
+    /// Realizes the gain of burning `shares` out of `shares_before` for `value`.
+    pub(crate) fn internal_record_burn(&mut self, account_id: &AccountId, shares: Balance, shares_before: Balance, value: Balance) -> BurnRecord {
+        let mut pnl = self.user_pnl.get(account_id).unwrap_or_default();
+        let cost = checked_mul_div(pnl.cost_basis, shares, shares_before).or_panic();
+        pnl.withdrawn = checked_add(pnl.withdrawn, value).or_panic();
+        pnl.cost_basis = checked_sub(pnl.cost_basis, cost).or_panic();
+        pnl.realized = signed_sub(value, cost)
+            .and_then(|gain| pnl.realized.checked_add(gain).ok_or(VaultError::Overflow))
+            .or_panic();
+        self.user_pnl.insert(account_id, &pnl);
+        BurnRecord { value: U128(value), cost: U128(cost) }
+    }
+
+    /// Undoes the `burn` recorded for shares given back to `account_id`.
+    pub(crate) fn internal_reverse_burn(&mut self, account_id: &AccountId, burn: BurnRecord) {
+        let mut pnl = self.user_pnl.get(account_id).unwrap_or_default();
+        pnl.withdrawn = checked_sub(pnl.withdrawn, burn.value.0).or_panic();
+        pnl.cost_basis = checked_add(pnl.cost_basis, burn.cost.0).or_panic();
+        pnl.realized = signed_sub(burn.value.0, burn.cost.0)
+            .and_then(|gain| pnl.realized.checked_sub(gain).ok_or(VaultError::Overflow))
+            .or_panic();
+        self.user_pnl.insert(account_id, &pnl);
+    }
+}
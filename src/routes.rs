@@ -0,0 +1,96 @@
+//! Swap route registry.
+//!
+//! Maps a `(token_in, token_out)` pair to the ordered pools to swap through, so zaps,
+//! unwinds and reward compounding work for tokens without a direct pool.
+
+use crate::owner::AdminAction;
+use crate::*;
+
+/// One hop of a swap route: swap the current token into `token_out` on `pool_id`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct RouteHop {
+    pub pool_id: u64,
+    pub token_out: AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct RouteInfo {
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub hops: Vec<RouteHop>,
+}
+
+/// Builds the chained swap actions of `hops`, starting with `amount_in` of `token_in`.
+/// Only the last action carries `min_amount_out`.
+pub fn route_actions(token_in: &str, hops: &[RouteHop], amount_in: Balance, min_amount_out: Balance) -> Vec<SwapAction> {
+    let mut token = token_in.to_string();
+    hops.iter()
+        .enumerate()
+        .map(|(index, hop)| {
+            let action = SwapAction {
+                pool_id: hop.pool_id,
+                token_in: token.clone(),
+                amount_in: if index == 0 { Some(U128(amount_in)) } else { None },
+                token_out: hop.token_out.clone(),
+                min_amount_out: if index + 1 == hops.len() { U128(min_amount_out) } else { U128(0) },
+            };
+            token = hop.token_out.clone();
+            action
+        })
+        .collect()
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the pools to swap through from `token_in` to `token_out`.
+    pub fn set_route(&mut self, token_in: ValidAccountId, token_out: ValidAccountId, hops: Vec<RouteHop>) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::SetRoute { token_in, token_out, hops })
+    }
+
+    pub fn remove_route(&mut self, token_in: ValidAccountId, token_out: ValidAccountId) -> u64 {
+        self.assert_owner();
+        self.internal_queue_change(AdminAction::RemoveRoute { token_in, token_out })
+    }
+
+    pub fn get_route(&self, token_in: ValidAccountId, token_out: ValidAccountId) -> Option<Vec<RouteHop>> {
+        self.routes.get(&(token_in.into(), token_out.into()))
+    }
+
+    pub fn get_routes(&self, from_index: u64, limit: u64) -> Vec<RouteInfo> {
+        let keys = self.routes.keys_as_vector();
+        let values = self.routes.values_as_vector();
+        (from_index..std::cmp::min(from_index.saturating_add(limit), keys.len()))
+            .map(|index| {
+                let (token_in, token_out) = keys.get(index).unwrap();
+                RouteInfo { token_in, token_out, hops: values.get(index).unwrap() }
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_route(&mut self, token_in: AccountId, token_out: AccountId, hops: Vec<RouteHop>) {
+        assert!(!hops.is_empty(), "{}", VaultError::EmptyRoute);
+        assert_eq!(hops.last().map(|hop| &hop.token_out), Some(&token_out), "{}", VaultError::RouteWrongTokenOut);
+        self.routes.insert(&(token_in, token_out), &hops);
+    }
+
+    /// Hops from `token_in` to `token_out`, empty if they are the same token.
+    /// Panics if no route is registered.
+    pub(crate) fn internal_get_route(&self, token_in: &str, token_out: &str) -> Vec<RouteHop> {
+        self.internal_find_route(token_in, token_out).unwrap_or_else(|| VaultError::NoRoute.panic())
+    }
+
+    /// Route from `token_in` to `token_out`, None if there is none.
+    pub(crate) fn internal_find_route(&self, token_in: &str, token_out: &str) -> Option<Vec<RouteHop>> {
+        if token_in == token_out {
+            return Some(vec![]);
+        }
+        self.routes.get(&(token_in.to_string(), token_out.to_string()))
+    }
+}
@@ -13,19 +13,19 @@ impl StorageManagement for Contract {
         let amount = env::attached_deposit();
         let account_id = account_id
             .map(|a| a.into())
-            .unwrap_or_else(|| env::predecessor_account_id());
+            .unwrap_or_else(env::predecessor_account_id);
         let registration_only = registration_only.unwrap_or(false);
         let min_balance = self.storage_balance_bounds().min.0;
         let already_registered = self.accounts.contains_key(&account_id);
         if amount < min_balance && !already_registered {
-            env::panic(b"ERR_DEPOSIT_LESS_THAN_MIN_STORAGE");
+            VaultError::DepositLessThanMinStorage.panic();
         }
         /**/
         /**/
         if registration_only {
             // Registration only setups the account but doesn't leave space for tokens.
             if already_registered {
-                log!("ERR_ACC_REGISTERED");
+                log!("{}", VaultError::AccountRegistered);
                 if amount > 0 {
                     Promise::new(env::predecessor_account_id()).transfer(amount);
                 }
@@ -56,20 +56,73 @@ impl StorageManagement for Contract {
             .unwrap()
     }
 
-    #[allow(unused_variables)]
+    /// Unregisters the caller and refunds its storage deposit and investable NEAR.
+    /// Without `force`, fails while the caller has token balances or vault shares.
+    /// With `force`, remaining token balances and shares are moved to the caller's
+    /// lostfound, to be claimed after registering again.
+    /// Fails in both cases while the caller has a deposit waiting for its epoch or
+    /// stranded tokens to withdraw.
     #[payable]
     fn storage_unregister(&mut self, force: Option<bool>) -> bool {
         assert_one_yocto();
         self.assert_contract_running();
         let account_id = env::predecessor_account_id();
-        if let Some(account_deposit) = self.internal_get_account(&account_id) {
-            // TODO: figure out force option logic.
+        let force = force.unwrap_or(false);
+        // claim settled epoch shares first, it appends to the account's history
+        self.internal_claim_pending(&account_id);
+        if let Some(mut account_deposit) = self.internal_get_account(&account_id) {
             assert!(
-                account_deposit.tokens.is_empty(),
-                "ERR_STORAGE_UNREGISTER_TOKENS_NOT_EMPTY"
+                !self.pending_deposits.contains_key(&account_id),
+                "{}",
+                VaultError::PendingDeposit
             );
+            assert!(!self.stranded.contains_key(&account_id), "{}", VaultError::UnregisterTokensNotEmpty);
+            let shares = self.user_shares.get(&account_id).unwrap_or(0);
+            if !force {
+                assert!(
+                    account_deposit.tokens.is_empty() && account_deposit.legacy_tokens.is_empty(),
+                    "{}",
+                    VaultError::UnregisterTokensNotEmpty
+                );
+                assert_eq!(shares, 0, "{}", VaultError::UnregisterSharesNotEmpty);
+            }
+
+            let mut lostfound = HashMap::new();
+            for token_id in account_deposit.get_tokens() {
+                let amount = account_deposit.get_balance(&token_id).unwrap();
+                if amount > 0 {
+                    self.internal_track_withdraw(&token_id, amount);
+                    self.internal_lostfound(&account_id, &token_id, amount);
+                    lostfound.insert(token_id, U128(amount));
+                }
+            }
+            account_deposit.tokens.clear();
+            self.user_shares.remove(&account_id);
+            let pnl = self.user_pnl.remove(&account_id).unwrap_or_default();
+            if shares > 0 {
+                self.internal_lostfound_shares(&account_id, shares, pnl.cost_basis);
+            }
+            for id in account_deposit.history_start..account_deposit.history_end {
+                account_deposit.history.remove(&id);
+            }
             self.accounts.remove(&account_id);
-            Promise::new(account_id.clone()).transfer(account_deposit.near_amount);
+            let refund = checked_add(account_deposit.near_amount, account_deposit.near_deposit).or_panic();
+            Promise::new(account_id.clone()).transfer(refund);
+            log!(
+                "EVENT_JSON:{}",
+                near_sdk::serde_json::json!({
+                    "standard": "vault",
+                    "version": "1.0.0",
+                    "event": "storage_unregister",
+                    "data": [{
+                        "account_id": account_id,
+                        "force": force,
+                        "refund": U128(refund),
+                        "lostfound": lostfound,
+                        "lostfound_shares": U128(shares),
+                    }],
+                })
+            );
             true
         } else {
             false
@@ -0,0 +1,152 @@
+//! Timelock on configuration changes.
+//!
+//! Setters of the owner and executed council proposals don't change the configuration
+//! right away: they queue the `AdminAction`, which anyone can apply with `execute_change`
+//! once `timelock_delay` passed, so depositors get notice of fee and strategy changes.
+//! The delay is at least `MIN_TIMELOCK_DELAY_SEC`. Pausing or resuming the contract is
+//! not delayed. Upgrades can only be queued by the council, and so can every other change
+//! once a council is set.
+//!
+//! Guardians can cancel a change queued by the owner, except their own removal. Changes
+//! approved by the council can't be cancelled.
+
+use near_sdk::json_types::U64;
+
+use crate::owner::AdminAction;
+use crate::*;
+
+/// Shortest delay between queueing a change and executing it, in seconds.
+pub(crate) const MIN_TIMELOCK_DELAY_SEC: u64 = 24 * 60 * 60;
+
+/// Configuration change waiting for its delay.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct QueuedChange {
+    pub action: AdminAction,
+    /// Block timestamp from which the change can be executed.
+    pub eta: u64,
+    /// Whether the council approved the change, rather than the owner queueing it.
+    pub by_council: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct PendingChangeInfo {
+    pub id: u64,
+    pub action: AdminAction,
+    pub eta: U64,
+    /// Whether the delay passed and the change can be executed.
+    pub ready: bool,
+    pub by_council: bool,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Queues `action`, to be executed after the timelock delay, and returns its id.
+    /// Pausing or resuming the contract is applied right away and returns None.
+    /// Only callable by the owner, for actions that are not reserved to the council.
+    pub fn queue_change(&mut self, action: AdminAction) -> Option<u64> {
+        self.assert_owner();
+        self.assert_owner_may_queue(&action);
+        self.internal_submit_action(action, false)
+    }
+
+    /// Sets the delay, in seconds, between queueing a change and executing it.
+    /// The new delay itself only applies after the current one.
+    pub fn set_timelock_delay(&mut self, delay_sec: u64) -> u64 {
+        self.assert_owner();
+        assert!(delay_sec >= MIN_TIMELOCK_DELAY_SEC, "{}", VaultError::TimelockTooShort);
+        self.internal_queue_change(AdminAction::SetTimelockDelay { delay_sec })
+    }
+
+    /// Applies a queued change whose delay passed. Can be called by anyone.
+    pub fn execute_change(&mut self, id: u64) {
+        let change = self.pending_changes.get(&id).unwrap_or_else(|| VaultError::NoChange.panic());
+        assert!(env::block_timestamp() >= change.eta, "{}", VaultError::TimelockNotExpired);
+        if !change.by_council {
+            // a council may have been set since the owner queued it
+            self.assert_owner_may_queue(&change.action);
+        }
+        self.pending_changes.remove(&id);
+        log!("Executing change {}", id);
+        self.internal_apply_action(change.action);
+    }
+
+    /// Drops a change queued by the owner. Only callable by a guardian or the owner.
+    /// A guardian can't cancel its own removal.
+    pub fn cancel_change(&mut self, id: u64) {
+        self.assert_guardian();
+        let account_id = env::predecessor_account_id();
+        let change = self.pending_changes.get(&id).unwrap_or_else(|| VaultError::NoChange.panic());
+        assert!(!change.by_council, "{}", VaultError::CouncilChange);
+        if let AdminAction::RemoveGuardian { account_id: guardian_id } = &change.action {
+            assert_ne!(guardian_id.as_ref(), &account_id, "{}", VaultError::NotAllowed);
+        }
+        self.pending_changes.remove(&id);
+        log!("{} cancelled change {}", account_id, id);
+    }
+
+    /// Delay between queueing a change and executing it, in seconds.
+    pub fn get_timelock_delay(&self) -> u64 {
+        self.timelock_delay / 1_000_000_000
+    }
+
+    /// Queued changes with the time from which they can be executed.
+    pub fn get_pending_changes(&self, from_index: u64, limit: u64) -> Vec<PendingChangeInfo> {
+        let keys = self.pending_changes.keys_as_vector();
+        let values = self.pending_changes.values_as_vector();
+        (from_index..std::cmp::min(from_index.saturating_add(limit), keys.len()))
+            .map(|index| {
+                let change = values.get(index).unwrap();
+                PendingChangeInfo {
+                    id: keys.get(index).unwrap(),
+                    action: change.action,
+                    eta: U64(change.eta),
+                    ready: env::block_timestamp() >= change.eta,
+                    by_council: change.by_council,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Applies `action` right away if it pauses or resumes the contract,
+    /// otherwise queues it and returns the id of the queued change.
+    pub(crate) fn internal_submit_action(&mut self, action: AdminAction, by_council: bool) -> Option<u64> {
+        match action {
+            AdminAction::ChangeState { .. } => {
+                self.internal_apply_action(action);
+                None
+            }
+            _ => Some(self.internal_queue(action, by_council)),
+        }
+    }
+
+    /// Queues a change of the owner.
+    pub(crate) fn internal_queue_change(&mut self, action: AdminAction) -> u64 {
+        self.assert_owner_may_queue(&action);
+        self.internal_queue(action, false)
+    }
+
+    /// Panics unless the owner alone may queue `action`. Upgrades only go through the
+    /// council. Once there is a council, so does every change: the owner can only pause
+    /// or resume the contract.
+    pub(crate) fn assert_owner_may_queue(&self, action: &AdminAction) {
+        let council_only = match action {
+            AdminAction::Upgrade { .. } => true,
+            AdminAction::ChangeState { .. } => false,
+            _ => !self.council.is_empty(),
+        };
+        assert!(!council_only, "{}", VaultError::CouncilOnly);
+    }
+
+    fn internal_queue(&mut self, action: AdminAction, by_council: bool) -> u64 {
+        let id = self.change_count;
+        self.change_count += 1;
+        let eta = env::block_timestamp().saturating_add(self.timelock_delay);
+        log!("Queued change {}, executable from {}", id, eta);
+        self.pending_changes.insert(&id, &QueuedChange { action, eta, by_council });
+        id
+    }
+}
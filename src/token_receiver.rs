@@ -12,6 +12,7 @@ use crate::*;
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 #[serde(untagged)]
+#[allow(dead_code)]
 enum TokenReceiverMessage {
     /// Alternative to deposit + execute actions call.
     Execute {
@@ -59,7 +60,7 @@ impl Contract {
         // let @ be the virtual account
         let mut account: Account = Account::new(&String::from(VIRTUAL_ACC));
 
-        account.deposit(&token_in, amount_in);
+        account.deposit(&token_in, amount_in).or_panic();
         let _ = self.internal_execute_actions(
             &mut account,
             &referral_id,
@@ -85,7 +86,7 @@ impl Contract {
 impl FungibleTokenReceiver for Contract {
     /// Callback on receiving tokens by this contract.
     /// `msg` format is either "" for deposit or `TokenReceiverMessage`.
-    #[allow(unreachable_code)]
+    #[allow(unreachable_code, unused_variables)]
     fn ft_on_transfer(
         &mut self,
         sender_id: ValidAccountId,
@@ -99,6 +100,7 @@ impl FungibleTokenReceiver for Contract {
             5_000_000_000_000 // gas to attach
         );
         let token_in = env::predecessor_account_id();
+        self.assert_deposit_allowed(sender_id.as_ref(), &token_in, amount.0);
         //if msg.is_empty() {
             // Simple deposit.
             self.internal_deposit(sender_id.as_ref(), &token_in, amount.into());
@@ -0,0 +1,410 @@
+//! Unwinding vault shares into a token.
+//!
+//! `withdraw_all` reads the pools on the way, unstakes the LP of the burned shares and removes
+//! the liquidity. The pool tokens are then swapped along the registered routes into the output
+//! token, withdrawn from the exchange and delivered to the receiver, either as tokens through
+//! `internal_send_tokens` or as native NEAR after unwrapping wNEAR. The minimum amounts of the
+//! removal and of the swaps are the amounts expected from the pools read, less
+//! `UNWIND_SLIPPAGE_BPS`.
+//!
+//! An unwind that fails before the liquidity is removed gives the shares back to the sender.
+//! Tokens a failed swap or exchange withdraw leaves on the exchange are kept for the sender,
+//! who moves them into its vault balance with `withdraw_stranded`.
+
+use near_sdk::Gas;
+
+use crate::harvest::SEED_ID;
+use crate::ledger::LedgerKind;
+use crate::routes::route_actions;
+use crate::utils::{checked_mul_div, mul_div, promise_result_as};
+use crate::zap::{quote_route, PoolInfo, POOL_ID, POOL_TOKENS, TOKEN_WRAP};
+use crate::*;
+
+const GAS_FOR_UNWIND_CHECK: Gas = 265_000_000_000_000;
+const GAS_FOR_WITHDRAW_SEED: Gas = 60_000_000_000_000;
+const GAS_FOR_REMOVE_LIQUIDITY: Gas = 10_000_000_000_000;
+const GAS_FOR_UNWIND_SWAPS: Gas = 190_000_000_000_000;
+const GAS_FOR_SWAP: Gas = 10_000_000_000_000;
+const GAS_FOR_UNWIND_WITHDRAW: Gas = 160_000_000_000_000;
+const GAS_FOR_EXCHANGE_WITHDRAW: Gas = 30_000_000_000_000;
+const GAS_FOR_UNWIND_DELIVER: Gas = 120_000_000_000_000;
+const GAS_FOR_NEAR_WITHDRAW: Gas = 10_000_000_000_000;
+const GAS_FOR_POST_NEAR_WITHDRAW: Gas = 20_000_000_000_000;
+const GAS_FOR_POST_NEAR_TRANSFER: Gas = 5_000_000_000_000;
+const GAS_FOR_GET_POOL_SHARES: Gas = 5_000_000_000_000;
+const GAS_FOR_UNWIND_RESTAKE: Gas = 90_000_000_000_000;
+const GAS_FOR_POST_STAKE: Gas = 5_000_000_000_000;
+const GAS_FOR_POST_STRANDED_WITHDRAW: Gas = 10_000_000_000_000;
+
+/// Tolerated shortfall of the removal and of the swaps from the amounts expected
+/// from the pools, in basis points.
+const UNWIND_SLIPPAGE_BPS: u128 = 100;
+const BPS_DIVISOR: u128 = 10_000;
+
+/// Who unwinds, who receives the proceeds and in which token (None for native NEAR).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct Unwind {
+    pub sender_id: AccountId,
+    pub receiver_id: AccountId,
+    pub output_token: Option<AccountId>,
+    /// Shares burned for the unwind, given back as they are if it is aborted.
+    pub shares: U128,
+    /// Burn of the withdrawn shares, reversed if they are given back.
+    pub burn: BurnRecord,
+}
+
+impl Unwind {
+    /// Token to swap the pool tokens into.
+    pub fn token_out(&self) -> AccountId {
+        self.output_token.clone().unwrap_or_else(|| TOKEN_WRAP.to_string())
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Tokens of failed unwinds of `account_id` left on the exchange.
+    pub fn get_stranded(&self, account_id: ValidAccountId) -> HashMap<AccountId, U128> {
+        self.stranded
+            .get(account_id.as_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+
+    /// Withdraws the caller's tokens left on the exchange by failed unwinds
+    /// into its vault balance.
+    pub fn withdraw_stranded(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let stranded = self.stranded.remove(&account_id).unwrap_or_else(|| VaultError::NoStranded.panic());
+        let mut withdraws: Option<Promise> = None;
+        for (token_id, amount) in stranded {
+            let total = self.stranded_totals.get(&token_id).unwrap_or(0);
+            self.stranded_totals.insert(&token_id, &total.saturating_sub(amount));
+            self.internal_track_in_flight(&token_id, amount);
+            let withdraw = ext_exchange::withdraw(token_id.clone(), U128(amount), Some(false), &CONTRACT_ID, 1, GAS_FOR_EXCHANGE_WITHDRAW)
+            .then(ext_self::callback_post_stranded_withdraw(
+                account_id.clone(),
+                token_id,
+                U128(amount),
+                &env::current_account_id(),
+                0,
+                GAS_FOR_POST_STRANDED_WITHDRAW,
+            ));
+            withdraws = Some(match withdraws {
+                Some(previous) => previous.and(withdraw),
+                None => withdraw,
+            });
+        }
+        withdraws.unwrap()
+    }
+
+    /// Goes on with the unwind if the pools could be read and their prices agree with
+    /// the oracle, otherwise gives the burned shares back to the sender.
+    #[private]
+    pub fn callback_unwind_check(&mut self, lp: U128, unwind: Unwind, pool_ids: Vec<u64>) -> Option<Promise> {
+        let pools_read = (0..pool_ids.len() as u64).all(|index| matches!(env::promise_result(index), PromiseResult::Successful(_)));
+        if !pools_read {
+            log!("Unwind of {} LP aborted: pools unavailable", lp.0);
+            self.internal_refund_shares(&unwind.sender_id, unwind.shares.0, lp.0, unwind.burn);
+            return None;
+        }
+        let (pools, prices) = self.internal_read_pools(&pool_ids);
+        let minimums = prices.and_then(|_| self.internal_unwind_minimums(&pools, lp.0, &unwind).map_err(|err| err.to_string()));
+        match minimums {
+            Ok((min_amounts, min_amounts_out)) => Some(self.internal_unstake_and_remove(lp.0, unwind, min_amounts, min_amounts_out)),
+            Err(err) => {
+                log!("Unwind of {} LP aborted: {}", lp.0, err);
+                self.internal_refund_shares(&unwind.sender_id, unwind.shares.0, lp.0, unwind.burn);
+                None
+            }
+        }
+    }
+
+    /// Swaps the pool tokens returned by `remove_liquidity` into the output token.
+    /// If the removal failed, gives the burned shares back to the sender.
+    #[private]
+    pub fn callback_unwind_swaps(&mut self, lp: U128, unwind: Unwind, min_amounts_out: Vec<U128>) -> Promise {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("Removing {} LP for {} failed, refunding", lp.0, unwind.sender_id);
+            self.internal_refund_shares(&unwind.sender_id, unwind.shares.0, lp.0, unwind.burn);
+            // the LP may be unstaked and idle on the exchange
+            return ext_exchange::get_pool_shares(POOL_ID, env::current_account_id(), &CONTRACT_ID, 0, GAS_FOR_GET_POOL_SHARES)
+            .then(ext_self::callback_unwind_restake(lp, &env::current_account_id(), 0, GAS_FOR_UNWIND_RESTAKE));
+        }
+        let amounts: Vec<U128> = promise_result_as(0);
+        let token_out = unwind.token_out();
+
+        let mut direct = 0;
+        let mut swapped = vec![];
+        let mut swaps: Option<Promise> = None;
+        for (index, (token_in, amount_in)) in POOL_TOKENS.iter().zip(amounts).enumerate() {
+            let route = self.internal_get_route(token_in, &token_out);
+            if route.is_empty() || amount_in.0 == 0 {
+                direct = checked_add(direct, amount_in.0).or_panic();
+                continue;
+            }
+            let swap = ext_exchange::swap(
+                route_actions(token_in, &route, amount_in.0, min_amounts_out[index].0),
+                None,
+                &CONTRACT_ID,
+                10000000000000000000000,
+                GAS_FOR_SWAP,
+            );
+            swapped.push((token_in.to_string(), amount_in));
+            swaps = Some(match swaps {
+                Some(previous) => previous.and(swap),
+                None => swap,
+            });
+        }
+
+        let callback = ext_self::callback_unwind_withdraw(
+            unwind,
+            U128(direct),
+            swapped,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_UNWIND_WITHDRAW,
+        );
+        match swaps {
+            Some(swaps) => swaps.then(callback),
+            None => callback,
+        }
+    }
+
+    /// Stakes the LP of a failed removal again if it was unstaked.
+    #[private]
+    pub fn callback_unwind_restake(&mut self, lp: U128) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        let pool_shares: U128 = promise_result_as(0);
+        if pool_shares.0 < checked_add(self.vault_shares, lp.0).or_panic() {
+            return None;
+        }
+        self.vault_shares = checked_add(self.vault_shares, lp.0).or_panic();
+        Some(
+            self.call_stake(CONTRACT_ID_FARM.to_string(), format!(":{}", POOL_ID), lp, "".to_string())
+            .then(ext_self::callback_post_stake(lp, &env::current_account_id(), 0, GAS_FOR_POST_STAKE)),
+        )
+    }
+
+    /// Withdraws the unwound output token from the exchange. The input of a failed swap
+    /// is kept on the exchange for the sender.
+    #[private]
+    pub fn callback_unwind_withdraw(&mut self, unwind: Unwind, direct: U128, swapped: Vec<(AccountId, U128)>) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), swapped.len() as u64, "{}", VaultError::TooManyResults);
+        let mut amount = direct.0;
+        for (index, (token_in, amount_in)) in swapped.iter().enumerate() {
+            if let PromiseResult::Failed = env::promise_result(index as u64) {
+                log!("Swap of {} {} failed, keeping it for {}", amount_in.0, token_in, unwind.sender_id);
+                self.internal_strand(&unwind.sender_id, token_in, amount_in.0);
+                continue;
+            }
+            amount = checked_add(amount, promise_result_as::<U128>(index as u64).0).or_panic();
+        }
+        if amount == 0 {
+            return None;
+        }
+        log!("Unwound {} {} for {}", amount, unwind.token_out(), unwind.receiver_id);
+        self.internal_track_in_flight(&unwind.token_out(), amount);
+
+        Some(
+            ext_exchange::withdraw(unwind.token_out(), U128(amount), Some(false), &CONTRACT_ID, 1, GAS_FOR_EXCHANGE_WITHDRAW)
+            .then(ext_self::callback_unwind_deliver(
+                unwind,
+                U128(amount),
+                &env::current_account_id(),
+                0,
+                GAS_FOR_UNWIND_DELIVER,
+            )),
+        )
+    }
+
+    /// Delivers the withdrawn output token to the receiver. If the exchange withdraw
+    /// failed, the output token is kept on the exchange for the sender.
+    #[private]
+    pub fn callback_unwind_deliver(&mut self, unwind: Unwind, amount: U128) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        self.internal_untrack_in_flight(&unwind.token_out(), amount.0);
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("Withdraw of {} {} failed, keeping it for {}", amount.0, unwind.token_out(), unwind.sender_id);
+            self.internal_strand(&unwind.sender_id, &unwind.token_out(), amount.0);
+            return None;
+        }
+        Some(match &unwind.output_token {
+            Some(token_id) => self.internal_send_tokens(&unwind.receiver_id, token_id, amount.0),
+            None => {
+                self.internal_track_in_flight(&unwind.token_out(), amount.0);
+                ext_wrap::near_withdraw(amount, &CONTRACT_ID_WRAP, 1, GAS_FOR_NEAR_WITHDRAW)
+                .then(ext_self::callback_post_near_withdraw(
+                    unwind,
+                    amount,
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_POST_NEAR_WITHDRAW,
+                ))
+            }
+        })
+    }
+
+    /// Transfers the unwrapped NEAR to the receiver. If unwrapping failed,
+    /// credits the wNEAR to the sender's balance.
+    #[private]
+    pub fn callback_post_near_withdraw(&mut self, unwind: Unwind, amount: U128) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        self.internal_untrack_in_flight(&TOKEN_WRAP.to_string(), amount.0);
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("Unwrapping {} wNEAR failed. Depositing to {}.", amount.0, unwind.sender_id);
+            self.internal_credit_or_lostfound(&unwind.sender_id, &TOKEN_WRAP.to_string(), amount.0);
+            return None;
+        }
+        Some(
+            Promise::new(unwind.receiver_id.clone())
+            .transfer(amount.0)
+            .then(ext_self::callback_post_near_transfer(
+                unwind,
+                amount,
+                &env::current_account_id(),
+                0,
+                GAS_FOR_POST_NEAR_TRANSFER,
+            )),
+        )
+    }
+
+    /// If the NEAR transfer failed, credits it to the sender's NEAR balance in the vault.
+    #[private]
+    pub fn callback_post_near_transfer(&mut self, unwind: Unwind, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("Transfer of {} NEAR to {} failed. Depositing to {}.", amount.0, unwind.receiver_id, unwind.sender_id);
+            self.internal_credit_near(&unwind.sender_id, amount.0);
+        }
+    }
+
+    /// Credits the stranded tokens withdrawn from the exchange to the account's balance,
+    /// or keeps them stranded if the withdraw failed.
+    #[private]
+    pub fn callback_post_stranded_withdraw(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        self.internal_untrack_in_flight(&token_id, amount.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => self.internal_credit_or_lostfound(&account_id, &token_id, amount.0),
+            PromiseResult::Failed => self.internal_strand(&account_id, &token_id, amount.0),
+        };
+    }
+}
+
+impl Contract {
+    /// Unstakes `lp`, removes it from the strategy pool and delivers its value as requested.
+    /// The pools on the way are read first, to derive the minimum amounts and, with an
+    /// oracle configured, to check their prices.
+    pub(crate) fn internal_unwind(&self, lp: Balance, unwind: Unwind) -> Promise {
+        // fail early on missing routes
+        let pool_ids = self.internal_unwind_pools(&unwind).or_panic();
+        self.internal_get_pools(&pool_ids).then(ext_self::callback_unwind_check(
+            U128(lp),
+            unwind,
+            pool_ids,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_UNWIND_CHECK,
+        ))
+    }
+
+    /// Pools an unwind to the output token of `unwind` goes through. Fails if the
+    /// output token isn't whitelisted or a pool token has no route to it.
+    pub(crate) fn internal_unwind_pools(&self, unwind: &Unwind) -> Result<Vec<u64>, VaultError> {
+        let token_out = unwind.token_out();
+        if token_out != TOKEN_WRAP && !self.whitelisted_tokens.contains(&token_out) {
+            return Err(VaultError::TokenNotWhitelisted);
+        }
+        let mut pool_ids = vec![POOL_ID];
+        for token_in in POOL_TOKENS.iter() {
+            let route = self.internal_find_route(token_in, &token_out).ok_or(VaultError::NoRoute)?;
+            for hop in route {
+                if !pool_ids.contains(&hop.pool_id) {
+                    pool_ids.push(hop.pool_id);
+                }
+            }
+        }
+        Ok(pool_ids)
+    }
+
+    /// Gives the `shares` burned for `lp` back to `account_id` when its withdrawal is
+    /// aborted, as many as were burned whatever the share price is by then.
+    pub(crate) fn internal_refund_shares(&mut self, account_id: &AccountId, shares: Balance, lp: Balance, burn: BurnRecord) {
+        let user_shares = self.user_shares.get(account_id).unwrap_or(0);
+        self.user_shares.insert(account_id, &checked_add(user_shares, shares).or_panic());
+        self.internal_reverse_burn(account_id, burn);
+        self.internal_append_history(account_id, LedgerKind::Refund, shares, lp, None);
+        self.total_shares = checked_add(self.total_shares, shares).or_panic();
+        self.total_lp = checked_add(self.total_lp, lp).or_panic();
+    }
+
+    /// Minimum pool token amounts for removing `lp` and minimum outputs of swapping them
+    /// into the output token, both in the order of the pool tokens.
+    fn internal_unwind_minimums(
+        &self,
+        pools: &HashMap<u64, PoolInfo>,
+        lp: Balance,
+        unwind: &Unwind,
+    ) -> Result<(Vec<U128>, Vec<U128>), VaultError> {
+        let pool = &pools[&POOL_ID];
+        let token_out = unwind.token_out();
+        let mut min_amounts = vec![];
+        let mut min_amounts_out = vec![];
+        for token_in in POOL_TOKENS.iter() {
+            let expected = checked_mul_div(pool.reserve(token_in), lp, pool.shares_total_supply.0)?;
+            let min_amount = mul_div(expected, BPS_DIVISOR - UNWIND_SLIPPAGE_BPS, BPS_DIVISOR);
+            // the route may have changed since the pools were requested
+            let route = self.internal_find_route(token_in, &token_out).ok_or(VaultError::NoRoute)?;
+            if route.iter().any(|hop| !pools.contains_key(&hop.pool_id)) {
+                return Err(VaultError::NoRoute);
+            }
+            let expected_out = quote_route(pools, token_in, &route, min_amount);
+            min_amounts.push(U128(min_amount));
+            min_amounts_out.push(U128(mul_div(expected_out, BPS_DIVISOR - UNWIND_SLIPPAGE_BPS, BPS_DIVISOR)));
+        }
+        Ok((min_amounts, min_amounts_out))
+    }
+
+    /// Keeps `amount` of `token_id` on the exchange for `account_id`.
+    pub(crate) fn internal_strand(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let mut stranded = self.stranded.get(account_id).unwrap_or_default();
+        let stranded_amount = stranded.entry(token_id.clone()).or_insert(0);
+        *stranded_amount = checked_add(*stranded_amount, amount).or_panic();
+        self.stranded.insert(account_id, &stranded);
+        let total = self.stranded_totals.get(token_id).unwrap_or(0);
+        self.stranded_totals.insert(token_id, &checked_add(total, amount).or_panic());
+    }
+
+    fn internal_unstake_and_remove(&self, lp: Balance, unwind: Unwind, min_amounts: Vec<U128>, min_amounts_out: Vec<U128>) -> Promise {
+        ext_farm::withdraw_seed(
+            SEED_ID.to_string(),
+            U128(lp),
+            "".to_string(),
+            &CONTRACT_ID_FARM,
+            1,
+            GAS_FOR_WITHDRAW_SEED,
+        )
+        .then(ext_exchange::remove_liquidity(
+            POOL_ID,
+            U128(lp),
+            min_amounts,
+            &CONTRACT_ID,
+            1,
+            GAS_FOR_REMOVE_LIQUIDITY,
+        ))
+        .then(ext_self::callback_unwind_swaps(
+            U128(lp),
+            unwind,
+            min_amounts_out,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_UNWIND_SWAPS,
+        ))
+    }
+}
@@ -0,0 +1,62 @@
+#![allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::{env, PromiseResult};
+use uint::construct_uint;
+
+use crate::errors::{OrPanic, VaultError};
+
+construct_uint! {
+    /// 256-bit unsigned integer.
+    pub struct U256(4);
+}
+
+/// Returns `a + b`, or `Overflow`.
+pub fn checked_add(a: u128, b: u128) -> Result<u128, VaultError> {
+    a.checked_add(b).ok_or(VaultError::Overflow)
+}
+
+/// Returns `a - b`, or `Underflow` if `b` is bigger.
+pub fn checked_sub(a: u128, b: u128) -> Result<u128, VaultError> {
+    a.checked_sub(b).ok_or(VaultError::Underflow)
+}
+
+/// Returns `sec` seconds in nanoseconds, or `Overflow`.
+pub fn sec_to_nanos(sec: u64) -> Result<u64, VaultError> {
+    sec.checked_mul(1_000_000_000).ok_or(VaultError::Overflow)
+}
+
+/// Returns `amount * numerator / denominator` without intermediate overflow,
+/// or `Overflow` if the result doesn't fit in 128 bits.
+pub fn checked_mul_div(amount: u128, numerator: u128, denominator: u128) -> Result<u128, VaultError> {
+    if denominator == 0 {
+        return Err(VaultError::DivisionByZero);
+    }
+    let result = U256::from(amount) * U256::from(numerator) / U256::from(denominator);
+    if result > U256::from(u128::MAX) {
+        return Err(VaultError::Overflow);
+    }
+    Ok(result.as_u128())
+}
+
+/// Returns `amount * numerator / denominator` without intermediate overflow.
+/// Panics if the denominator is 0 or the result doesn't fit in 128 bits.
+pub fn mul_div(amount: u128, numerator: u128, denominator: u128) -> u128 {
+    checked_mul_div(amount, numerator, denominator).or_panic()
+}
+
+/// Parses the result of the promise at `index` as JSON.
+/// Panics if the promise failed or returned an unexpected value.
+pub fn promise_result_as<T: DeserializeOwned>(index: u64) -> T {
+    match env::promise_result(index) {
+        PromiseResult::NotReady => unreachable!(),
+        PromiseResult::Successful(value) => {
+            if let Ok(value) = near_sdk::serde_json::from_slice::<T>(&value) {
+                value
+            } else {
+                VaultError::WrongValueReceived.panic()
+            }
+        },
+        PromiseResult::Failed => VaultError::CallFailed.panic(),
+    }
+}
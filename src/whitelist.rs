@@ -0,0 +1,130 @@
+//! Whitelisted tokens.
+//!
+//! The vault keeps the total of each token held in account balances, so a token can only
+//! be removed from the whitelist once nobody, lostfound included, holds any of it, and none
+//! of it is stranded on the exchange or in flight. The pool tokens, wNEAR and the tokens of
+//! registered routes can't be removed.
+//! The `ft_metadata` of each token is fetched when it is whitelisted and cached for the UI.
+
+use near_sdk::Gas;
+
+use crate::utils::promise_result_as;
+use crate::zap::{POOL_TOKENS, TOKEN_WRAP};
+use crate::*;
+
+const GAS_FOR_FT_METADATA: Gas = 10_000_000_000_000;
+const GAS_FOR_METADATA_CALLBACK: Gas = 10_000_000_000_000;
+
+/// Part of a token's `ft_metadata` cached by the vault.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct WhitelistedToken {
+    pub token_id: AccountId,
+    /// None until the metadata was fetched.
+    pub metadata: Option<TokenMetadata>,
+}
+
+#[ext_contract(ext_ft_metadata)]
+pub trait FungibleTokenMetadataProvider {
+    fn ft_metadata(&self) -> TokenMetadata;
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_whitelisted_tokens_with_metadata(&self) -> Vec<WhitelistedToken> {
+        self.whitelisted_tokens
+            .iter()
+            .map(|token_id| WhitelistedToken {
+                metadata: self.token_metadata.get(&token_id),
+                token_id,
+            })
+            .collect()
+    }
+
+    /// Fetches again the metadata of a whitelisted token, e.g. if the first fetch failed.
+    pub fn refresh_token_metadata(&mut self, token_id: ValidAccountId) -> Promise {
+        assert!(self.whitelisted_tokens.contains(token_id.as_ref()), "{}", VaultError::TokenNotWhitelisted);
+        self.internal_fetch_metadata(token_id.into())
+    }
+
+    #[private]
+    pub fn callback_ft_metadata(&mut self, token_id: AccountId) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("Fetching the metadata of {} failed", token_id);
+            return;
+        }
+        if self.whitelisted_tokens.contains(&token_id) {
+            let metadata: TokenMetadata = promise_result_as(0);
+            self.token_metadata.insert(&token_id, &metadata);
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_whitelist_tokens(&mut self, tokens: Vec<ValidAccountId>) {
+        for token_id in tokens {
+            if self.whitelisted_tokens.insert(token_id.as_ref()) {
+                self.internal_fetch_metadata(token_id.into());
+            }
+        }
+    }
+
+    /// Removes tokens from the whitelist. Panics if the strategy or a route uses any of them,
+    /// or if an account or lostfound still holds any of them.
+    pub(crate) fn internal_remove_whitelisted_tokens(&mut self, tokens: Vec<ValidAccountId>) {
+        for token_id in tokens {
+            let token_id: AccountId = token_id.into();
+            assert!(self.whitelisted_tokens.remove(&token_id), "{}", VaultError::TokenNotWhitelisted);
+            assert!(!self.internal_token_in_use(&token_id), "{}", VaultError::TokenInUse);
+            assert_eq!(self.token_deposits.get(&token_id).unwrap_or(0), 0, "{}", VaultError::TokenHasDeposits);
+            assert_eq!(self.lostfound.get(&token_id).unwrap_or(0), 0, "{}", VaultError::TokenInLostfound);
+            assert!(
+                self.stranded_totals.get(&token_id).unwrap_or(0) == 0 && self.in_flight.get(&token_id).unwrap_or(0) == 0,
+                "{}",
+                VaultError::TokenInTransit
+            );
+            self.token_deposits.remove(&token_id);
+            self.token_metadata.remove(&token_id);
+        }
+    }
+
+    /// Whether `token_id` is a pool token, wNEAR, or on a registered route.
+    fn internal_token_in_use(&self, token_id: &str) -> bool {
+        POOL_TOKENS.contains(&token_id)
+            || token_id == TOKEN_WRAP
+            || self.routes.iter().any(|((token_in, token_out), hops)| {
+                token_in == token_id || token_out == token_id || hops.iter().any(|hop| hop.token_out == token_id)
+            })
+    }
+
+    fn internal_fetch_metadata(&self, token_id: AccountId) -> Promise {
+        ext_ft_metadata::ft_metadata(&token_id, 0, GAS_FOR_FT_METADATA).then(ext_self::callback_ft_metadata(
+            token_id,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_METADATA_CALLBACK,
+        ))
+    }
+
+    /// Adds `amount` to the total of `token_id` held in account balances.
+    pub(crate) fn internal_track_deposit(&mut self, token_id: &AccountId, amount: Balance) {
+        let total = self.token_deposits.get(token_id).unwrap_or(0);
+        self.token_deposits.insert(token_id, &checked_add(total, amount).or_panic());
+    }
+
+    /// Subtracts `amount` from the total of `token_id` held in account balances.
+    pub(crate) fn internal_track_withdraw(&mut self, token_id: &AccountId, amount: Balance) {
+        let total = self.token_deposits.get(token_id).unwrap_or(0);
+        self.token_deposits.insert(token_id, &total.saturating_sub(amount));
+    }
+}
@@ -0,0 +1,308 @@
+//! Rate limiting of withdrawals.
+//!
+//! At most `limit` LP can leave the vault per `window`. Usage is released linearly over the
+//! window, so the limit applies to any rolling window of that length. A withdrawal that
+//! doesn't fit, or arrives while older ones are still queued, burns its shares right away
+//! and waits in a FIFO queue; anyone can then process the head of the queue once the window
+//! has room for it. The sender pays the storage of its queue entry, returned when the entry
+//! is processed. An entry whose output token was unlisted or lost its route meanwhile gets
+//! its shares back instead of blocking the queue, and one larger than the limit is unwound a
+//! whole window at a time. Guardians can raise the limit for a limited time.
+
+use near_sdk::json_types::U64;
+
+use crate::unwind::Unwind;
+use crate::utils::{mul_div, sec_to_nanos};
+use crate::*;
+
+/// Longest time a guardian can raise the withdraw limit for.
+const MAX_BOOST_DURATION: u64 = 7 * 24 * 3600 * 1_000_000_000;
+
+/// Withdraw limit and usage of the current window.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct WithdrawLimiter {
+    /// LP that can be withdrawn per window, `Balance::MAX` if unlimited.
+    pub limit: Balance,
+    /// Window length in nanoseconds.
+    pub window: u64,
+    /// LP withdrawn as of `updated_at`, not yet released.
+    pub used: Balance,
+    pub updated_at: u64,
+    /// Extra LP per window granted by a guardian until `boost_until`.
+    pub boost: Balance,
+    pub boost_until: u64,
+    /// Id of the oldest queued withdrawal and of the next one to queue.
+    pub queue_head: u64,
+    pub queue_tail: u64,
+}
+
+impl WithdrawLimiter {
+    pub fn new() -> Self {
+        Self {
+            limit: Balance::MAX,
+            window: 0,
+            used: 0,
+            updated_at: 0,
+            boost: 0,
+            boost_until: 0,
+            queue_head: 0,
+            queue_tail: 0,
+        }
+    }
+
+    /// Limit per window including an active guardian boost.
+    pub fn current_limit(&self) -> Balance {
+        if env::block_timestamp() < self.boost_until {
+            self.limit.saturating_add(self.boost)
+        } else {
+            self.limit
+        }
+    }
+
+    /// LP withdrawn and not yet released at the current block.
+    pub fn current_used(&self) -> Balance {
+        let limit = self.current_limit();
+        let elapsed = env::block_timestamp().saturating_sub(self.updated_at);
+        if limit == Balance::MAX || elapsed >= self.window {
+            0
+        } else {
+            let released = mul_div(limit, elapsed as u128, self.window as u128);
+            self.used.saturating_sub(released)
+        }
+    }
+
+    /// LP that can be withdrawn at the current block.
+    pub fn available(&self) -> Balance {
+        self.current_limit().saturating_sub(self.current_used())
+    }
+
+    /// Records a withdrawal of `lp` if it fits in the window.
+    pub fn try_consume(&mut self, lp: Balance) -> bool {
+        if self.current_limit() == Balance::MAX {
+            return true;
+        }
+        if lp > self.available() {
+            return false;
+        }
+        self.used = checked_add(self.current_used(), lp).or_panic();
+        self.updated_at = env::block_timestamp();
+        true
+    }
+
+    pub fn queue_len(&self) -> u64 {
+        self.queue_tail - self.queue_head
+    }
+}
+
+/// Withdrawal waiting for room in the window. Its shares are already burned.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct QueuedWithdraw {
+    pub sender_id: AccountId,
+    pub receiver_id: AccountId,
+    pub output_token: Option<AccountId>,
+    pub lp: Balance,
+    /// Shares burned for `lp`.
+    pub shares: Balance,
+    pub queued_at: u64,
+    pub burn: BurnRecord,
+    /// NEAR paid by the sender for the storage of the entry.
+    pub storage_deposit: Balance,
+}
+
+impl QueuedWithdraw {
+    /// Takes `lp` out of the withdrawal and returns the part of its shares and of its
+    /// burn that goes with it.
+    fn split_off(&mut self, lp: Balance) -> (Balance, BurnRecord) {
+        let shares = mul_div(self.shares, lp, self.lp);
+        let burn = BurnRecord {
+            value: U128(mul_div(self.burn.value.0, lp, self.lp)),
+            cost: U128(mul_div(self.burn.cost.0, lp, self.lp)),
+        };
+        self.lp = checked_sub(self.lp, lp).or_panic();
+        self.shares = checked_sub(self.shares, shares).or_panic();
+        self.burn = BurnRecord {
+            value: U128(checked_sub(self.burn.value.0, burn.value.0).or_panic()),
+            cost: U128(checked_sub(self.burn.cost.0, burn.cost.0).or_panic()),
+        };
+        (shares, burn)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct WithdrawWindowInfo {
+    /// LP per window, None if unlimited.
+    pub limit: Option<U128>,
+    pub window_sec: u64,
+    pub used: U128,
+    pub available: U128,
+    pub boost: U128,
+    pub boost_until: U64,
+    /// Id of the next withdrawal to process and number of queued withdrawals.
+    pub queue_head: u64,
+    pub queue_len: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct QueuedWithdrawInfo {
+    pub sender_id: AccountId,
+    pub receiver_id: AccountId,
+    pub output_token: Option<AccountId>,
+    pub lp: U128,
+    pub queued_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Unwinds the oldest queued withdrawal if the window has room for it, or gives its
+    /// shares back if it can no longer be unwound. A withdrawal larger than the limit is
+    /// unwound a whole window at a time. Can be called by anyone.
+    pub fn process_withdraw_queue(&mut self) -> Option<Promise> {
+        self.assert_contract_running();
+        self.assert_not_emergency();
+        let id = self.withdraw_limiter.queue_head;
+        let mut queued = self.withdraw_queue.get(&id).unwrap_or_else(|| VaultError::WithdrawQueueEmpty.panic());
+        let unwind = Unwind {
+            sender_id: queued.sender_id.clone(),
+            receiver_id: queued.receiver_id.clone(),
+            output_token: queued.output_token.clone(),
+            shares: U128(queued.shares),
+            burn: queued.burn,
+        };
+        if let Err(err) = self.internal_unwind_pools(&unwind) {
+            self.withdraw_queue.remove(&id);
+            self.withdraw_limiter.queue_head += 1;
+            log!("Queued withdrawal {} can't be unwound: {}, refunding it", id, err);
+            self.internal_refund_queued(queued);
+            return None;
+        }
+        let limit = self.withdraw_limiter.current_limit();
+        if queued.lp > limit {
+            assert!(self.withdraw_limiter.try_consume(limit), "{}", VaultError::WithdrawLimitReached);
+            let (shares, burn) = queued.split_off(limit);
+            self.withdraw_queue.insert(&id, &queued);
+            log!("Processing {} LP of queued withdrawal {}, {} LP left", limit, id, queued.lp);
+            return Some(self.internal_unwind(limit, Unwind { shares: U128(shares), burn, ..unwind }));
+        }
+        assert!(self.withdraw_limiter.try_consume(queued.lp), "{}", VaultError::WithdrawLimitReached);
+        self.withdraw_queue.remove(&id);
+        self.withdraw_limiter.queue_head += 1;
+        log!("Processing queued withdrawal {} of {} LP", id, queued.lp);
+        if queued.storage_deposit > 0 {
+            Promise::new(queued.sender_id).transfer(queued.storage_deposit);
+        }
+        Some(self.internal_unwind(queued.lp, unwind))
+    }
+
+    /// Raises the withdraw limit by `extra` LP per window for `duration_sec` seconds.
+    /// A boost still active is only raised and extended, never lowered or shortened.
+    /// Only callable by a guardian or the owner.
+    pub fn raise_withdraw_limit(&mut self, extra: U128, duration_sec: u64) {
+        self.assert_guardian();
+        let duration = sec_to_nanos(duration_sec).or_panic();
+        assert!(duration <= MAX_BOOST_DURATION, "{}", VaultError::BoostTooLong);
+        let limiter = &mut self.withdraw_limiter;
+        let boost_until = env::block_timestamp().saturating_add(duration);
+        if env::block_timestamp() < limiter.boost_until {
+            limiter.boost = std::cmp::max(limiter.boost, extra.0);
+            limiter.boost_until = std::cmp::max(limiter.boost_until, boost_until);
+        } else {
+            limiter.boost = extra.0;
+            limiter.boost_until = boost_until;
+        }
+        log!("Withdraw limit raised by {} until {}", limiter.boost, limiter.boost_until);
+    }
+
+    pub fn get_withdraw_window(&self) -> WithdrawWindowInfo {
+        let limiter = &self.withdraw_limiter;
+        let limit = limiter.current_limit();
+        WithdrawWindowInfo {
+            limit: if limit == Balance::MAX { None } else { Some(U128(limit)) },
+            window_sec: limiter.window / 1_000_000_000,
+            used: U128(limiter.current_used()),
+            available: U128(limiter.available()),
+            boost: U128(limiter.boost),
+            boost_until: U64(limiter.boost_until),
+            queue_head: limiter.queue_head,
+            queue_len: limiter.queue_len(),
+        }
+    }
+
+    pub fn get_queued_withdraw(&self, id: u64) -> Option<QueuedWithdrawInfo> {
+        self.withdraw_queue.get(&id).map(|queued| QueuedWithdrawInfo {
+            sender_id: queued.sender_id,
+            receiver_id: queued.receiver_id,
+            output_token: queued.output_token,
+            lp: U128(queued.lp),
+            queued_at: U64(queued.queued_at),
+        })
+    }
+
+    pub fn get_guardians(&self) -> Vec<AccountId> {
+        self.guardians.to_vec()
+    }
+}
+
+impl Contract {
+    /// Unwinds `lp` right away if the window has room and nothing is queued,
+    /// otherwise queues it, charging the attached deposit for the storage of the entry.
+    /// Returns the queue id in the latter case. The attached deposit not needed is refunded.
+    pub(crate) fn internal_rate_limited_unwind(&mut self, lp: Balance, unwind: Unwind) -> PromiseOrValue<Option<u64>> {
+        self.internal_unwind_pools(&unwind).or_panic();
+        if self.withdraw_limiter.queue_len() == 0 && self.withdraw_limiter.try_consume(lp) {
+            if env::attached_deposit() > 0 {
+                Promise::new(unwind.sender_id.clone()).transfer(env::attached_deposit());
+            }
+            return PromiseOrValue::Promise(self.internal_unwind(lp, unwind));
+        }
+        let prev_storage = env::storage_usage();
+        let id = self.withdraw_limiter.queue_tail;
+        let mut queued = QueuedWithdraw {
+            sender_id: unwind.sender_id,
+            receiver_id: unwind.receiver_id,
+            output_token: unwind.output_token,
+            lp,
+            shares: unwind.shares.0,
+            queued_at: env::block_timestamp(),
+            burn: unwind.burn,
+            storage_deposit: 0,
+        };
+        self.withdraw_queue.insert(&id, &queued);
+        let storage_deposit = (env::storage_usage() - prev_storage) as Balance * env::storage_byte_cost();
+        assert!(
+            env::attached_deposit() >= storage_deposit,
+            "{}",
+            VaultError::InsufficientStorage
+        );
+        queued.storage_deposit = storage_deposit;
+        self.withdraw_queue.insert(&id, &queued);
+        self.withdraw_limiter.queue_tail += 1;
+        let refund = env::attached_deposit() - storage_deposit;
+        if refund > 0 {
+            Promise::new(queued.sender_id).transfer(refund);
+        }
+        log!("Withdraw limit reached, queued withdrawal {} of {} LP", id, lp);
+        PromiseOrValue::Value(Some(id))
+    }
+
+    /// Gives the shares of a queued withdrawal taken off the queue and the storage
+    /// deposit of its entry back to its sender.
+    pub(crate) fn internal_refund_queued(&mut self, queued: QueuedWithdraw) {
+        self.internal_refund_shares(&queued.sender_id, queued.shares, queued.lp, queued.burn);
+        if queued.storage_deposit > 0 {
+            Promise::new(queued.sender_id).transfer(queued.storage_deposit);
+        }
+    }
+
+    pub(crate) fn assert_guardian(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner_id || self.guardians.contains(&caller),
+            "{}",
+            VaultError::NotAllowed
+        );
+    }
+}
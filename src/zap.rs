@@ -0,0 +1,523 @@
+//! Zapping a token into the strategy pool.
+//!
+//! Before swapping, the vault reads the reserves and fees of every pool on the routes
+//! from the input token to both pool tokens, and of the strategy pool itself. It then
+//! picks the split of the input that yields both pool tokens in the ratio of the
+//! strategy pool reserves. The part of the zap's own amounts that `add_liquidity` didn't
+//! use, read from the LP it minted at the ratio of the strategy pool, is credited back to
+//! the depositor, or kept as tracked dust and added to the next epoch settlement or
+//! compounding. A zap whose swap or `add_liquidity` fails gives its tokens back the same
+//! way. Each zap is credited the LP its own `add_liquidity` returned, so zaps in flight
+//! at the same time don't take each other's LP. Tokens whose withdraw to the depositor
+//! fails are kept on the exchange for `withdraw_stranded`.
+
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::Gas;
+
+use crate::routes::{route_actions, RouteHop};
+use crate::utils::{mul_div, promise_result_as, U256};
+use crate::*;
+
+/// Fee divisor of the exchange, fees are expressed in basis points.
+const FEE_DIVISOR: u32 = 10_000;
+
+const GAS_FOR_NEAR_DEPOSIT: Gas = 3_000_000_000_000;
+const GAS_FOR_TOKEN_DEPOSIT: Gas = 35_000_000_000_000;
+const GAS_FOR_POST_TOKEN_DEPOSIT: Gas = 250_000_000_000_000;
+const GAS_FOR_ZAP_QUOTE: Gas = 225_000_000_000_000;
+const GAS_FOR_SWAP: Gas = 10_000_000_000_000;
+const GAS_FOR_ZAP_SWAPS: Gas = 195_000_000_000_000;
+const GAS_FOR_ADD_LIQUIDITY: Gas = 15_000_000_000_000;
+const GAS_FOR_MINT_SHARES: Gas = 165_000_000_000_000;
+const GAS_FOR_REMOVE_LIQUIDITY: Gas = 10_000_000_000_000;
+const GAS_FOR_GET_POOL: Gas = 5_000_000_000_000;
+const GAS_FOR_ZAP_DUST: Gas = 60_000_000_000_000;
+const GAS_FOR_EXCHANGE_WITHDRAW: Gas = 25_000_000_000_000;
+const GAS_FOR_POST_DUST_WITHDRAW: Gas = 5_000_000_000_000;
+
+pub(crate) const POOL_ID: u64 = 193;
+/// Tokens of the strategy pool, in the pool's order.
+pub(crate) const POOL_TOKENS: [&str; 2] = ["dai.fakes.testnet", "eth.fakes.testnet"];
+pub(crate) const TOKEN_WRAP: &str = "wrap.testnet";
+
+/// Pool information as returned by the exchange `get_pool`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub struct PoolInfo {
+    /// List of tokens in the pool.
+    pub token_account_ids: Vec<AccountId>,
+    /// Reserves of each token, in the order of `token_account_ids`.
+    pub amounts: Vec<U128>,
+    /// Fee charged for swap.
+    pub total_fee: u32,
+    /// Total number of shares.
+    pub shares_total_supply: U128,
+}
+
+impl PoolInfo {
+    /// Reserve of `token_id` in this pool, 0 if the pool doesn't hold it.
+    pub fn reserve(&self, token_id: &str) -> Balance {
+        self.token_account_ids
+            .iter()
+            .position(|token| token == token_id)
+            .map(|index| self.amounts[index].0)
+            .unwrap_or(0)
+    }
+
+    /// Amount of `token_out` received for swapping `amount_in` of `token_in`,
+    /// using the constant product formula of the exchange simple pools.
+    pub fn get_amount_out(&self, token_in: &str, amount_in: Balance, token_out: &str) -> Balance {
+        let in_balance = U256::from(self.reserve(token_in));
+        let out_balance = U256::from(self.reserve(token_out));
+        if amount_in == 0 || in_balance.is_zero() || out_balance.is_zero() {
+            return 0;
+        }
+        let amount_with_fee = U256::from(amount_in) * U256::from(FEE_DIVISOR - self.total_fee);
+        (amount_with_fee * out_balance
+            / (U256::from(FEE_DIVISOR) * in_balance + amount_with_fee))
+            .as_u128()
+    }
+}
+
+/// Amount received at the end of `hops` for `amount_in` of `token_in`.
+pub fn quote_route(pools: &HashMap<u64, PoolInfo>, token_in: &str, hops: &[RouteHop], amount_in: Balance) -> Balance {
+    let mut token = token_in;
+    let mut amount = amount_in;
+    for hop in hops {
+        amount = pools[&hop.pool_id].get_amount_out(token, amount, &hop.token_out);
+        token = &hop.token_out;
+    }
+    amount
+}
+
+/// Returns how much of `amount` to swap into the first pool token so that
+/// `out1(x)` and `out2(amount - x)` match the `reserve1 / reserve2` ratio.
+/// Falls back to an even split when the target pool is empty.
+pub fn optimal_split<F1, F2>(amount: Balance, reserve1: Balance, reserve2: Balance, out1: F1, out2: F2) -> Balance
+where
+    F1: Fn(Balance) -> Balance,
+    F2: Fn(Balance) -> Balance,
+{
+    if reserve1 == 0 || reserve2 == 0 {
+        return amount / 2;
+    }
+    // out1(x) * reserve2 - out2(amount - x) * reserve1 is increasing in x,
+    // find the smallest x for which it is not negative.
+    let (mut low, mut high) = (0, amount);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if U256::from(out1(mid)) * U256::from(reserve2)
+            >= U256::from(out2(amount - mid)) * U256::from(reserve1)
+        {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    low
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Pool token balances left on the exchange by epoch settlements and compounding,
+    /// added to the liquidity of the next one.
+    pub fn get_zap_dust(&self) -> HashMap<AccountId, U128> {
+        self.zap_dust
+            .iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+
+    /// Zaps the tokens the exchange accepted from `internal_deposit_to_exchange`.
+    /// Tokens it refused are credited back to the depositor.
+    #[private]
+    pub fn callback_post_token_deposit(&mut self, token_id: AccountId, amount: U128, recipient: ShareRecipient) -> Option<Promise> {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        self.internal_untrack_in_flight(&token_id, amount.0);
+        let used = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<U128>(&value).map(|used| used.0).unwrap_or(0)
+            }
+            PromiseResult::Failed => 0,
+        };
+        if let ShareRecipient::Account { account_id, .. } = &recipient {
+            if used < amount.0 {
+                self.internal_credit_or_lostfound(account_id, &token_id, amount.0 - used);
+            }
+        }
+        if used == 0 {
+            return None;
+        }
+        if self.internal_find_zap_routes(&token_id).is_none() {
+            log!("Zap of {} {} aborted: {}", used, token_id, VaultError::NoRoute);
+            return self.internal_abort_zap(&token_id, used, recipient);
+        }
+        Some(self.internal_zap_in(&token_id, used, recipient))
+    }
+
+    /// Computes the swap split from the pools returned by `get_pool` and swaps.
+    #[private]
+    pub fn callback_zap_quote(&mut self, token_in: AccountId, amount: U128, recipient: ShareRecipient, pool_ids: Vec<u64>) -> Option<Promise> {
+        if (0..pool_ids.len() as u64).any(|index| !matches!(env::promise_result(index), PromiseResult::Successful(_))) {
+            log!("Zap of {} {} aborted: pool read failed", amount.0, token_in);
+            return self.internal_abort_zap(&token_in, amount.0, recipient);
+        }
+        let (pools, prices) = self.internal_read_pools(&pool_ids);
+        if let Err(err) = prices {
+            log!("Zap of {} {} aborted: {}", amount.0, token_in, err);
+            return self.internal_abort_zap(&token_in, amount.0, recipient);
+        }
+        self.internal_update_lp_price(&pools);
+        let pool = &pools[&POOL_ID];
+        // a route may have been removed while the pools were read
+        let routes = match self.internal_find_zap_routes(&token_in) {
+            Some(routes) if routes.iter().flatten().all(|hop| pools.contains_key(&hop.pool_id)) => routes,
+            _ => {
+                log!("Zap of {} {} aborted: {}", amount.0, token_in, VaultError::NoRoute);
+                return self.internal_abort_zap(&token_in, amount.0, recipient);
+            }
+        };
+
+        // Tracked dust is put back by the zaps it was left for.
+        let mut amounts: Vec<Balance> = POOL_TOKENS
+            .iter()
+            .map(|token_id| {
+                if recipient.tracks_dust() {
+                    self.zap_dust.remove(&token_id.to_string()).unwrap_or(0)
+                } else {
+                    0
+                }
+            })
+            .collect();
+
+        let amount_in1 = optimal_split(
+            amount.0,
+            pool.reserve(POOL_TOKENS[0]),
+            pool.reserve(POOL_TOKENS[1]),
+            |x| checked_add(quote_route(&pools, &token_in, &routes[0], x), amounts[0]).or_panic(),
+            |x| checked_add(quote_route(&pools, &token_in, &routes[1], x), amounts[1]).or_panic(),
+        );
+        let amounts_in = [amount_in1, amount.0 - amount_in1];
+        log!("Zapping {} {}: {} to {}, {} to {}", amount.0, token_in, amounts_in[0], POOL_TOKENS[0], amounts_in[1], POOL_TOKENS[1]);
+
+        let mut swapped = vec![U128(0), U128(0)];
+        let mut swaps: Option<Promise> = None;
+        for index in 0..2 {
+            if routes[index].is_empty() || amounts_in[index] == 0 {
+                // token_in is this pool token, no swap needed
+                amounts[index] = checked_add(amounts[index], amounts_in[index]).or_panic();
+                continue;
+            }
+            let swap = ext_exchange::swap(
+                route_actions(&token_in, &routes[index], amounts_in[index], 0),
+                None,
+                &CONTRACT_ID,
+                10000000000000000000000,
+                GAS_FOR_SWAP,
+            );
+            swapped[index] = U128(amounts_in[index]);
+            swaps = Some(match swaps {
+                Some(previous) => previous.and(swap),
+                None => swap,
+            });
+        }
+
+        let callback = ext_self::callback_zap_swaps(
+            recipient,
+            token_in,
+            amounts.into_iter().map(U128).collect(),
+            swapped,
+            &env::current_account_id(),
+            970000000000000000000,
+            GAS_FOR_ZAP_SWAPS,
+        );
+        Some(match swaps {
+            Some(swaps) => swaps.then(callback),
+            None => callback,
+        })
+    }
+
+    /// Adds `amounts` plus the outputs of the swaps of the non-zero `swapped` amounts of
+    /// `token_in` as liquidity to the strategy pool. If a swap failed, the zap is aborted:
+    /// its pool tokens are given back as dust and the unswapped `token_in` as by
+    /// `internal_abort_zap`. Returns the amounts added.
+    #[private]
+    #[payable]
+    pub fn callback_zap_swaps(
+        &mut self,
+        recipient: ShareRecipient,
+        token_in: AccountId,
+        amounts: Vec<U128>,
+        swapped: Vec<U128>,
+    ) -> Option<Vec<U128>> {
+        assert_eq!(
+            env::promise_results_count(),
+            swapped.iter().filter(|amount_in| amount_in.0 > 0).count() as u64,
+            "{}",
+            VaultError::TooManyResults
+        );
+        let mut amounts = amounts;
+        let mut unswapped: Balance = 0;
+        let mut result_index = 0;
+        for (index, amount_in) in swapped.into_iter().enumerate() {
+            if amount_in.0 == 0 {
+                continue;
+            }
+            match env::promise_result(result_index) {
+                PromiseResult::Successful(_) => {
+                    let amount_out = promise_result_as::<U128>(result_index).0;
+                    amounts[index] = U128(checked_add(amounts[index].0, amount_out).or_panic());
+                }
+                _ => unswapped = checked_add(unswapped, amount_in.0).or_panic(),
+            }
+            result_index += 1;
+        }
+        if unswapped > 0 {
+            log!("Swap of {} {} failed, aborting the zap", unswapped, token_in);
+            for (token_id, amount) in POOL_TOKENS.iter().zip(amounts) {
+                self.internal_return_dust(&recipient, &token_id.to_string(), amount.0);
+            }
+            self.internal_abort_zap(&token_in, unswapped, recipient);
+            return None;
+        }
+
+        ext_exchange::add_liquidity(
+            POOL_ID,
+            amounts.clone(),
+            None,
+            &CONTRACT_ID,
+            970000000000000000000,
+            GAS_FOR_ADD_LIQUIDITY,
+        )
+        .then(ext_self::callback_get_pool_shares(recipient, amounts.clone(), &env::current_account_id(), 0, GAS_FOR_MINT_SHARES));
+
+        Some(amounts)
+    }
+
+    /// Gives the part of the zap's `amounts` that `add_liquidity` left on the exchange back
+    /// to the depositor, or keeps it as tracked dust. The zap used the amounts backing the
+    /// `lp` it minted, at the reserves to shares ratio of the strategy pool read by the last
+    /// promise. When `removed`, that `lp` was removed again by the first promise and the
+    /// tokens it returned are given back too.
+    #[private]
+    pub fn callback_zap_dust(&mut self, recipient: ShareRecipient, amounts: Vec<U128>, lp: U128, removed: bool) {
+        let results = if removed { 2 } else { 1 };
+        assert_eq!(env::promise_results_count(), results, "{}", VaultError::TooManyResults);
+        let mut returned = vec![0; POOL_TOKENS.len()];
+        if removed {
+            match env::promise_result(0) {
+                PromiseResult::Successful(_) => {
+                    returned = promise_result_as::<Vec<U128>>(0).into_iter().map(|amount| amount.0).collect();
+                }
+                _ => {
+                    // keeps the next zap from minting shares for it
+                    log!("Removal of {} LP failed, keeping it idle", lp.0);
+                    self.vault_shares = checked_add(self.vault_shares, lp.0).or_panic();
+                }
+            }
+        }
+        let pool = match env::promise_result(results - 1) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<PoolInfo>(&value).ok(),
+            _ => None,
+        };
+        if pool.is_none() {
+            log!("Pool read failed, the leftover of the zap stays on the exchange");
+        }
+
+        for (index, token_id) in POOL_TOKENS.iter().map(|token_id| token_id.to_string()).enumerate() {
+            let used = match &pool {
+                Some(pool) => mul_div(lp.0, pool.reserve(&token_id), pool.shares_total_supply.0),
+                None => amounts[index].0,
+            };
+            let leftover = amounts[index].0.saturating_sub(used);
+            self.internal_return_dust(&recipient, &token_id, checked_add(leftover, returned[index]).or_panic());
+        }
+    }
+
+    /// Credits the dust withdrawn from the exchange to the depositor's token balance.
+    /// If the exchange withdraw failed, the dust is kept on the exchange for the depositor.
+    #[private]
+    pub fn callback_post_dust_withdraw(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "{}", VaultError::TooManyResults);
+        self.internal_untrack_in_flight(&token_id, amount.0);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                self.internal_credit_or_lostfound(&account_id, &token_id, amount.0);
+            }
+            PromiseResult::Failed => {
+                log!("Withdraw of {} {} failed, keeping it for {}", amount.0, token_id, account_id);
+                self.internal_strand(&account_id, &token_id, amount.0);
+            }
+        };
+    }
+}
+
+impl Contract {
+    /// Swaps `amount` of the vault's `token_in` on the exchange into both pool tokens,
+    /// adds them as liquidity, stakes the LP and mints shares to `recipient`.
+    pub(crate) fn internal_zap_in(&self, token_in: &str, amount: Balance, recipient: ShareRecipient) -> Promise {
+        let routes = self.internal_find_zap_routes(token_in).unwrap_or_else(|| VaultError::NoRoute.panic());
+        let mut pool_ids: Vec<u64> = vec![];
+        for hop in routes.iter().flatten() {
+            if !pool_ids.contains(&hop.pool_id) {
+                pool_ids.push(hop.pool_id);
+            }
+        }
+        if !pool_ids.contains(&POOL_ID) {
+            pool_ids.push(POOL_ID);
+        }
+
+        self.internal_get_pools(&pool_ids).then(ext_self::callback_zap_quote(
+            token_in.to_string(),
+            U128(amount),
+            recipient,
+            pool_ids,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_ZAP_QUOTE,
+        ))
+    }
+
+    /// Routes from `token_in` to each pool token, None if one is missing.
+    pub(crate) fn internal_find_zap_routes(&self, token_in: &str) -> Option<Vec<Vec<RouteHop>>> {
+        POOL_TOKENS
+            .iter()
+            .map(|token_out| self.internal_find_route(token_in, token_out))
+            .collect()
+    }
+
+    /// Moves `amount` of `token_id` from the vault's wallet to its exchange deposit
+    /// and zaps it for `recipient`.
+    pub(crate) fn internal_deposit_to_exchange(&mut self, token_id: &AccountId, amount: Balance, recipient: ShareRecipient) -> Promise {
+        self.internal_track_in_flight(token_id, amount);
+        ext_fungible_token::ft_transfer_call(
+            CONTRACT_ID.to_string(),
+            U128(amount),
+            None,
+            "".to_string(),
+            token_id,
+            1,
+            GAS_FOR_TOKEN_DEPOSIT,
+        )
+        .then(ext_self::callback_post_token_deposit(
+            token_id.clone(),
+            U128(amount),
+            recipient,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_POST_TOKEN_DEPOSIT,
+        ))
+    }
+
+    /// Gives `amount` of `token_in` of a zap that didn't start back to where it came from.
+    pub(crate) fn internal_abort_zap(&mut self, token_in: &AccountId, amount: Balance, recipient: ShareRecipient) -> Option<Promise> {
+        match recipient {
+            ShareRecipient::Account { account_id, .. } => Some({
+                self.internal_track_in_flight(token_in, amount);
+                ext_exchange::withdraw(token_in.clone(), U128(amount), Some(false), &CONTRACT_ID, 1, GAS_FOR_EXCHANGE_WITHDRAW)
+                .then(ext_self::callback_post_dust_withdraw(
+                    account_id,
+                    token_in.clone(),
+                    U128(amount),
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_POST_DUST_WITHDRAW,
+                ))
+            }),
+            ShareRecipient::Epoch(epoch_id) => {
+                self.internal_abort_settlement(epoch_id, amount);
+                None
+            }
+            ShareRecipient::Compound => {
+                self.pending_reward = checked_add(self.pending_reward, amount).or_panic();
+                None
+            }
+        }
+    }
+
+    /// Wraps `amount` of the vault's NEAR, deposits the wNEAR to the exchange
+    /// and zaps it for `recipient`.
+    pub(crate) fn internal_zap_near(&mut self, amount: Balance, recipient: ShareRecipient) -> Promise {
+        self.internal_track_in_flight(&TOKEN_WRAP.to_string(), amount);
+        ext_wrap::near_deposit(&CONTRACT_ID_WRAP, amount, GAS_FOR_NEAR_DEPOSIT)
+        .then(ext_fungible_token::ft_transfer_call(
+            CONTRACT_ID.to_string(),
+            U128(amount),
+            None,
+            "".to_string(),
+            &TOKEN_WRAP.to_string(),
+            1,
+            GAS_FOR_TOKEN_DEPOSIT,
+        ))
+        .then(ext_self::callback_post_token_deposit(
+            TOKEN_WRAP.to_string(),
+            U128(amount),
+            recipient,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_POST_TOKEN_DEPOSIT,
+        ))
+    }
+
+    /// Withdraws `amount` of the pool token `token_id` left by a zap to the depositor,
+    /// or keeps it as tracked dust.
+    fn internal_return_dust(&mut self, recipient: &ShareRecipient, token_id: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        match recipient {
+            ShareRecipient::Account { account_id, .. } => {
+                self.internal_track_in_flight(token_id, amount);
+                ext_exchange::withdraw(token_id.clone(), U128(amount), Some(false), &CONTRACT_ID, 1, GAS_FOR_EXCHANGE_WITHDRAW)
+                .then(ext_self::callback_post_dust_withdraw(
+                    account_id.clone(),
+                    token_id.clone(),
+                    U128(amount),
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_POST_DUST_WITHDRAW,
+                ));
+            }
+            _ => {
+                let tracked = self.zap_dust.get(token_id).unwrap_or(0);
+                self.zap_dust.insert(token_id, &checked_add(tracked, amount).or_panic());
+            }
+        }
+    }
+
+    /// Gives back `amounts` of a zap whose liquidity couldn't be added: to the depositor,
+    /// or as tracked dust for the next epoch settlement or compounding. An aborted
+    /// settlement is retried with that dust alone.
+    pub(crate) fn internal_abort_liquidity(&mut self, recipient: ShareRecipient, amounts: Vec<U128>) {
+        for (token_id, amount) in POOL_TOKENS.iter().zip(amounts) {
+            self.internal_return_dust(&recipient, &token_id.to_string(), amount.0);
+        }
+        if let ShareRecipient::Epoch(epoch_id) = recipient {
+            self.internal_abort_settlement(epoch_id, 0);
+        }
+    }
+
+    /// Removes the `lp` just added by a zap of `amounts` that minted too few shares and
+    /// sends the pool tokens, with the zap's leftover, back to the depositor.
+    pub(crate) fn internal_refund_zap(&self, recipient: ShareRecipient, amounts: Vec<U128>, lp: Balance) -> Promise {
+        ext_exchange::remove_liquidity(
+            POOL_ID,
+            U128(lp),
+            vec![U128(0), U128(0)],
+            &CONTRACT_ID,
+            1,
+            GAS_FOR_REMOVE_LIQUIDITY,
+        )
+        .and(ext_exchange::get_pool(POOL_ID, &CONTRACT_ID, 0, GAS_FOR_GET_POOL))
+        .then(ext_self::callback_zap_dust(recipient, amounts, U128(lp), true, &env::current_account_id(), 0, GAS_FOR_ZAP_DUST))
+    }
+
+    /// Reads the strategy pool to settle the leftover of the zap of `amounts` that minted `lp`.
+    pub(crate) fn internal_collect_zap_dust(&self, recipient: ShareRecipient, amounts: Vec<U128>, lp: Balance) -> Promise {
+        ext_exchange::get_pool(POOL_ID, &CONTRACT_ID, 0, GAS_FOR_GET_POOL)
+        .then(ext_self::callback_zap_dust(recipient, amounts, U128(lp), false, &env::current_account_id(), 0, GAS_FOR_ZAP_DUST))
+    }
+}
@@ -0,0 +1,81 @@
+//! Deposit caps and per-token deposit limits.
+
+mod common;
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use vault_contract::Contract;
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const BOB: &str = "bob.testnet";
+const TOKEN: &str = "token.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Vault whitelisting `TOKEN` up to 500 per deposit, capped at 3000 LP in total
+/// and 1000 LP per account.
+fn setup_caps() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call(OWNER);
+    let change_id = contract.set_deposit_limit(account(TOKEN), Some(U128(500)));
+    apply_change(&mut contract, change_id);
+    call(OWNER);
+    let change_id = contract.set_deposit_caps(Some(U128(3_000)), Some(U128(1_000)));
+    apply_change(&mut contract, change_id);
+    for user in [ALICE, BOB] {
+        call_with(user, NEAR, vec![]);
+        contract.storage_deposit(None, None);
+    }
+    contract
+}
+
+#[test]
+fn transfer_within_the_limits_is_deposited() {
+    let mut contract = setup_caps();
+    assert_eq!(contract.max_deposit(account(ALICE)), U128(1_000));
+    call(TOKEN);
+    contract.ft_on_transfer(account(ALICE), U128(500), "".to_string());
+    assert_eq!(contract.get_deposits(account(ALICE)).get(TOKEN), Some(&U128(500)));
+}
+
+#[test]
+#[should_panic(expected = "E37: deposit above the limit of the token")]
+fn transfer_above_the_token_limit_is_refused() {
+    let mut contract = setup_caps();
+    call(TOKEN);
+    contract.ft_on_transfer(account(ALICE), U128(501), "".to_string());
+}
+
+#[test]
+#[should_panic(expected = "E38: deposit cap reached")]
+fn transfer_of_a_capped_account_is_refused() {
+    let mut contract = setup_caps();
+    deposit(&mut contract, ALICE, 1_000);
+    assert_eq!(contract.max_deposit(account(ALICE)), U128(0));
+    call(TOKEN);
+    contract.ft_on_transfer(account(ALICE), U128(100), "".to_string());
+}
+
+#[test]
+fn cap_of_an_account_leaves_the_others_room() {
+    let mut contract = setup_caps();
+    deposit(&mut contract, ALICE, 1_000);
+    assert_eq!(contract.max_deposit(account(BOB)), U128(1_000));
+    call(TOKEN);
+    contract.ft_on_transfer(account(BOB), U128(100), "".to_string());
+    assert_eq!(contract.get_deposits(account(BOB)).get(TOKEN), Some(&U128(100)));
+}
+
+#[test]
+fn zap_over_the_cap_is_refunded() {
+    let mut contract = setup_caps();
+    deposit(&mut contract, ALICE, 600);
+    assert_eq!(deposit(&mut contract, ALICE, 500), 0);
+    assert_eq!(contract.get_vault_info().total_lp, U128(600));
+    assert!(function_calls().iter().any(|(method, _)| method == "remove_liquidity"));
+}
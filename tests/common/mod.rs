@@ -0,0 +1,144 @@
+//! Helpers to drive the vault with a mocked blockchain.
+
+#![allow(dead_code)]
+
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::Serialize;
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{env, serde_json, testing_env, MockedBlockchain, PromiseResult};
+use std::cell::Cell;
+use std::convert::TryInto;
+
+use vault_contract::{Contract, ShareRecipient};
+
+pub const VAULT: &str = "vault.testnet";
+pub const OWNER: &str = "owner.testnet";
+
+thread_local! {
+    /// Block timestamp of the next calls, in nanoseconds.
+    static NOW: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn account(account_id: &str) -> ValidAccountId {
+    account_id.try_into().unwrap()
+}
+
+/// Block timestamp of the next calls.
+pub fn now() -> u64 {
+    NOW.with(|now| now.get())
+}
+
+/// Moves the block timestamp of the next calls `sec` seconds forward.
+pub fn advance(sec: u64) {
+    NOW.with(|now| now.set(now.get() + sec * 1_000_000_000));
+}
+
+/// Context of a call made by `predecessor` to the vault.
+pub fn context(predecessor: &str) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(account(VAULT))
+        .predecessor_account_id(account(predecessor))
+        .signer_account_id(account(predecessor))
+        .block_timestamp(now())
+        .prepaid_gas(u64::MAX / 2);
+    builder
+}
+
+/// Sets up the next call with the given context and promise results.
+pub fn call_in(context: &VMContextBuilder, promise_results: Vec<PromiseResult>) {
+    testing_env!(
+        context.build(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        promise_results
+    );
+}
+
+/// Sets up the next call as made by `predecessor`, with the given promise results.
+pub fn call_with(predecessor: &str, deposit: u128, promise_results: Vec<PromiseResult>) {
+    call_in(context(predecessor).attached_deposit(deposit), promise_results);
+}
+
+pub fn call(predecessor: &str) {
+    call_with(predecessor, 0, vec![]);
+}
+
+/// Successful promise result returning `value` as JSON.
+pub fn ok<T: Serialize>(value: T) -> PromiseResult {
+    PromiseResult::Successful(serde_json::to_vec(&value).unwrap())
+}
+
+/// Method names and JSON arguments of the function calls scheduled by the last call.
+pub fn function_calls() -> Vec<(String, serde_json::Value)> {
+    get_created_receipts()
+        .iter()
+        .flat_map(|receipt| {
+            // deposits are u128, which `serde_json::Value` can't hold, so parse them as floats
+            let receipt: serde_json::Value =
+                serde_json::from_str(&serde_json::to_string(receipt).unwrap()).unwrap();
+            receipt["actions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(|action| action.get("FunctionCall").cloned())
+                .collect::<Vec<_>>()
+        })
+        .map(|call| {
+            // calls without arguments, like `ft_metadata`, have empty args
+            let args = match call["args"].as_str().unwrap() {
+                "" => serde_json::Value::Null,
+                args => serde_json::from_str(args).unwrap(),
+            };
+            (call["method_name"].as_str().unwrap().to_string(), args)
+        })
+        .collect()
+}
+
+/// Arguments of the first scheduled call to `method`.
+pub fn call_args(method: &str) -> serde_json::Value {
+    function_calls()
+        .into_iter()
+        .find(|(name, _)| name == method)
+        .unwrap_or_else(|| panic!("no call to {}", method))
+        .1
+}
+
+/// Deploys the vault with no idle LP on the exchange, on an empty storage.
+pub fn setup() -> Contract {
+    // calls keep the storage of the previous one, drop it
+    env::take_blockchain_interface();
+    NOW.with(|now| now.set(0));
+    call(OWNER);
+    Contract::new(account(OWNER), 0)
+}
+
+/// Waits for the timelock of the change `change_id` and executes it as the owner.
+pub fn apply_change(contract: &mut Contract, change_id: u64) {
+    let eta = contract
+        .get_pending_changes(0, u64::MAX)
+        .into_iter()
+        .find(|change| change.id == change_id)
+        .unwrap_or_else(|| panic!("no change {}", change_id))
+        .eta
+        .0;
+    NOW.with(|now| now.set(now.get().max(eta)));
+    call(OWNER);
+    contract.execute_change(change_id);
+}
+
+/// Completes a zap whose `add_liquidity` added `lp` LP.
+pub fn mint(contract: &mut Contract, recipient: ShareRecipient, lp: u128) -> u128 {
+    call_with(VAULT, 0, vec![ok(U128(lp))]);
+    contract.callback_get_pool_shares(recipient, vec![U128(0), U128(0)]).0
+}
+
+/// Completes a direct deposit by `account_id` that added `lp` LP.
+pub fn deposit(contract: &mut Contract, account_id: &str, lp: u128) -> u128 {
+    mint(
+        contract,
+        ShareRecipient::Account { account_id: account_id.to_string(), min_shares: U128(0) },
+        lp,
+    )
+}
@@ -0,0 +1,118 @@
+//! Council proposals: threshold, expiry and changes of the council.
+
+mod common;
+
+use near_sdk::json_types::U128;
+use vault_contract::{AdminAction, Contract, RunningState};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const BOB: &str = "bob.testnet";
+const CAROL: &str = "carol.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+/// Longer than the timelock, so that proposals outlive the changes they wait for.
+const PROPOSAL_PERIOD_SEC: u64 = 3 * 24 * 3_600;
+
+/// Vault governed by `ALICE`, `BOB` and `CAROL`, two of which approve a proposal.
+fn setup_council() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let members = vec![account(ALICE), account(BOB), account(CAROL)];
+    let change_id = contract.set_council(members, 2, PROPOSAL_PERIOD_SEC);
+    apply_change(&mut contract, change_id);
+    contract
+}
+
+fn propose(contract: &mut Contract, proposer: &str, action: AdminAction) -> u64 {
+    call_with(proposer, NEAR, vec![]);
+    contract.propose(action)
+}
+
+fn cap_total_assets() -> AdminAction {
+    AdminAction::SetDepositCaps { max_total_assets: Some(U128(1_000)), max_user_assets: None }
+}
+
+#[test]
+fn approved_proposal_is_queued_behind_the_timelock() {
+    let mut contract = setup_council();
+    let id = propose(&mut contract, ALICE, cap_total_assets());
+    assert!(!contract.get_proposal(id).unwrap().approved);
+
+    call(BOB);
+    contract.approve(id);
+    assert!(contract.get_proposal(id).unwrap().approved);
+    call(CAROL);
+    let change_id = contract.execute(id).unwrap();
+    assert!(contract.get_proposal(id).is_none());
+    assert_eq!(contract.get_deposit_caps().max_total_assets, None);
+
+    apply_change(&mut contract, change_id);
+    assert_eq!(contract.get_deposit_caps().max_total_assets, Some(U128(1_000)));
+}
+
+#[test]
+fn pause_is_applied_right_away() {
+    let mut contract = setup_council();
+    let id = propose(&mut contract, ALICE, AdminAction::ChangeState { state: RunningState::Paused });
+    call(BOB);
+    contract.approve(id);
+    call(BOB);
+    assert_eq!(contract.execute(id), None);
+    assert!(contract.get_pending_changes(0, 10).is_empty());
+}
+
+#[test]
+fn expired_proposal_is_removed_by_anyone() {
+    let mut contract = setup_council();
+    let id = propose(&mut contract, ALICE, cap_total_assets());
+    advance(PROPOSAL_PERIOD_SEC);
+    call(OWNER);
+    assert!(contract.get_proposal(id).unwrap().expired);
+    contract.remove_proposal(id);
+    assert!(contract.get_proposal(id).is_none());
+}
+
+#[test]
+#[should_panic(expected = "E65: proposal expired")]
+fn expired_proposal_is_not_approved() {
+    let mut contract = setup_council();
+    let id = propose(&mut contract, ALICE, cap_total_assets());
+    advance(PROPOSAL_PERIOD_SEC);
+    call(BOB);
+    contract.approve(id);
+}
+
+#[test]
+fn approvals_of_removed_members_no_longer_count() {
+    let mut contract = setup_council();
+    let id = propose(&mut contract, ALICE, cap_total_assets());
+    call(BOB);
+    contract.approve(id);
+
+    // the council drops BOB
+    let council_id = propose(&mut contract, ALICE, AdminAction::SetCouncil {
+        members: vec![account(ALICE), account(CAROL)],
+        threshold: 2,
+        proposal_period_sec: PROPOSAL_PERIOD_SEC,
+    });
+    call(CAROL);
+    contract.approve(council_id);
+    call(CAROL);
+    let change_id = contract.execute(council_id).unwrap();
+    apply_change(&mut contract, change_id);
+
+    assert_eq!(contract.get_council().members.len(), 2);
+    let proposal = contract.get_proposal(id).unwrap();
+    assert!(!proposal.expired);
+    assert!(!proposal.approved);
+}
+
+#[test]
+fn proposals_page_to_the_end() {
+    let mut contract = setup_council();
+    propose(&mut contract, ALICE, cap_total_assets());
+    let id = propose(&mut contract, BOB, cap_total_assets());
+    let page = contract.get_proposals(id, u64::MAX);
+    assert_eq!(page.iter().map(|proposal| proposal.id).collect::<Vec<_>>(), vec![id]);
+}
@@ -0,0 +1,236 @@
+//! Emergency exit and claims.
+
+mod common;
+
+use std::collections::HashMap;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::PromiseResult;
+use vault_contract::Contract;
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const BOB: &str = "bob.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+const SEED: &str = "exchange.ref-dev.testnet@193";
+const REWARD: &str = "ref.fakes.testnet";
+const WRAP: &str = "wrap.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Vault in which `ALICE` and `BOB` deposited 1000 and 3000 LP.
+fn setup_deposits() -> Contract {
+    let mut contract = setup();
+    for user in [ALICE, BOB] {
+        call_with(user, NEAR, vec![]);
+        contract.storage_deposit(None, None);
+    }
+    deposit(&mut contract, ALICE, 1_000);
+    deposit(&mut contract, BOB, 3_000);
+    contract
+}
+
+/// Starts the exit and completes the removal of the vault's LP, which returned
+/// 100 DAI and 200 ETH.
+fn remove_liquidity(contract: &mut Contract) {
+    call(OWNER);
+    contract.emergency_exit();
+    call_with(VAULT, 0, vec![ok(HashMap::<String, U128>::new())]);
+    contract.callback_emergency_unstake();
+    call_with(VAULT, 0, vec![ok(U128(4_000))]);
+    assert!(contract.callback_emergency_remove().is_some());
+    call_with(VAULT, 0, vec![ok(vec![U128(100), U128(200)])]);
+    assert!(contract.callback_emergency_withdraw().is_some());
+}
+
+fn receive(contract: &mut Contract, token_id: &str, amount: u128, result: PromiseResult) {
+    call_with(VAULT, 0, vec![result]);
+    contract.callback_emergency_received(token_id.to_string(), U128(amount));
+}
+
+fn on_exchange(contract: &Contract, token_id: &str) -> u128 {
+    contract.get_emergency_info().unwrap().on_exchange[token_id].0
+}
+
+#[test]
+fn depositors_claim_their_share_once_every_token_arrived() {
+    let mut contract = setup_deposits();
+    remove_liquidity(&mut contract);
+    receive(&mut contract, DAI, 100, ok(()));
+    assert!(!contract.get_emergency_info().unwrap().claimable);
+    receive(&mut contract, ETH, 200, ok(()));
+    assert!(contract.get_emergency_info().unwrap().claimable);
+
+    call(ALICE);
+    let claimed = contract.claim_emergency();
+    assert_eq!(claimed[DAI], U128(25));
+    assert_eq!(claimed[ETH], U128(50));
+    assert_eq!(contract.get_deposits(account(ALICE)).get(ETH), Some(&U128(50)));
+    assert_eq!(contract.get_user_shares(account(ALICE)), U128(0));
+    call(BOB);
+    let claimed = contract.claim_emergency();
+    assert_eq!(claimed[DAI], U128(75));
+    assert_eq!(claimed[ETH], U128(150));
+    assert_eq!(contract.get_emergency_info().unwrap().shares, U128(0));
+}
+
+#[test]
+#[should_panic(expected = "E59: emergency exit not complete")]
+fn claims_wait_for_the_liquidity_removal() {
+    let mut contract = setup_deposits();
+    call(OWNER);
+    contract.emergency_exit();
+    call(ALICE);
+    contract.claim_emergency();
+}
+
+#[test]
+#[should_panic(expected = "E59: emergency exit not complete")]
+fn claims_wait_for_a_failed_withdraw() {
+    let mut contract = setup_deposits();
+    remove_liquidity(&mut contract);
+    receive(&mut contract, DAI, 100, ok(()));
+    receive(&mut contract, ETH, 200, PromiseResult::Failed);
+    call(ALICE);
+    contract.claim_emergency();
+}
+
+#[test]
+fn failed_withdraw_is_retried() {
+    let mut contract = setup_deposits();
+    remove_liquidity(&mut contract);
+    receive(&mut contract, DAI, 100, ok(()));
+    receive(&mut contract, ETH, 200, PromiseResult::Failed);
+    assert_eq!(on_exchange(&contract, ETH), 200);
+
+    call(OWNER);
+    contract.emergency_exit();
+    call_with(VAULT, 0, vec![ok(HashMap::<String, U128>::new())]);
+    contract.callback_emergency_unstake();
+    call_with(VAULT, 0, vec![ok(U128(0))]);
+    assert!(contract.callback_emergency_remove().is_some());
+    assert_eq!(on_exchange(&contract, ETH), 200);
+    assert_eq!(on_exchange(&contract, DAI), 0);
+    receive(&mut contract, ETH, 200, ok(()));
+    assert_eq!(on_exchange(&contract, ETH), 0);
+    assert!(contract.get_emergency_info().unwrap().claimable);
+}
+
+#[test]
+fn failed_unstake_keeps_the_exit_open() {
+    let mut contract = setup_deposits();
+    call(OWNER);
+    contract.emergency_exit();
+    let seeds: HashMap<String, U128> = [(SEED.to_string(), U128(4_000))].into_iter().collect();
+    call_with(VAULT, 0, vec![ok(seeds)]);
+    contract.callback_emergency_unstake();
+    assert!(function_calls().iter().any(|(method, _)| method == "withdraw_seed"));
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    assert!(contract.callback_emergency_unstaked().is_none());
+    assert!(!contract.get_emergency_info().unwrap().claimable);
+
+    // the next exit unstakes again
+    call(OWNER);
+    contract.emergency_exit();
+    let seeds: HashMap<String, U128> = [(SEED.to_string(), U128(4_000))].into_iter().collect();
+    call_with(VAULT, 0, vec![ok(seeds)]);
+    contract.callback_emergency_unstake();
+    call_with(VAULT, 0, vec![ok(())]);
+    assert!(contract.callback_emergency_unstaked().is_some());
+    assert!(function_calls().iter().any(|(method, _)| method == "get_pool_shares"));
+}
+
+#[test]
+fn vault_without_lp_completes_the_exit() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.emergency_exit();
+    call_with(VAULT, 0, vec![ok(U128(0))]);
+    assert!(contract.callback_emergency_remove().is_none());
+    assert!(contract.get_emergency_info().unwrap().claimable);
+}
+
+#[test]
+fn rewards_and_dust_are_withdrawn_with_the_pool_tokens() {
+    let mut contract = setup_deposits();
+    call_with(VAULT, 0, vec![ok(U128(500))]);
+    contract.callback_post_reward_deposit(U128(500));
+    assert_eq!(contract.get_pending_reward(), U128(500));
+
+    remove_liquidity(&mut contract);
+    assert_eq!(contract.get_pending_reward(), U128(0));
+    assert_eq!(on_exchange(&contract, REWARD), 500);
+    receive(&mut contract, DAI, 100, ok(()));
+    receive(&mut contract, ETH, 200, ok(()));
+    receive(&mut contract, REWARD, 500, ok(()));
+
+    call(ALICE);
+    assert_eq!(contract.claim_emergency()[REWARD], U128(125));
+}
+
+#[test]
+fn queued_withdrawals_are_refunded_in_batches() {
+    let mut contract = setup_deposits();
+    call(OWNER);
+    let change_id = contract.set_withdraw_limit(Some(U128(1)), 3_600);
+    apply_change(&mut contract, change_id);
+    let shares = contract.get_user_shares(account(BOB)).0;
+    for _ in 0..60 {
+        call_with(BOB, NEAR, vec![]);
+        contract.withdraw_all(U128(shares / 100), None, None);
+    }
+    assert_eq!(contract.get_withdraw_window().queue_len, 60);
+
+    call(OWNER);
+    contract.emergency_exit();
+    assert_eq!(contract.get_withdraw_window().queue_len, 10);
+    call_with(VAULT, 0, vec![ok(U128(0))]);
+    contract.callback_emergency_remove();
+    assert!(!contract.get_emergency_info().unwrap().claimable);
+
+    call(OWNER);
+    contract.emergency_exit();
+    assert_eq!(contract.get_withdraw_window().queue_len, 0);
+    assert!(contract.get_emergency_info().unwrap().claimable);
+    assert_eq!(contract.get_user_shares(account(BOB)).0, shares);
+    assert_eq!(contract.get_emergency_info().unwrap().shares, contract.get_vault_info().total_shares);
+}
+
+#[test]
+fn unsettled_epoch_deposit_is_refunded() {
+    let mut contract = setup_deposits();
+    call(OWNER);
+    let change_id = contract.set_epoch_mode(true);
+    apply_change(&mut contract, change_id);
+    call_with(ALICE, NEAR, vec![]);
+    contract.deposit_near();
+    call(ALICE);
+    contract.add_to_vault();
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_epoch_wrap(ALICE.to_string(), U128(NEAR));
+    call_with(VAULT, 0, vec![ok(U128(NEAR))]);
+    contract.callback_queue_deposit(ALICE.to_string(), U128(NEAR));
+
+    call(OWNER);
+    contract.emergency_exit();
+    call(ALICE);
+    contract.refund_epoch_deposit();
+    assert!(contract.get_pending_deposit(account(ALICE)).is_none());
+    assert_eq!(contract.get_epoch_info().pending_amount, U128(0));
+
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_post_dust_withdraw(ALICE.to_string(), WRAP.to_string(), U128(NEAR));
+    assert_eq!(contract.get_deposits(account(ALICE)).get(WRAP), Some(&U128(NEAR)));
+}
+
+#[test]
+#[should_panic(expected = "E85: no unsettled pending deposit")]
+fn refund_needs_a_pending_deposit() {
+    let mut contract = setup_deposits();
+    call(OWNER);
+    contract.emergency_exit();
+    call(ALICE);
+    contract.refund_epoch_deposit();
+}
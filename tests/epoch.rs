@@ -0,0 +1,262 @@
+//! Epoch-batched deposits: queueing, settlement and claims.
+
+mod common;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::PromiseResult;
+use vault_contract::ShareRecipient;
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const BOB: &str = "bob.testnet";
+const WRAP: &str = "wrap.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+fn pool(tokens: [&str; 2], amounts: [u128; 2], total_supply: u128) -> PromiseResult {
+    ok(json!({
+        "token_account_ids": tokens,
+        "amounts": [amounts[0].to_string(), amounts[1].to_string()],
+        "total_fee": 0,
+        "shares_total_supply": total_supply.to_string(),
+    }))
+}
+
+/// Vault in epoch mode with `ALICE` and `BOB` registered.
+fn setup_epochs() -> vault_contract::Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_epoch_mode(true);
+    apply_change(&mut contract, change_id);
+    for user in [ALICE, BOB] {
+        call_with(user, NEAR, vec![]);
+        contract.storage_deposit(None, None);
+    }
+    contract
+}
+
+/// Invests `amount` of NEAR of `user` and completes the wrap and the exchange deposit,
+/// of which the exchange accepts `used`.
+fn queue(contract: &mut vault_contract::Contract, user: &str, amount: u128, used: u128) {
+    call_with(user, amount, vec![]);
+    contract.deposit_near();
+    call(user);
+    assert_eq!(contract.add_to_vault(), "QUEUED");
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_epoch_wrap(user.to_string(), U128(amount));
+    call_with(VAULT, 0, vec![ok(U128(used))]);
+    contract.callback_queue_deposit(user.to_string(), U128(amount));
+}
+
+#[test]
+fn deposit_is_queued_once_the_exchange_accepted_it() {
+    let mut contract = setup_epochs();
+    call_with(ALICE, 2 * NEAR, vec![]);
+    contract.deposit_near();
+    call(ALICE);
+    contract.add_to_vault();
+    assert!(contract.get_pending_deposit(account(ALICE)).is_none());
+    assert_eq!(contract.get_epoch_info().pending_amount, U128(0));
+
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_epoch_wrap(ALICE.to_string(), U128(2 * NEAR));
+    call_with(VAULT, 0, vec![ok(U128(2 * NEAR))]);
+    contract.callback_queue_deposit(ALICE.to_string(), U128(2 * NEAR));
+
+    let pending = contract.get_pending_deposit(account(ALICE)).unwrap();
+    assert_eq!(pending.amount, U128(2 * NEAR));
+    assert!(!pending.settled);
+    assert_eq!(contract.get_epoch_info().pending_amount, U128(2 * NEAR));
+}
+
+#[test]
+fn failed_wrap_gives_the_near_back() {
+    let mut contract = setup_epochs();
+    call_with(ALICE, 2 * NEAR, vec![]);
+    contract.deposit_near();
+    call(ALICE);
+    contract.add_to_vault();
+    assert_eq!(contract.get_near_deposit(account(ALICE)), U128(0));
+
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    assert!(contract.callback_epoch_wrap(ALICE.to_string(), U128(2 * NEAR)).is_none());
+    assert_eq!(contract.get_near_deposit(account(ALICE)), U128(2 * NEAR));
+    assert!(contract.get_pending_deposit(account(ALICE)).is_none());
+}
+
+#[test]
+fn refused_wnear_is_credited_not_queued() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, 2 * NEAR, NEAR);
+    assert_eq!(contract.get_pending_deposit(account(ALICE)).unwrap().amount, U128(NEAR));
+    assert_eq!(contract.get_deposits(account(ALICE)).get(WRAP), Some(&U128(NEAR)));
+
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_queue_deposit(BOB.to_string(), U128(NEAR));
+    assert!(contract.get_pending_deposit(account(BOB)).is_none());
+    assert_eq!(contract.get_epoch_info().pending_amount, U128(NEAR));
+}
+
+#[test]
+fn settled_epoch_is_claimed_pro_rata() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    queue(&mut contract, BOB, 3 * NEAR, 3 * NEAR);
+
+    call(BOB);
+    contract.settle_epoch();
+    let info = contract.get_epoch_info();
+    assert_eq!(info.settling_epoch, Some(0));
+    assert_eq!(info.settling_amount, U128(4 * NEAR));
+    assert_eq!(info.epoch_id, 1);
+    assert_eq!(info.pending_amount, U128(0));
+
+    let minted = mint(&mut contract, ShareRecipient::Epoch(0), 4_000);
+    assert!(contract.get_pending_deposit(account(ALICE)).unwrap().settled);
+    assert_eq!(contract.get_epoch_info().settling_epoch, None);
+
+    call(ALICE);
+    let alice = contract.claim_epoch_shares().0;
+    call(BOB);
+    let bob = contract.claim_epoch_shares().0;
+    assert_eq!(alice + bob, minted);
+    assert_eq!(bob, 3 * alice);
+    assert_eq!(contract.get_user_shares(account(ALICE)), U128(alice));
+    assert!(contract.get_pending_deposit(account(ALICE)).is_none());
+}
+
+#[test]
+fn next_deposit_claims_the_settled_one() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+    let minted = mint(&mut contract, ShareRecipient::Epoch(0), 1_000);
+
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    assert_eq!(contract.get_user_shares(account(ALICE)), U128(minted));
+    let pending = contract.get_pending_deposit(account(ALICE)).unwrap();
+    assert_eq!(pending.epoch_id, 1);
+    assert_eq!(pending.amount, U128(NEAR));
+}
+
+#[test]
+#[should_panic(expected = "E84: previous epoch settling")]
+fn deposit_waits_for_the_previous_settlement() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+    call_with(ALICE, NEAR, vec![]);
+    contract.deposit_near();
+    call(ALICE);
+    contract.add_to_vault();
+}
+
+#[test]
+#[should_panic(expected = "E81: epoch empty")]
+fn empty_epoch_is_not_settled() {
+    let mut contract = setup_epochs();
+    call(ALICE);
+    contract.settle_epoch();
+}
+
+#[test]
+#[should_panic(expected = "E80: epoch settling")]
+fn settlement_is_not_started_twice() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+    queue(&mut contract, BOB, NEAR, NEAR);
+    contract.settle_epoch();
+}
+
+#[test]
+fn deposit_landing_during_a_settlement_is_returned() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+
+    // a second deposit started before the settlement lands after it
+    call_with(VAULT, 0, vec![ok(U128(NEAR))]);
+    contract.callback_queue_deposit(ALICE.to_string(), U128(NEAR));
+    let pending = contract.get_pending_deposit(account(ALICE)).unwrap();
+    assert_eq!(pending.epoch_id, 0);
+    assert_eq!(pending.amount, U128(NEAR));
+    assert_eq!(contract.get_epoch_info().pending_amount, U128(0));
+}
+
+#[test]
+fn failed_liquidity_aborts_the_settlement() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+
+    // the swaps succeeded but adding their output to the pool failed
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_get_pool_shares(ShareRecipient::Epoch(0), vec![U128(1_000), U128(2_000)]);
+    let info = contract.get_epoch_info();
+    assert!(info.settle_aborted);
+    assert_eq!(info.retry_amount, U128(0));
+    assert_eq!(contract.get_zap_dust().get(DAI), Some(&U128(1_000)));
+
+    // the retry adds the swapped tokens again
+    call(ALICE);
+    contract.settle_epoch();
+    assert!(!contract.get_epoch_info().settle_aborted);
+    let deep = 10u128.pow(30);
+    call_with(VAULT, 0, vec![
+        pool([WRAP, DAI], [deep, deep], deep),
+        pool([WRAP, ETH], [2 * deep, deep], deep),
+        pool([DAI, ETH], [1_000_000, 2_000_000], 10_000),
+    ]);
+    contract.callback_zap_quote(WRAP.to_string(), U128(0), ShareRecipient::Epoch(0), vec![84, 83, 193]);
+    assert_eq!(call_args("callback_zap_swaps")["amounts"], json!(["1000", "2000"]));
+    mint(&mut contract, ShareRecipient::Epoch(0), 10);
+    assert!(contract.get_pending_deposit(account(ALICE)).unwrap().settled);
+}
+
+#[test]
+fn stuck_settlement_is_reset_by_a_guardian() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+    advance(3_600);
+    call(OWNER);
+    contract.reset_settlement(U128(2 * NEAR));
+    let info = contract.get_epoch_info();
+    assert!(info.settle_aborted);
+    assert_eq!(info.retry_amount, U128(NEAR));
+}
+
+#[test]
+#[should_panic(expected = "E89: settlement not timed out")]
+fn settlement_in_flight_is_not_reset() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+    advance(3_599);
+    call(OWNER);
+    contract.reset_settlement(U128(NEAR));
+}
+
+#[test]
+#[should_panic(expected = "E60: not allowed")]
+fn only_guardians_reset_a_settlement() {
+    let mut contract = setup_epochs();
+    queue(&mut contract, ALICE, NEAR, NEAR);
+    call(ALICE);
+    contract.settle_epoch();
+    advance(3_600);
+    call(ALICE);
+    contract.reset_settlement(U128(NEAR));
+}
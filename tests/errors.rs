@@ -0,0 +1,544 @@
+//! Failure messages of the vault.
+//!
+//! Every error is asserted here or by the tests of its feature, except those no call
+//! can reach: E42 and E43, whose checked operations are guarded by invariants, and
+//! E54, E98 and E99, which nothing raises anymore.
+
+mod common;
+
+use std::collections::HashSet;
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::test_utils::get_logs;
+use near_sdk::PromiseResult;
+use vault_contract::{AdminAction, Contract, RouteHop, RunningState, ShareRecipient};
+
+use common::*;
+
+const USER: &str = "user.testnet";
+const BOB: &str = "bob.testnet";
+const TOKEN: &str = "token.testnet";
+const WRAP: &str = "wrap.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+const PROPOSAL_PERIOD_SEC: u64 = 3_600;
+
+fn register(contract: &mut Contract, user: &str) {
+    call_with(user, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+}
+
+/// Vault whitelisting `TOKEN`, in which `USER` deposited 500 of it.
+fn setup_balance() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    register(&mut contract, USER);
+    call(TOKEN);
+    contract.ft_on_transfer(account(USER), U128(500), "".to_string());
+    contract
+}
+
+/// Vault governed by a council of `members`, of which `threshold` approve a proposal.
+fn setup_council(members: &[&str], threshold: u32) -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let members = members.iter().map(|member| account(member)).collect();
+    let change_id = contract.set_council(members, threshold, PROPOSAL_PERIOD_SEC);
+    apply_change(&mut contract, change_id);
+    contract
+}
+
+fn propose(contract: &mut Contract) -> u64 {
+    call_with(USER, NEAR, vec![]);
+    contract.propose(AdminAction::SetMinDeposit { min_deposit_lp: U128(1_000) })
+}
+
+/// Vault in epoch mode in which `USER` invested `amount` of NEAR.
+fn setup_epoch_deposit(amount: u128) -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_epoch_mode(true);
+    apply_change(&mut contract, change_id);
+    register(&mut contract, USER);
+    call_with(USER, amount, vec![]);
+    contract.deposit_near();
+    call(USER);
+    contract.add_to_vault();
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_epoch_wrap(USER.to_string(), U128(amount));
+    call_with(VAULT, 0, vec![ok(U128(amount))]);
+    contract.callback_queue_deposit(USER.to_string(), U128(amount));
+    contract
+}
+
+#[test]
+fn error_codes_are_unique_and_formatted() {
+    let contract = setup();
+    let errors = contract.get_error_codes();
+    let codes: HashSet<_> = errors.iter().map(|error| error.code.clone()).collect();
+    assert_eq!(codes.len(), errors.len());
+    for error in errors {
+        assert!(error.code.starts_with('E') && error.code[1..].parse::<u32>().is_ok(), "{}", error.code);
+        assert!(!error.message.is_empty());
+    }
+}
+
+#[test]
+fn error_codes_are_listed_in_order() {
+    let contract = setup();
+    let codes: Vec<u32> = contract.get_error_codes().iter().map(|error| error.code[1..].parse().unwrap()).collect();
+    assert!(codes.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", codes);
+}
+
+#[test]
+fn error_codes_list_the_messages() {
+    let contract = setup();
+    let errors = contract.get_error_codes();
+    let not_allowed = errors.iter().find(|error| error.code == "E60").unwrap();
+    assert_eq!(not_allowed.message, "not allowed");
+}
+
+#[test]
+#[should_panic(expected = "E10: account not registered")]
+fn withdraw_needs_registration() {
+    let mut contract = setup();
+    call_with(USER, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(1), None);
+}
+
+#[test]
+#[should_panic(expected = "E29: Illegal withdraw amount")]
+fn withdraw_of_zero_fails() {
+    let mut contract = setup();
+    call_with(USER, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(0), None);
+}
+
+#[test]
+#[should_panic(expected = "E36: not enough shares")]
+fn withdraw_all_needs_shares() {
+    let mut contract = setup();
+    deposit(&mut contract, USER, 1_000);
+    call(USER);
+    contract.withdraw_all(U128(1_001_000), None, None);
+}
+
+#[test]
+#[should_panic(expected = "E51: contract paused")]
+fn paused_contract_refuses_withdrawals() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.change_state(RunningState::Paused);
+    call(USER);
+    contract.withdraw_all(U128(1), None, None);
+}
+
+#[test]
+#[should_panic(expected = "E61: caller is not the pending owner")]
+fn only_pending_owner_accepts() {
+    let mut contract = setup();
+    call(USER);
+    contract.accept_owner();
+}
+
+#[test]
+#[should_panic(expected = "E62: no pending owner")]
+fn cancel_needs_pending_owner() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.cancel_owner_transfer();
+}
+
+#[test]
+#[should_panic(expected = "E63: caller is not a council member")]
+fn only_council_approves() {
+    let mut contract = setup();
+    call(USER);
+    contract.approve(0);
+}
+
+#[test]
+#[should_panic(expected = "E71: queued change not found")]
+fn execute_needs_queued_change() {
+    let mut contract = setup();
+    call(USER);
+    contract.execute_change(0);
+}
+
+#[test]
+#[should_panic(expected = "E72: timelock not expired")]
+fn execute_waits_for_timelock() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_min_deposit(U128(1_000));
+    advance(contract.get_timelock_delay() - 1);
+    call(OWNER);
+    contract.execute_change(change_id);
+}
+
+#[test]
+#[should_panic(expected = "E79: nothing in lostfound")]
+fn claim_needs_lostfound() {
+    let mut contract = setup();
+    call(USER);
+    contract.claim_lostfound();
+}
+
+#[test]
+#[should_panic(expected = "E15: deposit less than the minimum storage")]
+fn registration_needs_the_minimum_storage() {
+    let mut contract = setup();
+    call_with(USER, 1, vec![]);
+    contract.storage_deposit(None, None);
+}
+
+#[test]
+#[should_panic(expected = "E16: no storage can be withdrawn")]
+fn storage_withdraw_needs_free_storage() {
+    let mut contract = setup();
+    let min = contract.storage_balance_bounds().min.0;
+    call_with(USER, min, vec![]);
+    contract.storage_deposit(None, None);
+    call_with(USER, 1, vec![]);
+    contract.storage_withdraw(None);
+}
+
+#[test]
+#[should_panic(expected = "E17: storage withdraw exceeds the available storage")]
+fn storage_withdraw_is_bounded() {
+    let mut contract = setup();
+    register(&mut contract, USER);
+    call_with(USER, 1, vec![]);
+    contract.storage_withdraw(Some(U128(2 * NEAR)));
+}
+
+#[test]
+fn second_registration_is_logged() {
+    let mut contract = setup();
+    register(&mut contract, USER);
+    call(USER);
+    contract.storage_deposit(None, Some(true));
+    assert!(get_logs().iter().any(|log| log == "E20: account already registered"));
+}
+
+#[test]
+#[should_panic(expected = "E21: token not registered")]
+fn withdraw_needs_the_token() {
+    let mut contract = setup();
+    register(&mut contract, USER);
+    call_with(USER, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(1), None);
+}
+
+#[test]
+#[should_panic(expected = "E22: not enough tokens in deposit")]
+fn withdraw_needs_enough_tokens() {
+    let mut contract = setup_balance();
+    call_with(USER, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(501), None);
+}
+
+#[test]
+#[should_panic(expected = "E24: non-zero token balance")]
+fn token_with_a_balance_stays_registered() {
+    let mut contract = setup_balance();
+    call_with(USER, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(100), Some(true));
+}
+
+#[test]
+#[should_panic(expected = "E25: expected 1 promise result from withdraw")]
+fn withdraw_callback_needs_its_result() {
+    let mut contract = setup();
+    call(VAULT);
+    contract.exchange_callback_post_withdraw(TOKEN.to_string(), USER.to_string(), U128(1));
+}
+
+#[test]
+#[should_panic(expected = "E31: adding zero amount")]
+fn near_deposit_needs_near() {
+    let mut contract = setup();
+    register(&mut contract, USER);
+    call(USER);
+    contract.deposit_near();
+}
+
+#[test]
+#[should_panic(expected = "E34: no LP added")]
+fn zap_must_add_lp() {
+    let mut contract = setup();
+    deposit(&mut contract, USER, 0);
+}
+
+#[test]
+#[should_panic(expected = "E35: no shares")]
+fn compounding_needs_shares() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![ok(U128(500))]);
+    contract.callback_post_reward_deposit(U128(500));
+    call(USER);
+    contract.compound_rewards();
+}
+
+#[test]
+#[should_panic(expected = "E37: deposit above the limit of the token")]
+fn deposit_respects_the_token_limit() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_deposit_limit(account(WRAP), Some(U128(100)));
+    apply_change(&mut contract, change_id);
+    register(&mut contract, USER);
+    call_with(USER, 101, vec![]);
+    contract.deposit_near();
+    call(USER);
+    contract.add_to_vault();
+}
+
+#[test]
+#[should_panic(expected = "E38: deposit cap reached")]
+fn deposit_respects_the_cap() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_deposit_caps(Some(U128(1_000)), None);
+    apply_change(&mut contract, change_id);
+    register(&mut contract, USER);
+    deposit(&mut contract, USER, 1_000);
+    call_with(USER, 100, vec![]);
+    contract.deposit_near();
+    call(USER);
+    contract.add_to_vault();
+}
+
+#[test]
+#[should_panic(expected = "E39: deposit waiting for its epoch")]
+fn unregister_waits_for_the_epoch() {
+    let mut contract = setup_epoch_deposit(NEAR);
+    call_with(USER, 1, vec![]);
+    contract.storage_unregister(Some(true));
+}
+
+#[test]
+#[should_panic(expected = "E40: arithmetic overflow")]
+fn token_balance_does_not_overflow() {
+    let mut contract = setup_balance();
+    call(TOKEN);
+    contract.ft_on_transfer(account(USER), U128(u128::MAX), "".to_string());
+}
+
+#[test]
+#[should_panic(expected = "E40: arithmetic overflow")]
+fn duration_in_nanoseconds_does_not_overflow() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_epoch_duration(u64::MAX);
+    apply_change(&mut contract, change_id);
+}
+
+#[test]
+#[should_panic(expected = "E40: arithmetic overflow")]
+fn boost_duration_does_not_overflow() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.raise_withdraw_limit(U128(1_000), u64::MAX);
+}
+
+#[test]
+#[should_panic(expected = "E52: emergency exit in progress")]
+fn emergency_exit_stops_harvests() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.emergency_exit();
+    call(USER);
+    contract.harvest();
+}
+
+#[test]
+#[should_panic(expected = "E53: no emergency exit in progress")]
+fn emergency_claim_needs_an_exit() {
+    let mut contract = setup();
+    call(USER);
+    contract.claim_emergency();
+}
+
+#[test]
+#[should_panic(expected = "E56: withdraw queue empty")]
+fn queue_processing_needs_a_queued_withdrawal() {
+    let mut contract = setup();
+    call(USER);
+    contract.process_withdraw_queue();
+}
+
+#[test]
+#[should_panic(expected = "E57: withdraw limit boost too long")]
+fn withdraw_limit_boost_is_bounded() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.raise_withdraw_limit(U128(1_000), 8 * 24 * 3_600);
+}
+
+#[test]
+#[should_panic(expected = "E64: proposal not found")]
+fn approval_needs_a_proposal() {
+    let mut contract = setup_council(&[USER], 1);
+    call(USER);
+    contract.approve(0);
+}
+
+#[test]
+#[should_panic(expected = "E65: proposal expired")]
+fn expired_proposal_is_not_executed() {
+    let mut contract = setup_council(&[USER], 1);
+    let id = propose(&mut contract);
+    advance(PROPOSAL_PERIOD_SEC);
+    call(USER);
+    contract.execute(id);
+}
+
+#[test]
+#[should_panic(expected = "E66: proposal not expired")]
+fn live_proposal_is_not_removed() {
+    let mut contract = setup_council(&[USER], 1);
+    let id = propose(&mut contract);
+    call(BOB);
+    contract.remove_proposal(id);
+}
+
+#[test]
+#[should_panic(expected = "E67: proposal already approved by caller")]
+fn proposal_is_approved_once_per_member() {
+    let mut contract = setup_council(&[USER, BOB], 2);
+    let id = propose(&mut contract);
+    call(USER);
+    contract.approve(id);
+}
+
+#[test]
+#[should_panic(expected = "E68: not enough approvals")]
+fn proposal_needs_the_threshold() {
+    let mut contract = setup_council(&[USER, BOB], 2);
+    let id = propose(&mut contract);
+    call(USER);
+    contract.execute(id);
+}
+
+#[test]
+#[should_panic(expected = "E69: threshold above the council size")]
+fn threshold_fits_the_council() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_council(vec![account(USER)], 2, PROPOSAL_PERIOD_SEC);
+    apply_change(&mut contract, change_id);
+}
+
+#[test]
+#[should_panic(expected = "E70: threshold of a council must not be 0")]
+fn council_threshold_is_not_zero() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_council(vec![account(USER)], 0, PROPOSAL_PERIOD_SEC);
+    apply_change(&mut contract, change_id);
+}
+
+#[test]
+#[should_panic(expected = "E73: harvest bounty too high")]
+fn harvest_bounty_is_bounded() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.set_harvest_config(1_001, 0, U128(0));
+}
+
+#[test]
+#[should_panic(expected = "E74: empty route")]
+fn route_needs_hops() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_route(account(WRAP), account(DAI), vec![]);
+    apply_change(&mut contract, change_id);
+}
+
+#[test]
+#[should_panic(expected = "E75: route doesn't end with its output token")]
+fn route_ends_with_its_output_token() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_route(account(WRAP), account(DAI), vec![RouteHop { pool_id: 83, token_out: ETH.to_string() }]);
+    apply_change(&mut contract, change_id);
+}
+
+#[test]
+#[should_panic(expected = "E77: accounts still hold the token")]
+fn deposited_token_stays_whitelisted() {
+    let mut contract = setup_balance();
+    call(OWNER);
+    let change_id = contract.remove_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+}
+
+#[test]
+#[should_panic(expected = "E78: lostfound still holds the token")]
+fn token_in_lostfound_stays_whitelisted() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_post_dust_withdraw(USER.to_string(), TOKEN.to_string(), U128(500));
+    call(OWNER);
+    let change_id = contract.remove_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+}
+
+#[test]
+#[should_panic(expected = "E82: epoch not finished")]
+fn epoch_is_settled_once_finished() {
+    let mut contract = setup_epoch_deposit(NEAR);
+    call(OWNER);
+    let change_id = contract.set_epoch_duration(30 * 24 * 3_600);
+    apply_change(&mut contract, change_id);
+    call(USER);
+    contract.settle_epoch();
+}
+
+#[test]
+#[should_panic(expected = "E83: epoch not settling")]
+fn only_the_settling_epoch_is_minted() {
+    let mut contract = setup();
+    mint(&mut contract, ShareRecipient::Epoch(5), 1_000);
+}
+
+#[test]
+#[should_panic(expected = "E88: no reward")]
+fn compounding_needs_rewards() {
+    let mut contract = setup();
+    call(USER);
+    contract.compound_rewards();
+}
+
+#[test]
+#[should_panic(expected = "E95: unexpected number of promise results")]
+fn sweep_callback_needs_the_balance() {
+    let mut contract = setup();
+    call(VAULT);
+    contract.callback_sweep(TOKEN.to_string());
+}
+
+#[test]
+#[should_panic(expected = "E96: wrong value received")]
+fn pool_shares_must_parse() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Successful(b"shares".to_vec())]);
+    contract.callback_get_pool_shares(ShareRecipient::Compound, vec![U128(0), U128(0)]);
+}
+
+#[test]
+#[should_panic(expected = "E97: call failed")]
+fn pool_shares_read_must_succeed() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_unwind_restake(U128(1_000));
+}
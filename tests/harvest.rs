@@ -0,0 +1,119 @@
+//! Harvest interval and bounty.
+
+mod common;
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::PromiseResult;
+use vault_contract::Contract;
+
+use common::*;
+
+const HARVESTER: &str = "harvester.testnet";
+const REWARD: &str = "ref.fakes.testnet";
+const INTERVAL_SEC: u64 = 3_600;
+
+/// Vault paying a bounty of `bounty_bps` to harvests at least an hour apart of at least
+/// 100 reward tokens.
+fn setup_harvest(bounty_bps: u32) -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_harvest_config(bounty_bps, INTERVAL_SEC, U128(100));
+    apply_change(&mut contract, change_id);
+    contract
+}
+
+/// Completes the withdraw of `amount` harvested reward tokens from the farm.
+fn withdraw_reward(contract: &mut Contract, amount: u128) {
+    call_with(VAULT, 0, vec![ok(U128(amount))]);
+    contract.callback_post_withdraw_reward(HARVESTER.to_string(), U128(amount));
+}
+
+#[test]
+fn harvester_is_paid_the_bounty() {
+    let mut contract = setup_harvest(500);
+    withdraw_reward(&mut contract, 1_000);
+    let bounty = call_args("ft_transfer");
+    assert_eq!(bounty["receiver_id"], json!(HARVESTER));
+    assert_eq!(bounty["amount"], json!("50"));
+    assert_eq!(call_args("ft_transfer_call")["amount"], json!("950"));
+}
+
+#[test]
+fn no_bounty_deposits_the_whole_reward() {
+    let mut contract = setup_harvest(0);
+    withdraw_reward(&mut contract, 1_000);
+    assert!(function_calls().iter().all(|(method, _)| method != "ft_transfer"));
+    assert_eq!(call_args("ft_transfer_call")["amount"], json!("1000"));
+}
+
+#[test]
+fn failed_reward_withdraw_pays_nothing() {
+    let mut contract = setup_harvest(500);
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_post_withdraw_reward(HARVESTER.to_string(), U128(1_000));
+    assert!(function_calls().is_empty());
+}
+
+#[test]
+fn harvest_waits_for_the_interval() {
+    let mut contract = setup_harvest(500);
+    call(HARVESTER);
+    contract.harvest();
+    assert_eq!(contract.get_last_harvest(), 0);
+    withdraw_reward(&mut contract, 1_000);
+    assert_eq!(contract.get_last_harvest(), now());
+    advance(INTERVAL_SEC);
+    call(HARVESTER);
+    contract.harvest();
+}
+
+#[test]
+#[should_panic(expected = "E86: harvest too soon")]
+fn harvest_after_a_withdrawn_reward_is_refused() {
+    let mut contract = setup_harvest(500);
+    withdraw_reward(&mut contract, 1_000);
+    call(HARVESTER);
+    contract.harvest();
+}
+
+#[test]
+fn failed_harvest_is_retried_right_away() {
+    let mut contract = setup_harvest(500);
+    call(HARVESTER);
+    contract.harvest();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_post_withdraw_reward(HARVESTER.to_string(), U128(1_000));
+    assert_eq!(contract.get_last_harvest(), 0);
+    call(HARVESTER);
+    contract.harvest();
+}
+
+#[test]
+fn failed_reward_deposit_is_kept_for_the_next_harvest() {
+    let mut contract = setup_harvest(0);
+    withdraw_reward(&mut contract, 1_000);
+    assert_eq!(contract.get_in_flight(account(REWARD)), U128(1_000));
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_post_reward_deposit(U128(1_000));
+    assert_eq!(contract.get_in_flight(account(REWARD)), U128(0));
+    assert_eq!(contract.get_pending_reward(), U128(0));
+    assert_eq!(contract.get_idle_reward(), U128(1_000));
+
+    advance(INTERVAL_SEC);
+    withdraw_reward(&mut contract, 500);
+    assert_eq!(call_args("ft_transfer_call")["amount"], json!("1500"));
+    assert_eq!(contract.get_idle_reward(), U128(0));
+    call_with(VAULT, 0, vec![ok(U128(1_200))]);
+    contract.callback_post_reward_deposit(U128(1_500));
+    assert_eq!(contract.get_pending_reward(), U128(1_200));
+    assert_eq!(contract.get_idle_reward(), U128(300));
+}
+
+#[test]
+#[should_panic(expected = "E87: harvest too small")]
+fn small_reward_is_not_harvested() {
+    let mut contract = setup_harvest(500);
+    call_with(VAULT, 0, vec![ok(U128(99))]);
+    contract.callback_get_reward(HARVESTER.to_string());
+}
@@ -0,0 +1,65 @@
+//! Per-account ledger and its pruning.
+
+mod common;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use vault_contract::Contract;
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Vault in which `ALICE` made three deposits of 1000 LP.
+fn setup_history() -> Contract {
+    let mut contract = setup();
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    for _ in 0..3 {
+        deposit(&mut contract, ALICE, 1_000);
+        advance(1);
+    }
+    contract
+}
+
+fn available(contract: &Contract) -> u128 {
+    contract.storage_balance_of(account(ALICE)).unwrap().available.0
+}
+
+#[test]
+fn deposits_are_recorded_in_order() {
+    let contract = setup_history();
+    let history = contract.get_user_history(account(ALICE), 0, 10);
+    assert_eq!(history.iter().map(|entry| entry.id).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert!(history.iter().all(|entry| entry.lp == U128(1_000)));
+    assert!(history.windows(2).all(|pair| pair[0].timestamp.0 < pair[1].timestamp.0));
+    assert_eq!(contract.get_user_history(account(ALICE), 1, 1)[0].id, 1);
+}
+
+#[test]
+fn pruning_frees_the_storage_of_the_oldest_entries() {
+    let mut contract = setup_history();
+    let before = available(&contract);
+    call_with(ALICE, 1, vec![]);
+    assert_eq!(contract.prune_history(2), 2);
+    let history = contract.get_user_history(account(ALICE), 0, 10);
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].id, 2);
+    let freed = available(&contract) - before;
+    assert!(freed > 0);
+
+    call_with(ALICE, 1, vec![]);
+    contract.storage_withdraw(Some(U128(freed)));
+    assert_eq!(available(&contract), before);
+}
+
+#[test]
+fn pruning_stops_at_the_latest_entry() {
+    let mut contract = setup_history();
+    call_with(ALICE, 1, vec![]);
+    assert_eq!(contract.prune_history(10), 3);
+    assert!(contract.get_user_history(account(ALICE), 0, 10).is_empty());
+    call_with(ALICE, 1, vec![]);
+    assert_eq!(contract.prune_history(1), 0);
+}
@@ -0,0 +1,70 @@
+//! Release of compounded profit over the unlock period.
+
+mod common;
+
+use near_sdk::json_types::U128;
+use vault_contract::{Contract, ShareRecipient};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const BOB: &str = "bob.testnet";
+const UNLOCK_PERIOD_SEC: u64 = 1_000;
+
+/// Vault releasing profit over `UNLOCK_PERIOD_SEC`, in which `ALICE` deposited 10000 LP
+/// and which then compounded 1000 LP.
+fn setup_compounded() -> (Contract, u128) {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_profit_unlock_period(UNLOCK_PERIOD_SEC);
+    apply_change(&mut contract, change_id);
+    let shares = deposit(&mut contract, ALICE, 10_000);
+    mint(&mut contract, ShareRecipient::Compound, 1_000);
+    (contract, shares)
+}
+
+#[test]
+fn compounded_profit_is_released_linearly() {
+    let (contract, shares) = setup_compounded();
+    let info = contract.get_vault_info();
+    assert_eq!(info.total_lp, U128(11_000));
+    assert_eq!(info.locked_profit, U128(1_000));
+    assert_eq!(info.free_lp, U128(10_000));
+    assert_eq!(info.profit_unlock_period, UNLOCK_PERIOD_SEC);
+    let redeemed = contract.preview_redeem(U128(shares)).0;
+
+    advance(UNLOCK_PERIOD_SEC / 4);
+    call(ALICE);
+    let info = contract.get_vault_info();
+    assert_eq!(info.locked_profit, U128(750));
+    assert_eq!(info.free_lp, U128(10_250));
+    let gain = contract.preview_redeem(U128(shares)).0 - redeemed;
+    assert!((249..=250).contains(&gain), "gained {} LP", gain);
+
+    advance(UNLOCK_PERIOD_SEC);
+    call(ALICE);
+    let info = contract.get_vault_info();
+    assert_eq!(info.locked_profit, U128(0));
+    assert_eq!(info.free_lp, U128(11_000));
+    let gain = contract.preview_redeem(U128(shares)).0 - redeemed;
+    assert!((999..=1_000).contains(&gain), "gained {} LP", gain);
+}
+
+#[test]
+fn compounding_relocks_the_profit_not_yet_released() {
+    let (mut contract, _) = setup_compounded();
+    advance(UNLOCK_PERIOD_SEC / 2);
+    mint(&mut contract, ShareRecipient::Compound, 1_000);
+    assert_eq!(contract.get_vault_info().locked_profit, U128(1_500));
+    advance(UNLOCK_PERIOD_SEC / 2);
+    call(ALICE);
+    assert_eq!(contract.get_vault_info().locked_profit, U128(750));
+}
+
+#[test]
+fn deposit_after_compounding_gets_no_locked_profit() {
+    let (mut contract, _) = setup_compounded();
+    let shares = deposit(&mut contract, BOB, 1_000);
+    let redeemed = contract.preview_redeem(U128(shares)).0;
+    assert!((999..=1_000).contains(&redeemed), "redeems {} LP", redeemed);
+}
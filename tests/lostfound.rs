@@ -0,0 +1,121 @@
+//! Lostfound claims and sweeping of untracked tokens.
+
+mod common;
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::PromiseResult;
+use vault_contract::Contract;
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const TOKEN: &str = "token.testnet";
+const REWARD: &str = "ref.fakes.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Vault whitelisting `TOKEN`, in which `ALICE` deposited 500 of it.
+fn setup_balance() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call(TOKEN);
+    contract.ft_on_transfer(account(ALICE), U128(500), "".to_string());
+    contract
+}
+
+/// Keeps 500 `TOKEN` in lostfound for the unregistered `ALICE`.
+fn setup_lostfound() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_post_dust_withdraw(ALICE.to_string(), TOKEN.to_string(), U128(500));
+    assert_eq!(contract.get_account_lostfound(account(ALICE)).get(TOKEN), Some(&U128(500)));
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    contract
+}
+
+/// Sweeps `token_id` with a wallet balance of `balance` and returns the amount swept.
+fn sweep(contract: &mut Contract, token_id: &str, balance: u128) -> Option<String> {
+    call_with(VAULT, 0, vec![ok(U128(balance))]);
+    // the promise is scheduled once dropped
+    contract.callback_sweep(token_id.to_string())?;
+    Some(call_args("ft_transfer")["amount"].as_str().unwrap().to_string())
+}
+
+#[test]
+fn lostfound_is_claimed_into_the_balance() {
+    let mut contract = setup_lostfound();
+    let storage_cost = contract.get_lostfound_storage_cost(account(ALICE)).0;
+    assert!(storage_cost > 0);
+
+    call_with(ALICE, storage_cost, vec![]);
+    let claimed = contract.claim_lostfound();
+    assert_eq!(claimed.get(TOKEN), Some(&U128(500)));
+    assert_eq!(contract.get_deposits(account(ALICE)).get(TOKEN), Some(&U128(500)));
+    assert!(contract.get_account_lostfound(account(ALICE)).is_empty());
+    assert!(contract.get_lostfound().get(TOKEN).is_none_or(|amount| amount.0 == 0));
+}
+
+#[test]
+#[should_panic(expected = "E11: insufficient $NEAR storage deposit")]
+fn claim_pays_back_the_storage_of_the_entry() {
+    let mut contract = setup_lostfound();
+    let storage_cost = contract.get_lostfound_storage_cost(account(ALICE)).0;
+    call_with(ALICE, storage_cost - 1, vec![]);
+    contract.claim_lostfound();
+}
+
+#[test]
+fn sweep_takes_only_untracked_tokens() {
+    let mut contract = setup_balance();
+    assert_eq!(sweep(&mut contract, TOKEN, 800), Some("300".to_string()));
+    assert_eq!(call_args("ft_transfer")["receiver_id"], json!(OWNER));
+    assert_eq!(sweep(&mut contract, TOKEN, 500), None);
+}
+
+#[test]
+fn lostfound_is_not_swept() {
+    let mut contract = setup_lostfound();
+    assert_eq!(sweep(&mut contract, TOKEN, 500), None);
+}
+
+#[test]
+fn withdrawal_in_flight_is_not_swept() {
+    let mut contract = setup_balance();
+    call_with(ALICE, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(500), None);
+    assert_eq!(contract.get_in_flight(account(TOKEN)), U128(500));
+    assert_eq!(sweep(&mut contract, TOKEN, 500), None);
+
+    // the failed transfer came back to the wallet and to the balance
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.exchange_callback_post_withdraw(TOKEN.to_string(), ALICE.to_string(), U128(500));
+    assert_eq!(contract.get_in_flight(account(TOKEN)), U128(0));
+    assert_eq!(contract.get_deposits(account(ALICE)).get(TOKEN), Some(&U128(500)));
+    assert_eq!(sweep(&mut contract, TOKEN, 500), None);
+}
+
+#[test]
+fn harvested_rewards_are_not_swept() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![ok(U128(1_000))]);
+    contract.callback_get_reward(ALICE.to_string());
+    assert_eq!(sweep(&mut contract, REWARD, 1_000), None);
+
+    call_with(VAULT, 0, vec![ok(U128(1_000))]);
+    contract.callback_post_withdraw_reward(ALICE.to_string(), U128(1_000));
+    assert_eq!(sweep(&mut contract, REWARD, 1_000), None);
+
+    call_with(VAULT, 0, vec![ok(U128(1_000))]);
+    contract.callback_post_reward_deposit(U128(1_000));
+    assert_eq!(contract.get_in_flight(account(REWARD)), U128(0));
+}
@@ -0,0 +1,72 @@
+//! Investable NEAR balance, kept apart from the storage deposit.
+
+mod common;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+
+use common::*;
+
+const USER: &str = "user.testnet";
+const ATTACKER: &str = "attacker.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Registers `USER` and deposits `amount` of investable NEAR.
+fn setup_with_near(amount: u128) -> vault_contract::Contract {
+    let mut contract = setup();
+    call_with(USER, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call_with(USER, amount, vec![]);
+    contract.deposit_near();
+    contract
+}
+
+#[test]
+fn wrapping_spends_the_callers_deposit() {
+    let mut contract = setup_with_near(5 * NEAR);
+    call_with(USER, 1, vec![]);
+    contract.near_to_wrap(account(ATTACKER), U128(2 * NEAR), "".to_string());
+    assert_eq!(contract.get_near_deposit(account(USER)), U128(3 * NEAR));
+}
+
+#[test]
+#[should_panic(expected = "E10: account not registered")]
+fn wrapping_cant_spend_another_deposit() {
+    let mut contract = setup_with_near(5 * NEAR);
+    call_with(ATTACKER, 1, vec![]);
+    contract.near_to_wrap(account(ATTACKER), U128(2 * NEAR), "".to_string());
+}
+
+#[test]
+#[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+fn wrapping_needs_one_yocto() {
+    let mut contract = setup_with_near(5 * NEAR);
+    call(USER);
+    contract.near_to_wrap(account(USER), U128(NEAR), "".to_string());
+}
+
+#[test]
+fn investing_spends_the_callers_deposit() {
+    let mut contract = setup_with_near(5 * NEAR);
+    call(USER);
+    contract.add_to_vault();
+    assert_eq!(contract.get_near_deposit(account(USER)), U128(0));
+}
+
+#[test]
+fn attached_near_is_invested_with_the_deposit() {
+    let mut contract = setup_with_near(5 * NEAR);
+    call_with(USER, NEAR, vec![]);
+    contract.add_to_vault();
+    assert_eq!(contract.get_near_deposit(account(USER)), U128(0));
+    assert_eq!(call_args("ft_transfer_call")["amount"], json!((6 * NEAR).to_string()));
+}
+
+#[test]
+#[should_panic(expected = "E23: not enough NEAR in deposit")]
+fn investing_needs_a_deposit_of_the_caller() {
+    let mut contract = setup_with_near(5 * NEAR);
+    call(ATTACKER);
+    contract.add_to_vault();
+}
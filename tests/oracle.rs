@@ -0,0 +1,140 @@
+//! Oracle circuit breaker on zaps, against a mocked oracle.
+
+mod common;
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::test_utils::get_logs;
+use near_sdk::PromiseResult;
+use vault_contract::ShareRecipient;
+
+use common::*;
+
+const ORACLE: &str = "oracle.testnet";
+const WRAP: &str = "wrap.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+/// Pools read by a wNEAR zap: wNEAR/DAI, wNEAR/ETH and the strategy pool.
+const POOL_IDS: [u64; 3] = [84, 83, 193];
+const NOW: u64 = 1_000_000 * 1_000_000_000;
+
+/// Mock of the price oracle: wNEAR at $2, DAI at $1 and ETH at $2000.
+fn oracle_prices(timestamp: u64) -> PromiseResult {
+    ok(json!({
+        "timestamp": timestamp.to_string(),
+        "recency_duration_sec": 90,
+        "prices": [
+            { "asset_id": WRAP, "price": { "multiplier": "20000", "decimals": 28 } },
+            { "asset_id": DAI, "price": { "multiplier": "10000", "decimals": 22 } },
+            { "asset_id": ETH, "price": { "multiplier": "20000000", "decimals": 22 } },
+        ],
+    }))
+}
+
+fn pool(tokens: [&str; 2], amounts: [u128; 2]) -> PromiseResult {
+    ok(json!({
+        "token_account_ids": tokens,
+        "amounts": [amounts[0].to_string(), amounts[1].to_string()],
+        "total_fee": 30,
+        "shares_total_supply": "1000000000000000000000000",
+    }))
+}
+
+/// Pools at the oracle prices, with the wNEAR/DAI reserve of DAI scaled by `dai_bps`.
+fn pools(dai_bps: u128) -> Vec<PromiseResult> {
+    let e18 = 10u128.pow(18);
+    let e24 = 10u128.pow(24);
+    vec![
+        pool([WRAP, DAI], [1_000 * e24, 2_000 * e18 * dai_bps / 10_000]),
+        pool([WRAP, ETH], [1_000 * e24, e18]),
+        pool([DAI, ETH], [2_000 * e18, e18]),
+    ]
+}
+
+fn setup_with_oracle() -> vault_contract::Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_oracle(Some(account(ORACLE)), 100, 60);
+    apply_change(&mut contract, change_id);
+    contract
+}
+
+fn zap(contract: &mut vault_contract::Contract, recipient: ShareRecipient, results: Vec<PromiseResult>) {
+    call_in(context(VAULT).block_timestamp(NOW), results);
+    contract.callback_zap_quote(WRAP.to_string(), U128(10u128.pow(24)), recipient, POOL_IDS.to_vec());
+}
+
+fn depositor() -> ShareRecipient {
+    ShareRecipient::Account { account_id: "alice.testnet".to_string(), min_shares: U128(0) }
+}
+
+fn aborted(reason: &str) -> bool {
+    get_logs().iter().any(|log| log.contains("aborted") && log.contains(reason))
+}
+
+#[test]
+fn zap_goes_on_when_pools_match_oracle() {
+    let mut contract = setup_with_oracle();
+    let mut results = pools(10_050);
+    results.push(oracle_prices(NOW));
+    zap(&mut contract, depositor(), results);
+    assert!(get_logs().iter().any(|log| log.starts_with("Zapping")));
+}
+
+#[test]
+fn zap_aborted_on_price_deviation() {
+    let mut contract = setup_with_oracle();
+    let mut results = pools(10_500);
+    results.push(oracle_prices(NOW));
+    zap(&mut contract, depositor(), results);
+    assert!(aborted("E93: pool price deviates from the oracle"));
+    assert!(!get_logs().iter().any(|log| log.starts_with("Zapping")));
+}
+
+#[test]
+fn zap_aborted_on_stale_oracle() {
+    let mut contract = setup_with_oracle();
+    let mut results = pools(10_000);
+    results.push(oracle_prices(NOW - 61 * 1_000_000_000));
+    zap(&mut contract, depositor(), results);
+    assert!(aborted("E91: oracle data stale"));
+}
+
+#[test]
+fn zap_aborted_when_oracle_fails() {
+    let mut contract = setup_with_oracle();
+    let mut results = pools(10_000);
+    results.push(PromiseResult::Failed);
+    zap(&mut contract, depositor(), results);
+    assert!(aborted("E90: oracle unavailable"));
+}
+
+#[test]
+fn zap_aborted_without_an_oracle_price() {
+    let mut contract = setup_with_oracle();
+    let mut results = pools(10_000);
+    results.push(ok(json!({
+        "timestamp": NOW.to_string(),
+        "recency_duration_sec": 90,
+        "prices": [{ "asset_id": WRAP, "price": { "multiplier": "20000", "decimals": 28 } }],
+    })));
+    zap(&mut contract, depositor(), results);
+    assert!(aborted("E92: no oracle price"));
+}
+
+#[test]
+fn aborted_compounding_keeps_reward_pending() {
+    let mut contract = setup_with_oracle();
+    let mut results = pools(5_000);
+    results.push(oracle_prices(NOW));
+    zap(&mut contract, ShareRecipient::Compound, results);
+    assert!(aborted("E93: pool price deviates from the oracle"));
+    assert_eq!(contract.get_pending_reward(), U128(10u128.pow(24)));
+}
+
+#[test]
+fn no_check_without_oracle() {
+    let mut contract = setup();
+    zap(&mut contract, depositor(), pools(5_000));
+    assert!(get_logs().iter().any(|log| log.starts_with("Zapping")));
+}
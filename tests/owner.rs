@@ -0,0 +1,87 @@
+//! Two-step ownership transfer.
+
+mod common;
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use vault_contract::Contract;
+
+use common::*;
+
+const SAFE: &str = "safe.testnet";
+const ALICE: &str = "alice.testnet";
+const TOKEN: &str = "token.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Moves the ownership of the vault to `SAFE`.
+fn transfer(contract: &mut Contract) {
+    call(OWNER);
+    contract.propose_owner(account(SAFE));
+    assert_eq!(contract.get_pending_owner(), Some(SAFE.to_string()));
+    assert_eq!(contract.get_owner(), OWNER.to_string());
+    call(SAFE);
+    contract.accept_owner();
+}
+
+fn setup_transferred() -> Contract {
+    let mut contract = setup();
+    transfer(&mut contract);
+    contract
+}
+
+#[test]
+fn ownership_moves_once_accepted() {
+    let contract = setup_transferred();
+    assert_eq!(contract.get_owner(), SAFE.to_string());
+    assert_eq!(contract.get_pending_owner(), None);
+}
+
+#[test]
+#[should_panic(expected = "E60: not allowed")]
+fn previous_owner_loses_its_rights() {
+    let mut contract = setup_transferred();
+    call(OWNER);
+    contract.set_epoch_mode(true);
+}
+
+#[test]
+fn swept_tokens_go_to_the_new_owner() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call(TOKEN);
+    contract.ft_on_transfer(account(ALICE), U128(500), "".to_string());
+    transfer(&mut contract);
+
+    call_with(VAULT, 0, vec![ok(U128(800))]);
+    contract.callback_sweep(TOKEN.to_string());
+    let sweep = call_args("ft_transfer");
+    assert_eq!(sweep["receiver_id"], json!(SAFE));
+    assert_eq!(sweep["amount"], json!("300"));
+}
+
+#[test]
+fn cancelled_transfer_cannot_be_accepted() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.propose_owner(account(SAFE));
+    call(OWNER);
+    contract.cancel_owner_transfer();
+    assert_eq!(contract.get_pending_owner(), None);
+    assert_eq!(contract.get_owner(), OWNER.to_string());
+}
+
+#[test]
+#[should_panic(expected = "E61: caller is not the pending owner")]
+fn only_the_pending_owner_accepts() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.propose_owner(account(SAFE));
+    call(ALICE);
+    contract.accept_owner();
+}
@@ -0,0 +1,94 @@
+//! Cost basis and realized gains.
+
+mod common;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{self, json};
+use near_sdk::PromiseResult;
+use vault_contract::{Contract, ShareRecipient, Unwind};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const WRAP: &str = "wrap.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+fn pool(tokens: [&str; 2], amounts: [u128; 2], total_supply: u128) -> PromiseResult {
+    ok(json!({
+        "token_account_ids": tokens,
+        "amounts": [amounts[0].to_string(), amounts[1].to_string()],
+        "total_fee": 0,
+        "shares_total_supply": total_supply.to_string(),
+    }))
+}
+
+/// Quotes a zap with a strategy pool of 10000 LP holding 1000000 DAI and 2000000 ETH,
+/// where 1 DAI is worth `dai_price` wNEAR and 1 ETH 2 wNEAR.
+fn set_price(contract: &mut Contract, dai_price: u128) {
+    let deep = 10u128.pow(30);
+    call_with(VAULT, 0, vec![
+        pool([WRAP, DAI], [dai_price * deep, deep], deep),
+        pool([WRAP, ETH], [2 * deep, deep], deep),
+        pool([DAI, ETH], [1_000_000, 2_000_000], 10_000),
+    ]);
+    contract.callback_zap_quote(WRAP.to_string(), U128(1_000), ShareRecipient::Compound, vec![84, 83, 193]);
+}
+
+/// Vault in which `ALICE` deposited 1000 LP worth 500 yoctoNEAR each.
+fn setup_deposit() -> (Contract, u128) {
+    let mut contract = setup();
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    set_price(&mut contract, 1);
+    assert_eq!(contract.get_lp_price_near(), U128(500 * NEAR));
+    let shares = deposit(&mut contract, ALICE, 1_000);
+    (contract, shares)
+}
+
+#[test]
+fn deposit_adds_its_value_to_the_cost_basis() {
+    let (contract, shares) = setup_deposit();
+    let pnl = contract.get_user_pnl(account(ALICE));
+    assert_eq!(pnl.deposited, U128(500_000));
+    assert_eq!(pnl.cost_basis, U128(500_000));
+    assert_eq!(pnl.average_cost, U128(500_000 * NEAR / shares));
+    assert_eq!(pnl.unrealized.0, 0);
+}
+
+#[test]
+fn withdrawal_realizes_the_gain_of_its_shares() {
+    let (mut contract, shares) = setup_deposit();
+    // DAI doubled in wNEAR, one LP is now worth 600
+    set_price(&mut contract, 2);
+    assert_eq!(contract.get_user_pnl(account(ALICE)).unrealized.0, 100_000);
+
+    call(ALICE);
+    contract.withdraw_all(U128(shares / 2), None, None);
+    let pnl = contract.get_user_pnl(account(ALICE));
+    assert_eq!(pnl.withdrawn, U128(300_000));
+    assert_eq!(pnl.cost_basis, U128(250_000));
+    assert_eq!(pnl.realized.0, 50_000);
+    assert_eq!(pnl.unrealized.0, 50_000);
+}
+
+#[test]
+fn refunded_withdrawal_reverses_its_burn() {
+    let (mut contract, shares) = setup_deposit();
+    set_price(&mut contract, 2);
+    call(ALICE);
+    contract.withdraw_all(U128(shares / 2), None, None);
+    let args = call_args("callback_unwind_check");
+    let unwind: Unwind = serde_json::from_value(args["unwind"].clone()).unwrap();
+    assert_eq!(unwind.burn.value, U128(300_000));
+
+    call_with(VAULT, 0, vec![PromiseResult::Failed, PromiseResult::Failed, PromiseResult::Failed]);
+    contract.callback_unwind_check(U128(args["lp"].as_str().unwrap().parse().unwrap()), unwind, vec![193, 84, 83]);
+    let pnl = contract.get_user_pnl(account(ALICE));
+    assert_eq!(pnl.deposited, U128(500_000));
+    assert_eq!(pnl.withdrawn, U128(0));
+    assert_eq!(pnl.cost_basis, U128(500_000));
+    assert_eq!(pnl.realized.0, 0);
+}
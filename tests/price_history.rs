@@ -0,0 +1,57 @@
+//! Share price snapshots and APY.
+
+mod common;
+
+use near_sdk::json_types::{U128, U64};
+use vault_contract::{Contract, ShareRecipient};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const PRICE_HISTORY_SIZE: u64 = 720;
+const YEAR_SEC: u64 = 365 * 24 * 3_600;
+const LP: u128 = 1_000_000_000_000;
+
+/// Records a snapshot through the deposit of harvested rewards.
+fn record_price(contract: &mut Contract) {
+    call_with(VAULT, 0, vec![ok(U128(0))]);
+    contract.callback_post_reward_deposit(U128(0));
+}
+
+#[test]
+fn history_keeps_the_latest_snapshots() {
+    let mut contract = setup();
+    let start = now();
+    for _ in 0..PRICE_HISTORY_SIZE + 5 {
+        record_price(&mut contract);
+        advance(1);
+    }
+    let history = contract.get_price_history(0, 2 * PRICE_HISTORY_SIZE);
+    assert_eq!(history.len() as u64, PRICE_HISTORY_SIZE);
+    // the five oldest snapshots were overwritten
+    assert_eq!(history[0].timestamp, U64(start + 5 * 1_000_000_000));
+    assert_eq!(history.last().unwrap().timestamp, U64(start + (PRICE_HISTORY_SIZE + 4) * 1_000_000_000));
+    assert!(history.windows(2).all(|pair| pair[0].timestamp.0 < pair[1].timestamp.0));
+
+    let page = contract.get_price_history(PRICE_HISTORY_SIZE - 2, 10);
+    assert_eq!(page.len(), 2);
+    assert_eq!(page[1].timestamp, history.last().unwrap().timestamp);
+    assert_eq!(contract.get_price_history(PRICE_HISTORY_SIZE - 2, u64::MAX).len(), 2);
+}
+
+#[test]
+fn apy_annualizes_the_share_price_growth() {
+    let mut contract = setup();
+    deposit(&mut contract, ALICE, 10 * LP);
+    record_price(&mut contract);
+    assert_eq!(contract.get_apy(0), None);
+
+    // 5% in half a year
+    advance(YEAR_SEC / 2);
+    mint(&mut contract, ShareRecipient::Compound, LP / 2);
+    // growth is rounded down to 499 bps by the virtual LP
+    let apy = contract.get_apy(YEAR_SEC / 2).unwrap();
+    assert!((998..=1_000).contains(&apy), "APY of {} bps", apy);
+    // no snapshot is old enough for a longer window
+    assert_eq!(contract.get_apy(YEAR_SEC), None);
+}
@@ -0,0 +1,219 @@
+//! Invariants of account balances and share conversions under random operations.
+
+mod common;
+
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use proptest::prelude::*;
+use vault_contract::{Contract, ShareRecipient};
+
+use common::*;
+
+const USERS: [&str; 3] = ["alice.testnet", "bob.testnet", "carol.testnet"];
+const TOKEN: &str = "token.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Runs `f` and returns the message it panicked with, if any.
+fn panic_message(f: impl FnOnce()) -> Option<String> {
+    catch_unwind(AssertUnwindSafe(f)).err().map(|payload| match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().unwrap_or(&"").to_string(),
+    })
+}
+
+/// Vault with `TOKEN` whitelisted and every user registered.
+fn setup_accounts() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    for user in USERS {
+        call_with(user, NEAR, vec![]);
+        contract.storage_deposit(None, None);
+    }
+    contract
+}
+
+fn balance_of(contract: &Contract, user: &str) -> Option<u128> {
+    contract.get_deposits(account(user)).get(TOKEN).map(|amount| amount.0)
+}
+
+#[derive(Clone, Debug)]
+enum AccountOp {
+    Deposit(usize, u128),
+    Withdraw(usize, u128),
+    Unregister(usize),
+}
+
+fn account_op() -> impl Strategy<Value = AccountOp> {
+    prop_oneof![
+        (0..USERS.len(), 1..1_000_000_000u128).prop_map(|(user, amount)| AccountOp::Deposit(user, amount)),
+        (0..USERS.len(), 1..1_000_000_000u128).prop_map(|(user, amount)| AccountOp::Withdraw(user, amount)),
+        (0..USERS.len()).prop_map(AccountOp::Unregister),
+    ]
+}
+
+#[derive(Clone, Debug)]
+enum ShareOp {
+    Deposit(usize, u128),
+    Compound(u128),
+    /// Withdraws the given per mille of the user's shares.
+    Withdraw(usize, u128),
+}
+
+fn share_op() -> impl Strategy<Value = ShareOp> {
+    prop_oneof![
+        (0..USERS.len(), 1..1_000_000_000_000_000_000_000_000u128).prop_map(|(user, lp)| ShareOp::Deposit(user, lp)),
+        (1..1_000_000_000_000_000_000_000u128).prop_map(ShareOp::Compound),
+        (0..USERS.len(), 1..=1_000u128).prop_map(|(user, per_mille)| ShareOp::Withdraw(user, per_mille)),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn account_balances_follow_their_operations(ops in prop::collection::vec(account_op(), 1..40)) {
+        let mut contract = setup_accounts();
+        let mut model: HashMap<&str, Option<u128>> = USERS.iter().map(|user| (*user, None)).collect();
+        for op in ops {
+            match op {
+                AccountOp::Deposit(user, amount) => {
+                    let user = USERS[user];
+                    call(TOKEN);
+                    contract.ft_on_transfer(account(user), U128(amount), "".to_string());
+                    let balance = model.get_mut(user).unwrap();
+                    *balance = Some(balance.unwrap_or(0) + amount);
+                }
+                AccountOp::Withdraw(user, amount) => {
+                    let user = USERS[user];
+                    let balance = model[user];
+                    call_with(user, 1, vec![]);
+                    let failure = panic_message(|| {
+                        contract.withdraw(account(TOKEN), U128(amount), None);
+                    });
+                    match balance {
+                        Some(balance) if balance >= amount => {
+                            prop_assert_eq!(failure, None);
+                            model.insert(user, Some(balance - amount));
+                        }
+                        Some(_) => prop_assert!(failure.unwrap().contains("E22: not enough tokens in deposit")),
+                        None => prop_assert!(failure.unwrap().contains("E21: token not registered")),
+                    }
+                }
+                AccountOp::Unregister(user) => {
+                    let user = USERS[user];
+                    call_with(user, 1, vec![]);
+                    let failure = panic_message(|| {
+                        contract.unregister_tokens(vec![account(TOKEN)]);
+                    });
+                    if model[user].unwrap_or(0) == 0 {
+                        prop_assert_eq!(failure, None);
+                        model.insert(user, None);
+                    } else {
+                        prop_assert!(failure.unwrap().contains("E24: non-zero token balance"));
+                    }
+                }
+            }
+            for user in USERS {
+                prop_assert_eq!(balance_of(&contract, user), model[user]);
+            }
+        }
+    }
+
+    #[test]
+    fn deposits_overflowing_a_balance_fail(first in 1..=u128::MAX, second in 1..=u128::MAX) {
+        let mut contract = setup_accounts();
+        call(TOKEN);
+        contract.ft_on_transfer(account(USERS[0]), U128(first), "".to_string());
+        call(TOKEN);
+        let failure = panic_message(|| {
+            contract.ft_on_transfer(account(USERS[0]), U128(second), "".to_string());
+        });
+        match first.checked_add(second) {
+            Some(total) => {
+                prop_assert_eq!(failure, None);
+                prop_assert_eq!(balance_of(&contract, USERS[0]), Some(total));
+            }
+            None => {
+                prop_assert!(failure.unwrap().contains("E40: arithmetic overflow"));
+                prop_assert_eq!(balance_of(&contract, USERS[0]), Some(first));
+            }
+        }
+    }
+
+    #[test]
+    fn forced_unregister_keeps_other_accounts(amount in 1..1_000_000_000u128, lp in 1..1_000_000u128) {
+        let mut contract = setup_accounts();
+        call(TOKEN);
+        contract.ft_on_transfer(account(USERS[0]), U128(amount), "".to_string());
+        deposit(&mut contract, USERS[0], lp);
+        deposit(&mut contract, USERS[1], 2 * lp);
+        let shares = contract.get_user_shares(account(USERS[0]));
+        let other_shares = contract.get_user_shares(account(USERS[1]));
+
+        call_with(USERS[0], 1, vec![]);
+        prop_assert!(contract.storage_unregister(Some(true)));
+        let lostfound = contract.get_account_lostfound(account(USERS[0]));
+        prop_assert_eq!(lostfound.get(TOKEN), Some(&U128(amount)));
+        prop_assert_eq!(contract.get_lostfound_shares(account(USERS[0])), shares);
+        prop_assert_eq!(contract.get_user_shares(account(USERS[1])), other_shares);
+        prop_assert_eq!(contract.get_vault_info().total_shares.0, shares.0 + other_shares.0);
+    }
+
+    #[test]
+    fn conversions_round_down(
+        lp in 0..1_000_000_000_000_000_000_000_000u128,
+        shares in 0..1_000_000_000_000_000_000_000_000u128,
+        deposited in 1..1_000_000_000_000_000_000_000_000u128,
+        donation in 0..1_000_000_000_000_000_000_000_000u128,
+    ) {
+        let mut contract = setup();
+        deposit(&mut contract, USERS[0], deposited);
+        if donation > 0 {
+            mint(&mut contract, ShareRecipient::Compound, donation);
+        }
+
+        let minted = contract.preview_deposit(U128(lp)).0;
+        prop_assert!(contract.preview_redeem(U128(minted)).0 <= lp);
+        let redeemed = contract.preview_redeem(U128(shares)).0;
+        prop_assert!(contract.preview_deposit(U128(redeemed)).0 <= shares);
+        prop_assert!(contract.preview_deposit(U128(lp + 1)).0 >= minted);
+    }
+
+    #[test]
+    fn shares_never_redeem_more_than_the_vault_holds(ops in prop::collection::vec(share_op(), 1..30)) {
+        let mut contract = setup();
+        for op in ops {
+            match op {
+                ShareOp::Deposit(user, lp) => {
+                    let minted = deposit(&mut contract, USERS[user], lp);
+                    prop_assert!(contract.preview_redeem(U128(minted)).0 <= lp);
+                }
+                ShareOp::Compound(lp) => {
+                    mint(&mut contract, ShareRecipient::Compound, lp);
+                }
+                ShareOp::Withdraw(user, per_mille) => {
+                    let shares = contract.get_user_shares(account(USERS[user])).0 * per_mille / 1_000;
+                    if shares == 0 {
+                        continue;
+                    }
+                    let lp = contract.preview_redeem(U128(shares)).0;
+                    let total_lp = contract.get_vault_info().total_lp.0;
+                    call(USERS[user]);
+                    contract.withdraw_all(U128(shares), None, None);
+                    prop_assert_eq!(contract.get_vault_info().total_lp.0, total_lp - lp);
+                }
+            }
+            let info = contract.get_vault_info();
+            let user_shares: Vec<u128> = USERS.iter().map(|user| contract.get_user_shares(account(user)).0).collect();
+            prop_assert_eq!(user_shares.iter().sum::<u128>(), info.total_shares.0);
+            let redeemable: u128 = user_shares.iter().map(|shares| contract.preview_redeem(U128(*shares)).0).sum();
+            prop_assert!(redeemable <= info.free_lp.0);
+        }
+    }
+}
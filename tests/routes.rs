@@ -0,0 +1,141 @@
+//! Route registry and multi-hop swaps.
+
+mod common;
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::PromiseResult;
+use vault_contract::{Contract, RouteHop, ShareRecipient};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const TOKEN: &str = "token.testnet";
+const WRAP: &str = "wrap.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+/// Pool trading `TOKEN` for wNEAR.
+const TOKEN_POOL_ID: u64 = 7;
+
+fn hop(pool_id: u64, token_out: &str) -> RouteHop {
+    RouteHop { pool_id, token_out: token_out.to_string() }
+}
+
+fn pool(tokens: [&str; 2], amounts: [u128; 2], total_supply: u128) -> PromiseResult {
+    ok(json!({
+        "token_account_ids": tokens,
+        "amounts": [amounts[0].to_string(), amounts[1].to_string()],
+        "total_fee": 0,
+        "shares_total_supply": total_supply.to_string(),
+    }))
+}
+
+fn set_route(contract: &mut Contract, token_out: &str, hops: Vec<RouteHop>) {
+    call(OWNER);
+    let change_id = contract.set_route(account(TOKEN), account(token_out), hops);
+    apply_change(contract, change_id);
+}
+
+/// Vault routing `TOKEN` to both pool tokens through wNEAR.
+fn setup_routes() -> Contract {
+    let mut contract = setup();
+    set_route(&mut contract, DAI, vec![hop(TOKEN_POOL_ID, WRAP), hop(84, DAI)]);
+    set_route(&mut contract, ETH, vec![hop(TOKEN_POOL_ID, WRAP), hop(83, ETH)]);
+    contract
+}
+
+/// Actions of the swaps of the last call.
+fn swaps() -> Vec<Value> {
+    function_calls()
+        .into_iter()
+        .filter(|(method, _)| method == "swap")
+        .map(|(_, args)| args["actions"].clone())
+        .collect()
+}
+
+#[test]
+fn route_is_set_and_removed_through_the_timelock() {
+    let mut contract = setup_routes();
+    assert_eq!(contract.get_route(account(TOKEN), account(DAI)), Some(vec![hop(TOKEN_POOL_ID, WRAP), hop(84, DAI)]));
+    // the four direct wNEAR routes come first
+    let routes = contract.get_routes(4, 10);
+    assert_eq!(routes.len(), 2);
+    assert_eq!(routes[1].token_out, ETH);
+    assert_eq!(contract.get_routes(4, u64::MAX).len(), 2);
+
+    call(OWNER);
+    let change_id = contract.remove_route(account(TOKEN), account(DAI));
+    assert!(contract.get_route(account(TOKEN), account(DAI)).is_some());
+    apply_change(&mut contract, change_id);
+    assert_eq!(contract.get_route(account(TOKEN), account(DAI)), None);
+}
+
+#[test]
+fn zap_chains_the_hops_of_its_routes() {
+    let mut contract = setup_routes();
+    let deep = 10u128.pow(30);
+    call_with(VAULT, 0, vec![
+        pool([TOKEN, WRAP], [deep, deep], deep),
+        pool([WRAP, DAI], [deep, deep], deep),
+        pool([WRAP, ETH], [2 * deep, deep], deep),
+        pool([DAI, ETH], [1_000_000, 2_000_000], 10_000),
+    ]);
+    contract.callback_zap_quote(TOKEN.to_string(), U128(1_000), ShareRecipient::Compound, vec![TOKEN_POOL_ID, 84, 83, 193]);
+
+    let swaps = swaps();
+    assert_eq!(swaps.len(), 2);
+    for (actions, (pool_id, token_out)) in swaps.iter().zip([(84, DAI), (83, ETH)]) {
+        let actions = actions.as_array().unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0]["pool_id"], json!(TOKEN_POOL_ID));
+        assert_eq!(actions[0]["token_in"], json!(TOKEN));
+        assert_eq!(actions[0]["token_out"], json!(WRAP));
+        assert_eq!(actions[0]["min_amount_out"], json!("0"));
+        // the second hop takes the output of the first
+        assert_eq!(actions[1]["pool_id"], json!(pool_id));
+        assert_eq!(actions[1]["token_in"], json!(WRAP));
+        assert_eq!(actions[1]["amount_in"], Value::Null);
+        assert_eq!(actions[1]["token_out"], json!(token_out));
+    }
+    let amounts_in: u128 = swaps.iter().map(|actions| actions[0]["amount_in"].as_str().unwrap().parse::<u128>().unwrap()).sum();
+    assert_eq!(amounts_in, 1_000);
+}
+
+fn depositor() -> ShareRecipient {
+    ShareRecipient::Account { account_id: ALICE.to_string(), min_shares: U128(0) }
+}
+
+/// Withdrawal from the exchange scheduled by the last call.
+fn withdrawal() -> (Value, Value) {
+    let args = call_args("withdraw");
+    (args["token_id"].clone(), args["amount"].clone())
+}
+
+#[test]
+fn deposit_without_a_route_is_given_back() {
+    let mut contract = setup();
+    set_route(&mut contract, DAI, vec![hop(TOKEN_POOL_ID, WRAP), hop(84, DAI)]);
+    call_with(VAULT, 0, vec![ok(U128(1_000))]);
+    contract.callback_post_token_deposit(TOKEN.to_string(), U128(1_000), depositor());
+    assert_eq!(withdrawal(), (json!(TOKEN), json!("1000")));
+    assert_eq!(call_args("callback_post_dust_withdraw")["account_id"], json!(ALICE));
+    assert_eq!(contract.get_in_flight(account(TOKEN)), U128(1_000));
+}
+
+#[test]
+fn zap_whose_route_was_removed_is_given_back() {
+    let mut contract = setup_routes();
+    call(OWNER);
+    let change_id = contract.remove_route(account(TOKEN), account(ETH));
+    apply_change(&mut contract, change_id);
+    let deep = 10u128.pow(30);
+    call_with(VAULT, 0, vec![
+        pool([TOKEN, WRAP], [deep, deep], deep),
+        pool([WRAP, DAI], [deep, deep], deep),
+        pool([WRAP, ETH], [2 * deep, deep], deep),
+        pool([DAI, ETH], [1_000_000, 2_000_000], 10_000),
+    ]);
+    contract.callback_zap_quote(TOKEN.to_string(), U128(1_000), depositor(), vec![TOKEN_POOL_ID, 84, 83, 193]);
+    assert!(swaps().is_empty());
+    assert_eq!(withdrawal(), (json!(TOKEN), json!("1000")));
+}
@@ -0,0 +1,70 @@
+//! Share price protection on a nearly empty vault.
+
+mod common;
+
+use near_sdk::json_types::U128;
+use vault_contract::ShareRecipient;
+
+use common::*;
+
+const ATTACKER: &str = "attacker.testnet";
+const VICTIM: &str = "victim.testnet";
+
+#[test]
+fn first_deposit_redeems_its_lp() {
+    let mut contract = setup();
+    let shares = deposit(&mut contract, VICTIM, 1_000);
+    assert!(shares > 0);
+    assert_eq!(contract.get_user_shares(account(VICTIM)), U128(shares));
+    let lp = contract.preview_redeem(U128(shares)).0;
+    assert!((999..=1_000).contains(&lp), "redeems {} LP", lp);
+}
+
+#[test]
+fn donation_attack_is_unprofitable() {
+    let mut contract = setup();
+    // attacker mints the first shares with a single LP
+    let attacker_shares = deposit(&mut contract, ATTACKER, 1);
+
+    // and donates LP to the vault, which the next compounding counts as profit
+    let donation = 1_000_000;
+    mint(&mut contract, ShareRecipient::Compound, donation);
+    assert_eq!(contract.get_vault_info().total_lp, U128(1 + donation));
+
+    // the victim deposits less than the donation
+    let victim_lp = 100_000;
+    let victim_shares = deposit(&mut contract, VICTIM, victim_lp);
+    assert!(victim_shares > 0, "victim got no shares");
+
+    let victim_redeem = contract.preview_redeem(U128(victim_shares)).0;
+    assert!(victim_redeem * 100 >= victim_lp * 99, "victim redeems {} of {} LP", victim_redeem, victim_lp);
+
+    let attacker_redeem = contract.preview_redeem(U128(attacker_shares)).0;
+    let attacker_gain = attacker_redeem as i128 - (1 + donation) as i128;
+    let victim_loss = victim_lp as i128 - victim_redeem as i128;
+    assert!(attacker_gain < 0, "attacker gained {}", attacker_gain);
+    assert!(-attacker_gain > victim_loss * 100);
+}
+
+#[test]
+fn deposits_below_minimum_are_refunded() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_min_deposit(U128(1_000));
+    apply_change(&mut contract, change_id);
+
+    assert_eq!(deposit(&mut contract, VICTIM, 999), 0);
+    assert_eq!(contract.get_user_shares(account(VICTIM)), U128(0));
+    assert_eq!(contract.get_vault_info().total_shares, U128(0));
+
+    assert!(deposit(&mut contract, VICTIM, 1_000) > 0);
+    assert_eq!(contract.get_vault_info().total_lp, U128(1_000));
+}
+
+#[test]
+#[should_panic(expected = "E60: not allowed")]
+fn only_owner_sets_min_deposit() {
+    let mut contract = setup();
+    call(ATTACKER);
+    contract.set_min_deposit(U128(1_000));
+}
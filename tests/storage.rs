@@ -0,0 +1,189 @@
+mod common;
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::env;
+use near_sdk::json_types::U128;
+use near_sdk::PromiseResult;
+use vault_contract::{BurnRecord, Unwind};
+
+use common::*;
+
+/// Longest account id, the one the storage formula is written for.
+const USER: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.testnet";
+const TOKEN: &str = "tttttttttttttttttttttttttttttttttttttttttttttttttttttttt.testnet";
+/// Storage already used when a call starts, so rewriting a record doesn't underflow.
+const BASE_USAGE: u64 = 1_000_000;
+/// Entries reserved at registration: key, value and record overhead.
+const SHARES_STORAGE: u64 = 69 + 16 + 40;
+const PNL_STORAGE: u64 = 69 + 64 + 40;
+const PENDING_DEPOSIT_STORAGE: u64 = 69 + 24 + 40;
+/// A ledger entry is charged with the longest token id, a deposit records none.
+const NO_TOKEN_ID: u64 = 68;
+
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Runs `f` in a call made by `predecessor` and returns the storage it added, in bytes.
+fn storage_delta(predecessor: &str, deposit: u128, f: impl FnOnce()) -> u64 {
+    call_in(
+        context(predecessor).attached_deposit(deposit).storage_usage(BASE_USAGE),
+        vec![],
+    );
+    let before = env::storage_usage();
+    f();
+    env::storage_usage() - before
+}
+
+/// Storage charged to `USER`, in bytes.
+fn charged(contract: &vault_contract::Contract) -> u64 {
+    let state = contract.get_user_storage_state(account(USER)).unwrap();
+    (state.usage.0 / env::storage_byte_cost()) as u64
+}
+
+#[test]
+fn registration_is_charged() {
+    let mut contract = setup();
+    let used = storage_delta(USER, NEAR, || {
+        contract.storage_deposit(None, None);
+    });
+    assert_eq!(charged(&contract), used + SHARES_STORAGE + PNL_STORAGE + PENDING_DEPOSIT_STORAGE);
+}
+
+#[test]
+fn shares_pnl_and_history_are_charged() {
+    let mut contract = setup();
+    let mut used = storage_delta(USER, NEAR, || {
+        contract.storage_deposit(None, None);
+    });
+    call_in(context(VAULT).storage_usage(BASE_USAGE), vec![ok(U128(1_000))]);
+    let before = env::storage_usage();
+    deposit_in_call(&mut contract, USER);
+    used += env::storage_usage() - before;
+
+    assert!(contract.get_user_shares(account(USER)).0 > 0);
+    assert_eq!(contract.get_user_history(account(USER), 0, 10).len(), 1);
+    assert_eq!(charged(&contract), used + PENDING_DEPOSIT_STORAGE + NO_TOKEN_ID);
+}
+
+#[test]
+fn token_balances_are_charged() {
+    let mut contract = setup();
+    let mut used = storage_delta(USER, NEAR, || {
+        contract.storage_deposit(None, None);
+    });
+    used += storage_delta(USER, 1, || {
+        contract.register_tokens(vec![account(TOKEN)]);
+    });
+    assert_eq!(charged(&contract), used + SHARES_STORAGE + PNL_STORAGE + PENDING_DEPOSIT_STORAGE);
+}
+
+#[test]
+#[should_panic(expected = "E11: insufficient $NEAR storage deposit")]
+fn token_registration_needs_storage() {
+    let mut contract = setup();
+    let min = contract.storage_balance_bounds().min.0;
+    call_with(USER, min, vec![]);
+    contract.storage_deposit(None, Some(true));
+    call_with(USER, 1, vec![]);
+    contract.register_tokens(vec![account(TOKEN)]);
+}
+
+/// Registers `USER` with shares and a balance of `TOKEN`.
+fn setup_with_balances() -> vault_contract::Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call_with(USER, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call(TOKEN);
+    contract.ft_on_transfer(account(USER), U128(500), "".to_string());
+    deposit(&mut contract, USER, 1_000);
+    contract
+}
+
+#[test]
+#[should_panic(expected = "E18: account still holds tokens")]
+fn unregister_refuses_token_balances() {
+    let mut contract = setup_with_balances();
+    call_with(USER, 1, vec![]);
+    contract.storage_unregister(None);
+}
+
+#[test]
+#[should_panic(expected = "E19: account still holds shares")]
+fn unregister_refuses_shares() {
+    let mut contract = setup();
+    call_with(USER, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    deposit(&mut contract, USER, 1_000);
+    call_with(USER, 1, vec![]);
+    contract.storage_unregister(None);
+}
+
+#[test]
+fn forced_unregister_moves_tokens_and_shares_to_lostfound() {
+    let mut contract = setup_with_balances();
+    let info_before = contract.get_vault_info();
+    let shares = contract.get_user_shares(account(USER));
+    call_with(USER, 1, vec![]);
+    assert!(contract.storage_unregister(Some(true)));
+
+    assert!(contract.storage_balance_of(account(USER)).is_none());
+    assert_eq!(contract.get_user_shares(account(USER)), U128(0));
+    assert_eq!(contract.get_account_lostfound(account(USER)).get(TOKEN), Some(&U128(500)));
+    assert_eq!(contract.get_lostfound_shares(account(USER)), shares);
+    // the shares keep their LP, no profit is locked for the other depositors
+    let info = contract.get_vault_info();
+    assert_eq!(info.total_shares, info_before.total_shares);
+    assert_eq!(info.total_lp, info_before.total_lp);
+    assert_eq!(info.locked_profit, U128(0));
+    assert!(near_sdk::test_utils::get_logs()
+        .iter()
+        .any(|log| log.starts_with("EVENT_JSON:") && log.contains("\"storage_unregister\"")));
+}
+
+#[test]
+fn shares_of_a_forced_unregister_are_claimed_back() {
+    let mut contract = setup_with_balances();
+    let shares = contract.get_user_shares(account(USER));
+    let cost_basis = contract.get_user_pnl(account(USER)).cost_basis;
+    call_with(USER, 1, vec![]);
+    contract.storage_unregister(Some(true));
+
+    call_with(USER, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call_with(USER, NEAR, vec![]);
+    contract.claim_lostfound();
+    assert_eq!(contract.get_user_shares(account(USER)), shares);
+    assert_eq!(contract.get_user_pnl(account(USER)).cost_basis, cost_basis);
+    assert_eq!(contract.get_lostfound_shares(account(USER)), U128(0));
+}
+
+#[test]
+#[should_panic(expected = "E18: account still holds tokens")]
+fn forced_unregister_refuses_stranded_tokens() {
+    let mut contract = setup();
+    call_with(USER, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    let unwind = Unwind {
+        sender_id: USER.to_string(),
+        receiver_id: USER.to_string(),
+        output_token: None,
+        shares: U128(0),
+        burn: BurnRecord { value: U128(0), cost: U128(0) },
+    };
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_unwind_deliver(unwind, U128(500));
+    assert!(!contract.get_stranded(account(USER)).is_empty());
+    call_with(USER, 1, vec![]);
+    contract.storage_unregister(Some(true));
+}
+
+/// Completes a deposit of `account_id` in the current call.
+fn deposit_in_call(contract: &mut vault_contract::Contract, account_id: &str) {
+    contract.callback_get_pool_shares(
+        vault_contract::ShareRecipient::Account { account_id: account_id.to_string(), min_shares: U128(0) },
+        vec![U128(0), U128(0)],
+    );
+}
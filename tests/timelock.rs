@@ -0,0 +1,207 @@
+//! Timelock on configuration changes and who can queue or cancel them.
+
+mod common;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use near_sdk::json_types::{Base64VecU8, U128};
+use vault_contract::{AdminAction, Contract, RouteHop, RunningState};
+
+use common::*;
+
+const GUARDIAN: &str = "guardian.testnet";
+const ALICE: &str = "alice.testnet";
+const BOB: &str = "bob.testnet";
+const TOKEN: &str = "token.testnet";
+const WRAP: &str = "wrap.testnet";
+const ANYONE: &str = "anyone.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+const DAY: u64 = 24 * 60 * 60;
+
+fn setup_guardian() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.add_guardian(account(GUARDIAN));
+    apply_change(&mut contract, change_id);
+    contract
+}
+
+/// Vault whose council of `ALICE` and `BOB` needs one approval.
+fn setup_council() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_council(vec![account(ALICE), account(BOB)], 1, DAY);
+    apply_change(&mut contract, change_id);
+    contract
+}
+
+fn set_council() -> AdminAction {
+    AdminAction::SetCouncil { members: vec![account(OWNER)], threshold: 1, proposal_period_sec: DAY }
+}
+
+#[test]
+fn change_waits_for_the_minimum_delay() {
+    let mut contract = setup();
+    assert_eq!(contract.get_timelock_delay(), DAY);
+    call(OWNER);
+    let change_id = contract.set_min_deposit(U128(1_000));
+    let change = &contract.get_pending_changes(0, 10)[0];
+    assert_eq!(change.eta.0, DAY * 1_000_000_000);
+    assert!(!change.ready && !change.by_council);
+
+    advance(DAY);
+    call(ANYONE);
+    contract.execute_change(change_id);
+    assert!(contract.get_pending_changes(0, 10).is_empty());
+}
+
+#[test]
+#[should_panic(expected = "E102: timelock delay below the minimum")]
+fn delay_has_a_minimum() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.set_timelock_delay(DAY - 1);
+}
+
+#[test]
+fn delay_can_be_raised() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_timelock_delay(2 * DAY);
+    apply_change(&mut contract, change_id);
+    assert_eq!(contract.get_timelock_delay(), 2 * DAY);
+}
+
+#[test]
+#[should_panic(expected = "E100: only the council can queue this change")]
+fn owner_cant_queue_an_upgrade() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.queue_change(AdminAction::Upgrade { code: Base64VecU8(vec![0]) });
+}
+
+#[test]
+#[should_panic(expected = "E100: only the council can queue this change")]
+fn owner_cant_replace_the_council() {
+    let mut contract = setup_council();
+    call(OWNER);
+    contract.queue_change(set_council());
+}
+
+#[test]
+#[should_panic(expected = "E100: only the council can queue this change")]
+fn owner_council_change_expires_once_a_council_is_set() {
+    let mut contract = setup();
+    call(OWNER);
+    let first = contract.set_council(vec![account(ALICE)], 1, DAY);
+    let second = contract.queue_change(set_council()).unwrap();
+    apply_change(&mut contract, first);
+    apply_change(&mut contract, second);
+}
+
+#[test]
+fn council_replaces_itself() {
+    let mut contract = setup_council();
+    call_with(ALICE, NEAR, vec![]);
+    let proposal_id = contract.propose(set_council());
+    let change_id = contract.execute(proposal_id).unwrap();
+    assert!(contract.get_pending_changes(0, 10)[0].by_council);
+    apply_change(&mut contract, change_id);
+    assert_eq!(contract.get_council().members, vec![OWNER.to_string()]);
+}
+
+#[test]
+fn guardian_cancels_an_owner_change() {
+    let mut contract = setup_guardian();
+    call(OWNER);
+    let change_id = contract.set_min_deposit(U128(1_000));
+    call(GUARDIAN);
+    contract.cancel_change(change_id);
+    assert!(contract.get_pending_changes(0, 10).is_empty());
+}
+
+#[test]
+#[should_panic(expected = "E60: not allowed")]
+fn guardian_cant_cancel_its_removal() {
+    let mut contract = setup_guardian();
+    call(OWNER);
+    let change_id = contract.remove_guardian(account(GUARDIAN));
+    call(GUARDIAN);
+    contract.cancel_change(change_id);
+}
+
+#[test]
+#[should_panic(expected = "E101: changes approved by the council can't be cancelled")]
+fn council_change_cant_be_cancelled() {
+    let mut contract = setup_council();
+    call_with(ALICE, NEAR, vec![]);
+    let proposal_id = contract.propose(AdminAction::SetMinDeposit { min_deposit_lp: U128(1_000) });
+    let change_id = contract.execute(proposal_id).unwrap();
+    call(OWNER);
+    contract.cancel_change(change_id);
+}
+
+#[test]
+fn owner_cant_queue_changes_once_a_council_is_set() {
+    let mut contract = setup_council();
+    let actions = vec![
+        AdminAction::SetEpochMode { epoch_mode: true },
+        AdminAction::SetEpochDuration { duration_sec: DAY },
+        AdminAction::SetHarvestConfig { bounty_bps: 0, interval_sec: DAY, min_reward: U128(0) },
+        AdminAction::SetProfitUnlockPeriod { period_sec: DAY },
+        AdminAction::SetMinDeposit { min_deposit_lp: U128(1_000) },
+        AdminAction::SetDepositCaps { max_total_assets: Some(U128(1_000)), max_user_assets: None },
+        AdminAction::SetDepositLimit { token_id: account(TOKEN), limit: Some(U128(1_000)) },
+        AdminAction::SetWithdrawLimit { limit: Some(U128(1_000)), window_sec: DAY },
+        AdminAction::SetOracle { oracle_id: None, max_deviation_bps: 0, max_age_sec: 0 },
+        AdminAction::SetRoute {
+            token_in: account(TOKEN),
+            token_out: account(WRAP),
+            hops: vec![RouteHop { pool_id: 1, token_out: WRAP.to_string() }],
+        },
+        AdminAction::RemoveRoute { token_in: account(TOKEN), token_out: account(WRAP) },
+        AdminAction::ExtendWhitelistedTokens { tokens: vec![account(TOKEN)] },
+        AdminAction::RemoveWhitelistedTokens { tokens: vec![account(TOKEN)] },
+        AdminAction::AddGuardian { account_id: account(GUARDIAN) },
+        AdminAction::RemoveGuardian { account_id: account(GUARDIAN) },
+        AdminAction::SetTimelockDelay { delay_sec: 2 * DAY },
+        set_council(),
+        AdminAction::Upgrade { code: Base64VecU8(vec![0]) },
+    ];
+    for action in actions {
+        let name = format!("{:?}", action);
+        call(OWNER);
+        let failure = catch_unwind(AssertUnwindSafe(|| {
+            contract.queue_change(action);
+        }));
+        let message = failure.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.contains("E100: only the council can queue this change"), "{}: {}", name, message);
+    }
+    assert!(contract.get_pending_changes(0, 10).is_empty());
+}
+
+#[test]
+#[should_panic(expected = "E100: only the council can queue this change")]
+fn owner_setters_need_the_council_once_it_is_set() {
+    let mut contract = setup_council();
+    call(OWNER);
+    contract.set_harvest_config(0, DAY, U128(0));
+}
+
+#[test]
+fn owner_pauses_without_the_council() {
+    let mut contract = setup_council();
+    call(OWNER);
+    assert_eq!(contract.queue_change(AdminAction::ChangeState { state: RunningState::Paused }), None);
+}
+
+#[test]
+fn pending_changes_page_to_the_end() {
+    let mut contract = setup();
+    for min_deposit in [1_000, 2_000] {
+        call(OWNER);
+        contract.set_min_deposit(U128(min_deposit));
+    }
+    let page = contract.get_pending_changes(1, u64::MAX);
+    assert_eq!(page.iter().map(|change| change.id).collect::<Vec<_>>(), vec![1]);
+}
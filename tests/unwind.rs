@@ -0,0 +1,219 @@
+//! Unwinding shares: minimum amounts and recovery from failed steps.
+
+mod common;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::PromiseResult;
+use vault_contract::{BurnRecord, Contract, ShareRecipient, Unwind};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const BOB: &str = "bob.testnet";
+const WRAP: &str = "wrap.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+/// Pools read by an unwind into wNEAR: the strategy pool, wNEAR/DAI and wNEAR/ETH.
+const POOL_IDS: [u64; 3] = [193, 84, 83];
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+/// Shares minted for the first 1000 LP deposited, with the virtual shares.
+const SHARES: u128 = 1_000_000;
+
+fn pool(tokens: [&str; 2], amounts: [u128; 2], total_supply: u128) -> PromiseResult {
+    ok(json!({
+        "token_account_ids": tokens,
+        "amounts": [amounts[0].to_string(), amounts[1].to_string()],
+        "total_fee": 0,
+        "shares_total_supply": total_supply.to_string(),
+    }))
+}
+
+/// Strategy pool of 10000 LP holding 1000000 DAI and 2000000 ETH, and deep wNEAR pools
+/// trading 1 DAI for 1 wNEAR and 1 ETH for 2 wNEAR.
+fn pools() -> Vec<PromiseResult> {
+    let deep = 10u128.pow(30);
+    vec![
+        pool([DAI, ETH], [1_000_000, 2_000_000], 10_000),
+        pool([WRAP, DAI], [deep, deep], deep),
+        pool([WRAP, ETH], [2 * deep, deep], deep),
+    ]
+}
+
+fn unwind() -> Unwind {
+    Unwind {
+        sender_id: ALICE.to_string(),
+        receiver_id: ALICE.to_string(),
+        output_token: None,
+        shares: U128(SHARES),
+        burn: BurnRecord { value: U128(0), cost: U128(0) },
+    }
+}
+
+/// Vault in which `ALICE` burned all the `SHARES` of its 1000 LP to unwind them.
+fn setup_unwinding() -> (Contract, u128) {
+    let mut contract = setup();
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    let shares = deposit(&mut contract, ALICE, 1_000);
+    assert_eq!(shares, SHARES);
+    let lp = contract.preview_redeem(U128(shares)).0;
+    call(ALICE);
+    contract.withdraw_all(U128(shares), None, None);
+    assert_eq!(contract.get_user_shares(account(ALICE)), U128(0));
+    (contract, lp)
+}
+
+fn amounts(value: &near_sdk::serde_json::Value) -> Vec<u128> {
+    value.as_array().unwrap().iter().map(|amount| amount.as_str().unwrap().parse().unwrap()).collect()
+}
+
+#[test]
+fn removal_minimums_follow_the_pool() {
+    let (mut contract, lp) = setup_unwinding();
+    call_with(VAULT, 0, pools());
+    assert!(contract.callback_unwind_check(U128(lp), unwind(), POOL_IDS.to_vec()).is_some());
+
+    let remove = call_args("remove_liquidity");
+    let expected = [lp * 100, lp * 200];
+    assert_eq!(amounts(&remove["min_amounts"]), vec![expected[0] * 99 / 100, expected[1] * 99 / 100]);
+    // the swaps of the unwind need at least the quote of the minimum amounts, less slippage
+    let swaps = call_args("callback_unwind_swaps");
+    let min_amounts_out = amounts(&swaps["min_amounts_out"]);
+    assert!(min_amounts_out[0] > 0 && min_amounts_out[0] < expected[0]);
+    assert!(min_amounts_out[1] > 0 && min_amounts_out[1] < 2 * expected[1]);
+}
+
+#[test]
+fn small_withdrawal_has_small_minimums() {
+    let (mut contract, _) = setup_unwinding();
+    call_with(VAULT, 0, pools());
+    contract.callback_unwind_check(U128(1), unwind(), POOL_IDS.to_vec());
+    assert_eq!(amounts(&call_args("remove_liquidity")["min_amounts"]), vec![99, 198]);
+}
+
+#[test]
+fn failed_pool_read_gives_the_shares_back() {
+    let (mut contract, lp) = setup_unwinding();
+    let mut results = pools();
+    results[1] = PromiseResult::Failed;
+    call_with(VAULT, 0, results);
+    assert!(contract.callback_unwind_check(U128(lp), unwind(), POOL_IDS.to_vec()).is_none());
+    assert_eq!(contract.preview_redeem(contract.get_user_shares(account(ALICE))), U128(lp));
+}
+
+#[test]
+fn refund_gives_the_burned_shares_back_at_any_price() {
+    let (mut contract, lp) = setup_unwinding();
+    call_with(BOB, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    deposit(&mut contract, BOB, 1_000);
+    // the share price doubles while the unwind is in flight
+    mint(&mut contract, ShareRecipient::Compound, 1_000);
+    advance(30 * 24 * 3_600);
+    call(ALICE);
+    assert!(contract.preview_redeem(U128(SHARES)).0 > lp);
+
+    let mut results = pools();
+    results[1] = PromiseResult::Failed;
+    call_with(VAULT, 0, results);
+    contract.callback_unwind_check(U128(lp), unwind(), POOL_IDS.to_vec());
+    assert_eq!(contract.get_user_shares(account(ALICE)), U128(SHARES));
+}
+
+#[test]
+fn failed_removal_gives_the_shares_back_and_restakes() {
+    let (mut contract, lp) = setup_unwinding();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_unwind_swaps(U128(lp), unwind(), vec![U128(1), U128(1)]);
+    assert_eq!(contract.preview_redeem(contract.get_user_shares(account(ALICE))), U128(lp));
+
+    // the LP was unstaked before the removal failed and sits next to the idle 1000 LP
+    call_with(VAULT, 0, vec![ok(U128(1_000 + lp))]);
+    assert!(contract.callback_unwind_restake(U128(lp)).is_some());
+    assert_eq!(call_args("mft_transfer_call")["amount"], json!(lp.to_string()));
+}
+
+#[test]
+fn staked_lp_of_a_failed_removal_stays_staked() {
+    let (mut contract, lp) = setup_unwinding();
+    call_with(VAULT, 0, vec![ok(U128(1_000))]);
+    assert!(contract.callback_unwind_restake(U128(lp)).is_none());
+}
+
+#[test]
+fn swaps_carry_their_minimums() {
+    let (mut contract, _) = setup_unwinding();
+    call_with(VAULT, 0, vec![ok(vec![U128(100_000), U128(200_000)])]);
+    contract.callback_unwind_swaps(U128(1_000), unwind(), vec![U128(95_000), U128(390_000)]);
+    let mins: Vec<String> = function_calls()
+        .into_iter()
+        .filter(|(method, _)| method == "swap")
+        .map(|(_, args)| args["actions"][0]["min_amount_out"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(mins, vec!["95000", "390000"]);
+}
+
+#[test]
+fn failed_swap_keeps_its_input_for_the_sender() {
+    let (mut contract, _) = setup_unwinding();
+    call_with(VAULT, 0, vec![PromiseResult::Failed, ok(U128(400_000))]);
+    let swapped = vec![(DAI.to_string(), U128(100_000)), (ETH.to_string(), U128(200_000))];
+    assert!(contract.callback_unwind_withdraw(unwind(), U128(0), swapped).is_some());
+    assert_eq!(call_args("withdraw")["amount"], json!("400000"));
+    assert_eq!(contract.get_stranded(account(ALICE)).get(DAI), Some(&U128(100_000)));
+}
+
+#[test]
+fn failed_exchange_withdraw_keeps_the_output_for_the_sender() {
+    let (mut contract, _) = setup_unwinding();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    assert!(contract.callback_unwind_deliver(unwind(), U128(500)).is_none());
+    assert_eq!(contract.get_stranded(account(ALICE)).get(WRAP), Some(&U128(500)));
+}
+
+#[test]
+fn stranded_tokens_are_withdrawn_into_the_balance() {
+    let (mut contract, _) = setup_unwinding();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_unwind_deliver(unwind(), U128(500));
+
+    call(ALICE);
+    contract.withdraw_stranded();
+    assert!(contract.get_stranded(account(ALICE)).is_empty());
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_post_stranded_withdraw(ALICE.to_string(), WRAP.to_string(), U128(500));
+    assert_eq!(contract.get_stranded(account(ALICE)).get(WRAP), Some(&U128(500)));
+
+    call(ALICE);
+    contract.withdraw_stranded();
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_post_stranded_withdraw(ALICE.to_string(), WRAP.to_string(), U128(500));
+    assert_eq!(contract.get_deposits(account(ALICE)).get(WRAP), Some(&U128(500)));
+}
+
+#[test]
+#[should_panic(expected = "E30: no stranded tokens")]
+fn stranded_withdraw_needs_stranded_tokens() {
+    let mut contract = setup();
+    call(ALICE);
+    contract.withdraw_stranded();
+}
+
+#[test]
+fn failed_unwrap_credits_the_wnear() {
+    let (mut contract, _) = setup_unwinding();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    assert!(contract.callback_post_near_withdraw(unwind(), U128(700)).is_none());
+    assert_eq!(contract.get_deposits(account(ALICE)).get(WRAP), Some(&U128(700)));
+}
+
+#[test]
+fn failed_near_transfer_to_an_unknown_sender_is_kept_in_lostfound() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_post_near_transfer(unwind(), U128(700));
+    assert_eq!(contract.get_lostfound_near(account(ALICE)), U128(700));
+    assert!(contract.storage_balance_of(account(ALICE)).is_none());
+}
@@ -0,0 +1,168 @@
+//! Whitelist removal and the token metadata cache.
+
+mod common;
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::PromiseResult;
+use vault_contract::{BurnRecord, Contract, RouteHop, Unwind};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const TOKEN: &str = "token.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const WRAP: &str = "wrap.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// Vault whitelisting `TOKEN`, whose metadata was fetched.
+fn setup_whitelisted() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    assert!(function_calls().iter().any(|(method, _)| method == "ft_metadata"));
+    call_with(VAULT, 0, vec![ok(json!({ "spec": "ft-1.0.0", "name": "Token", "symbol": "TKN", "decimals": 18 }))]);
+    contract.callback_ft_metadata(TOKEN.to_string());
+    contract
+}
+
+fn is_whitelisted(contract: &Contract) -> bool {
+    contract.get_whitelisted_tokens_with_metadata().iter().any(|token| token.token_id == TOKEN)
+}
+
+fn remove(contract: &mut Contract) {
+    remove_token(contract, TOKEN);
+}
+
+fn remove_token(contract: &mut Contract, token_id: &str) {
+    call(OWNER);
+    let change_id = contract.remove_whitelisted_tokens(vec![account(token_id)]);
+    apply_change(contract, change_id);
+}
+
+/// Completes the withdraw of `ALICE`'s 500 `TOKEN`.
+fn complete_withdraw(contract: &mut Contract) {
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.exchange_callback_post_withdraw(TOKEN.to_string(), ALICE.to_string(), U128(500));
+}
+
+#[test]
+fn metadata_is_cached_until_removal() {
+    let mut contract = setup_whitelisted();
+    let tokens = contract.get_whitelisted_tokens_with_metadata();
+    let metadata = tokens.iter().find(|token| token.token_id == TOKEN).unwrap().metadata.as_ref().unwrap();
+    assert_eq!((metadata.symbol.as_str(), metadata.decimals), ("TKN", 18));
+
+    remove(&mut contract);
+    assert!(!is_whitelisted(&contract));
+    // a late metadata fetch doesn't bring the token back
+    call_with(VAULT, 0, vec![ok(json!({ "symbol": "TKN", "decimals": 18 }))]);
+    contract.callback_ft_metadata(TOKEN.to_string());
+    assert!(!is_whitelisted(&contract));
+}
+
+#[test]
+fn withdrawn_token_can_be_removed() {
+    let mut contract = setup_whitelisted();
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call(TOKEN);
+    contract.ft_on_transfer(account(ALICE), U128(500), "".to_string());
+    call_with(ALICE, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(500), None);
+    complete_withdraw(&mut contract);
+
+    remove(&mut contract);
+    assert!(!is_whitelisted(&contract));
+}
+
+#[test]
+fn claimed_lostfound_token_can_be_removed() {
+    let mut contract = setup_whitelisted();
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_post_dust_withdraw(ALICE.to_string(), TOKEN.to_string(), U128(500));
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call_with(ALICE, NEAR, vec![]);
+    contract.claim_lostfound();
+    call_with(ALICE, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(500), None);
+    complete_withdraw(&mut contract);
+
+    remove(&mut contract);
+    assert!(!is_whitelisted(&contract));
+}
+
+#[test]
+#[should_panic(expected = "E12: token not whitelisted")]
+fn token_is_removed_once() {
+    let mut contract = setup_whitelisted();
+    remove(&mut contract);
+    remove(&mut contract);
+}
+
+#[test]
+#[should_panic(expected = "E110: token used by the strategy or a route")]
+fn pool_token_is_not_removed() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(DAI)]);
+    apply_change(&mut contract, change_id);
+    remove_token(&mut contract, DAI);
+}
+
+#[test]
+#[should_panic(expected = "E110: token used by the strategy or a route")]
+fn wrap_is_not_removed() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(WRAP)]);
+    apply_change(&mut contract, change_id);
+    remove_token(&mut contract, WRAP);
+}
+
+#[test]
+#[should_panic(expected = "E110: token used by the strategy or a route")]
+fn token_of_a_route_is_not_removed() {
+    let mut contract = setup_whitelisted();
+    call(OWNER);
+    let hops = vec![RouteHop { pool_id: 10, token_out: DAI.to_string() }];
+    let change_id = contract.set_route(account(TOKEN), account(DAI), hops);
+    apply_change(&mut contract, change_id);
+    remove(&mut contract);
+}
+
+#[test]
+#[should_panic(expected = "E111: token stranded or in flight")]
+fn token_in_flight_is_not_removed() {
+    let mut contract = setup_whitelisted();
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call(TOKEN);
+    contract.ft_on_transfer(account(ALICE), U128(500), "".to_string());
+    call_with(ALICE, 1, vec![]);
+    contract.withdraw(account(TOKEN), U128(500), None);
+    remove(&mut contract);
+}
+
+#[test]
+#[should_panic(expected = "E111: token stranded or in flight")]
+fn stranded_token_is_not_removed() {
+    let mut contract = setup_whitelisted();
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    let unwind = Unwind {
+        sender_id: ALICE.to_string(),
+        receiver_id: ALICE.to_string(),
+        output_token: Some(TOKEN.to_string()),
+        shares: U128(0),
+        burn: BurnRecord { value: U128(0), cost: U128(0) },
+    };
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_unwind_deliver(unwind, U128(500));
+    assert_eq!(contract.get_stranded(account(ALICE)).get(TOKEN), Some(&U128(500)));
+    remove(&mut contract);
+}
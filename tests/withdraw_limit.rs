@@ -0,0 +1,189 @@
+//! Rate limited withdrawals and the withdraw queue.
+
+mod common;
+
+use near_sdk::json_types::U128;
+use near_sdk::PromiseOrValue;
+use vault_contract::{Contract, RouteHop};
+
+use common::*;
+
+const USER: &str = "user.testnet";
+const TOKEN: &str = "token.testnet";
+const POOL_TOKENS: [&str; 2] = ["dai.fakes.testnet", "eth.fakes.testnet"];
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+const WINDOW_SEC: u64 = 3_600;
+
+/// Vault limiting withdrawals to `limit` LP per hour, with `USER` holding the shares of 10000 LP.
+fn setup_limited(limit: u128) -> (Contract, u128) {
+    let mut contract = setup();
+    let shares = deposit(&mut contract, USER, 10_000);
+    call(OWNER);
+    let change_id = contract.set_withdraw_limit(Some(U128(limit)), WINDOW_SEC);
+    apply_change(&mut contract, change_id);
+    (contract, shares)
+}
+
+/// Whitelists `TOKEN` with a route to it from each pool token.
+fn route_to_token(contract: &mut Contract) {
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(contract, change_id);
+    for (pool_id, token_in) in POOL_TOKENS.iter().enumerate() {
+        let hops = vec![RouteHop { pool_id: pool_id as u64 + 10, token_out: TOKEN.to_string() }];
+        let change_id = contract.set_route(account(token_in), account(TOKEN), hops);
+        apply_change(contract, change_id);
+    }
+}
+
+fn queue_id(result: PromiseOrValue<Option<u64>>) -> Option<u64> {
+    match result {
+        PromiseOrValue::Promise(_) => None,
+        PromiseOrValue::Value(id) => id,
+    }
+}
+
+#[test]
+fn withdrawal_within_the_limit_is_unwound() {
+    let (mut contract, shares) = setup_limited(10_000);
+    call(USER);
+    assert_eq!(queue_id(contract.withdraw_all(U128(shares / 2), None, None)), None);
+    assert_eq!(contract.get_withdraw_window().queue_len, 0);
+}
+
+#[test]
+fn withdrawal_over_the_limit_is_queued() {
+    let (mut contract, shares) = setup_limited(1_000);
+    let lp = contract.preview_redeem(U128(shares / 2)).0;
+    call_with(USER, NEAR, vec![]);
+    assert_eq!(queue_id(contract.withdraw_all(U128(shares / 2), None, None)), Some(0));
+    let queued = contract.get_queued_withdraw(0).unwrap();
+    assert_eq!(queued.sender_id, USER);
+    assert_eq!(queued.lp, U128(lp));
+    assert_eq!(contract.get_withdraw_window().queue_len, 1);
+}
+
+#[test]
+#[should_panic(expected = "E11: insufficient $NEAR storage deposit")]
+fn queued_withdrawal_pays_its_storage() {
+    let (mut contract, shares) = setup_limited(1_000);
+    call(USER);
+    contract.withdraw_all(U128(shares / 2), None, None);
+}
+
+#[test]
+#[should_panic(expected = "E12: token not whitelisted")]
+fn output_token_is_checked_before_queueing() {
+    let (mut contract, shares) = setup_limited(1_000);
+    call_with(USER, NEAR, vec![]);
+    contract.withdraw_all(U128(shares / 2), Some(account(TOKEN)), None);
+}
+
+#[test]
+#[should_panic(expected = "E76: no route")]
+fn route_is_checked_before_queueing() {
+    let (mut contract, shares) = setup_limited(1_000);
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call_with(USER, NEAR, vec![]);
+    contract.withdraw_all(U128(shares / 2), Some(account(TOKEN)), None);
+}
+
+#[test]
+fn queue_waits_for_room_in_the_window() {
+    let (mut contract, shares) = setup_limited(1_000);
+    call(USER);
+    contract.withdraw_all(U128(shares * 8 / 100), None, None);
+    call_with(USER, NEAR, vec![]);
+    contract.withdraw_all(U128(shares * 8 / 100), None, None);
+    assert_eq!(contract.get_withdraw_window().queue_len, 1);
+
+    advance(WINDOW_SEC);
+    call(USER);
+    assert!(contract.process_withdraw_queue().is_some());
+    assert_eq!(contract.get_withdraw_window().queue_len, 0);
+    assert!(contract.get_queued_withdraw(0).is_none());
+}
+
+#[test]
+#[should_panic(expected = "E55: withdraw limit reached")]
+fn queue_is_not_processed_before_the_window_has_room() {
+    let (mut contract, shares) = setup_limited(1_000);
+    call(USER);
+    contract.withdraw_all(U128(shares * 8 / 100), None, None);
+    call_with(USER, NEAR, vec![]);
+    contract.withdraw_all(U128(shares * 8 / 100), None, None);
+    call(USER);
+    contract.process_withdraw_queue();
+}
+
+#[test]
+fn withdrawal_larger_than_the_limit_goes_out_a_window_at_a_time() {
+    let (mut contract, shares) = setup_limited(1_000);
+    let lp = contract.preview_redeem(U128(shares / 4)).0;
+    call_with(USER, NEAR, vec![]);
+    assert_eq!(queue_id(contract.withdraw_all(U128(shares / 4), None, None)), Some(0));
+
+    call(USER);
+    assert!(contract.process_withdraw_queue().is_some());
+    assert_eq!(contract.get_queued_withdraw(0).unwrap().lp, U128(lp - 1_000));
+    advance(WINDOW_SEC);
+    call(USER);
+    assert!(contract.process_withdraw_queue().is_some());
+    assert_eq!(contract.get_queued_withdraw(0).unwrap().lp, U128(lp - 2_000));
+    advance(WINDOW_SEC);
+    call(USER);
+    assert!(contract.process_withdraw_queue().is_some());
+    assert!(contract.get_queued_withdraw(0).is_none());
+    assert_eq!(contract.get_withdraw_window().queue_len, 0);
+}
+
+#[test]
+fn active_boost_is_not_lowered() {
+    let (mut contract, _) = setup_limited(1_000);
+    call(OWNER);
+    contract.raise_withdraw_limit(U128(5_000), 2 * WINDOW_SEC);
+    let boost_until = contract.get_withdraw_window().boost_until;
+    call(OWNER);
+    contract.raise_withdraw_limit(U128(100), WINDOW_SEC);
+    let window = contract.get_withdraw_window();
+    assert_eq!(window.boost, U128(5_000));
+    assert_eq!(window.boost_until, boost_until);
+    assert_eq!(window.limit, Some(U128(6_000)));
+}
+
+#[test]
+fn entry_that_lost_its_route_is_refunded() {
+    let (mut contract, shares) = setup_limited(1_000);
+    route_to_token(&mut contract);
+    call_with(USER, NEAR, vec![]);
+    assert_eq!(queue_id(contract.withdraw_all(U128(shares / 2), Some(account(TOKEN)), None)), Some(0));
+    assert_eq!(contract.get_user_shares(account(USER)), U128(shares - shares / 2));
+
+    call(OWNER);
+    let change_id = contract.remove_route(account(POOL_TOKENS[0]), account(TOKEN));
+    apply_change(&mut contract, change_id);
+    call(USER);
+    assert!(contract.process_withdraw_queue().is_none());
+    assert_eq!(contract.get_withdraw_window().queue_len, 0);
+    let refunded = contract.get_user_shares(account(USER)).0;
+    assert!(refunded > shares - shares / 2 && refunded <= shares);
+}
+
+#[test]
+#[should_panic(expected = "E58: withdraw limit window must not be 0")]
+fn limit_needs_a_window() {
+    let mut contract = setup();
+    call(OWNER);
+    contract.set_withdraw_limit(Some(U128(1_000)), 0);
+}
+
+#[test]
+fn unlimited_withdrawals_need_no_window() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_withdraw_limit(None, 0);
+    apply_change(&mut contract, change_id);
+    assert_eq!(contract.get_withdraw_window().limit, None);
+}
@@ -0,0 +1,279 @@
+//! Zap split, leftovers and failed swaps.
+
+mod common;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk::PromiseResult;
+use vault_contract::{optimal_split, Contract, ShareRecipient};
+
+use common::*;
+
+const ALICE: &str = "alice.testnet";
+const WRAP: &str = "wrap.testnet";
+const DAI: &str = "dai.fakes.testnet";
+const ETH: &str = "eth.fakes.testnet";
+const TOKEN: &str = "token.testnet";
+const NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+fn depositor() -> ShareRecipient {
+    ShareRecipient::Account { account_id: ALICE.to_string(), min_shares: U128(0) }
+}
+
+/// Strategy pool of 10000 LP holding 1000000 DAI and 2000000 ETH.
+fn strategy_pool() -> PromiseResult {
+    ok(json!({
+        "token_account_ids": [DAI, ETH],
+        "amounts": ["1000000", "2000000"],
+        "total_fee": 30,
+        "shares_total_supply": "10000",
+    }))
+}
+
+/// Tokens and amounts withdrawn from the exchange by the last call.
+fn withdrawals() -> Vec<(String, String)> {
+    function_calls()
+        .into_iter()
+        .filter(|(method, _)| method == "withdraw")
+        .map(|(_, args)| (args["token_id"].as_str().unwrap().to_string(), args["amount"].as_str().unwrap().to_string()))
+        .collect()
+}
+
+/// Output of a constant product pool with no fee.
+fn amount_out(reserve_in: u128, reserve_out: u128) -> impl Fn(u128) -> u128 {
+    move |amount_in| amount_in * reserve_out / (reserve_in + amount_in)
+}
+
+#[test]
+fn split_follows_the_pool_ratio() {
+    assert_eq!(optimal_split(1_000, 1, 1, |x| x, |x| x), 500);
+    assert_eq!(optimal_split(1_000, 1, 3, |x| x, |x| x), 250);
+    // a pool token worth twice as much needs twice the input
+    assert_eq!(optimal_split(900, 1, 1, |x| x / 2, |x| x), 600);
+}
+
+#[test]
+fn split_balances_curved_outputs() {
+    let (amount, reserve1, reserve2) = (10u128.pow(12), 2_000, 1);
+    let out1 = amount_out(10u128.pow(15), 2 * 10u128.pow(12));
+    let out2 = amount_out(10u128.pow(15), 10u128.pow(9));
+    let x = optimal_split(amount, reserve1, reserve2, &out1, &out2);
+    assert!(out1(x) * reserve2 >= out2(amount - x) * reserve1);
+    assert!(out1(x - 1) * reserve2 < out2(amount - x + 1) * reserve1);
+}
+
+#[test]
+fn split_of_an_empty_pool_is_even() {
+    assert_eq!(optimal_split(1_001, 0, 5, |x| x, |x| x), 500);
+    assert_eq!(optimal_split(1_000, 5, 0, |x| x, |x| x), 500);
+}
+
+#[test]
+fn depositor_gets_the_leftover_of_its_zap() {
+    let mut contract = setup();
+    // 10 LP take 1000 DAI and 2000 ETH
+    call_with(VAULT, 0, vec![strategy_pool()]);
+    contract.callback_zap_dust(depositor(), vec![U128(1_000), U128(2_500)], U128(10), false);
+    assert_eq!(withdrawals(), vec![(ETH.to_string(), "500".to_string())]);
+    assert_eq!(call_args("callback_post_dust_withdraw")["account_id"], json!(ALICE));
+}
+
+#[test]
+fn compounding_keeps_its_leftover_as_dust() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![strategy_pool()]);
+    contract.callback_zap_dust(ShareRecipient::Compound, vec![U128(1_300), U128(2_000)], U128(10), false);
+    let dust = contract.get_zap_dust();
+    assert_eq!(dust.get(DAI), Some(&U128(300)));
+    assert_eq!(dust.get(ETH), None);
+    assert!(withdrawals().is_empty());
+}
+
+#[test]
+fn refunded_zap_gives_back_the_removed_liquidity() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![ok(vec![U128(999), U128(1_999)]), strategy_pool()]);
+    contract.callback_zap_dust(depositor(), vec![U128(1_000), U128(2_500)], U128(10), true);
+    assert_eq!(
+        withdrawals(),
+        vec![(DAI.to_string(), "999".to_string()), (ETH.to_string(), "2499".to_string())]
+    );
+}
+
+#[test]
+fn zaps_in_flight_get_their_own_lp() {
+    let mut contract = setup();
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    // both zaps added liquidity before either callback ran
+    let minted = deposit(&mut contract, ALICE, 1_000);
+    let bob = ShareRecipient::Account { account_id: "bob.testnet".to_string(), min_shares: U128(0) };
+    let bob_minted = mint(&mut contract, bob, 3_000);
+    assert!(contract.preview_redeem(U128(minted)).0 <= 1_000);
+    assert!(contract.preview_redeem(U128(bob_minted)).0 >= 2_999);
+    assert_eq!(contract.get_vault_info().total_lp, U128(4_000));
+}
+
+#[test]
+fn failed_liquidity_gives_the_zap_back() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    assert_eq!(contract.callback_get_pool_shares(depositor(), vec![U128(1_000), U128(2_000)]), U128(0));
+    assert_eq!(
+        withdrawals(),
+        vec![(DAI.to_string(), "1000".to_string()), (ETH.to_string(), "2000".to_string())]
+    );
+    assert_eq!(contract.get_in_flight(account(DAI)), U128(1_000));
+    assert_eq!(contract.get_vault_info().total_lp, U128(0));
+}
+
+#[test]
+fn failed_compounding_liquidity_is_kept_as_dust() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_get_pool_shares(ShareRecipient::Compound, vec![U128(1_000), U128(2_000)]);
+    let dust = contract.get_zap_dust();
+    assert_eq!(dust.get(DAI), Some(&U128(1_000)));
+    assert_eq!(dust.get(ETH), Some(&U128(2_000)));
+}
+
+#[test]
+fn failed_pool_read_returns_nothing() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_zap_dust(ShareRecipient::Compound, vec![U128(1_000), U128(2_500)], U128(10), false);
+    assert!(contract.get_zap_dust().is_empty());
+}
+
+#[test]
+fn failed_swap_gives_the_zap_back() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![ok(U128(390)), PromiseResult::Failed]);
+    let added = contract.callback_zap_swaps(
+        depositor(),
+        WRAP.to_string(),
+        vec![U128(0), U128(0)],
+        vec![U128(400), U128(600)],
+    );
+    assert!(added.is_none());
+    assert_eq!(
+        withdrawals(),
+        vec![(DAI.to_string(), "390".to_string()), (WRAP.to_string(), "600".to_string())]
+    );
+}
+
+#[test]
+fn settlement_retries_what_its_failed_swap_left() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.set_epoch_mode(true);
+    apply_change(&mut contract, change_id);
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call_with(ALICE, 1_000, vec![]);
+    contract.deposit_near();
+    call(ALICE);
+    contract.add_to_vault();
+    call_with(VAULT, 0, vec![ok(())]);
+    contract.callback_epoch_wrap(ALICE.to_string(), U128(1_000));
+    call_with(VAULT, 0, vec![ok(U128(1_000))]);
+    contract.callback_queue_deposit(ALICE.to_string(), U128(1_000));
+    call(ALICE);
+    contract.settle_epoch();
+
+    call_with(VAULT, 0, vec![ok(U128(390)), PromiseResult::Failed]);
+    contract.callback_zap_swaps(
+        ShareRecipient::Epoch(0),
+        WRAP.to_string(),
+        vec![U128(0), U128(0)],
+        vec![U128(400), U128(600)],
+    );
+    let info = contract.get_epoch_info();
+    assert!(info.settle_aborted);
+    assert_eq!(info.retry_amount, U128(600));
+    assert_eq!(contract.get_zap_dust().get(DAI), Some(&U128(390)));
+
+    call(ALICE);
+    contract.settle_epoch();
+    assert_eq!(call_args("callback_zap_quote")["amount"], json!("600"));
+}
+
+#[test]
+fn failed_dust_withdraw_is_kept_for_the_depositor() {
+    let mut contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    contract.callback_post_dust_withdraw(ALICE.to_string(), ETH.to_string(), U128(500));
+    assert_eq!(contract.get_stranded(account(ALICE)).get(ETH), Some(&U128(500)));
+    assert!(contract.get_zap_dust().is_empty());
+}
+
+#[test]
+fn failed_pool_read_aborts_the_zap() {
+    let mut contract: Contract = setup();
+    call_with(VAULT, 0, vec![PromiseResult::Failed, strategy_pool(), strategy_pool()]);
+    assert!(contract
+        .callback_zap_quote(WRAP.to_string(), U128(1_000), depositor(), vec![84, 83, 193])
+        .is_some());
+    assert_eq!(withdrawals(), vec![(WRAP.to_string(), "1000".to_string())]);
+}
+
+/// Vault in which `ALICE` deposited 500 wNEAR to her balance and zaps 300 of them.
+fn setup_balance_zap() -> Contract {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(WRAP)]);
+    apply_change(&mut contract, change_id);
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    call(WRAP);
+    contract.ft_on_transfer(account(ALICE), U128(500), "".to_string());
+    call_with(ALICE, 1, vec![]);
+    contract.deposit_from_balance(account(WRAP), U128(300), U128(0));
+    assert_eq!(contract.get_deposits(account(ALICE)).get(WRAP), Some(&U128(200)));
+    assert_eq!(call_args("ft_transfer_call")["amount"], json!("300"));
+    contract
+}
+
+#[test]
+fn balance_refused_by_the_exchange_is_refunded() {
+    let mut contract = setup_balance_zap();
+    call_with(VAULT, 0, vec![ok(U128(100))]);
+    assert!(contract.callback_post_token_deposit(WRAP.to_string(), U128(300), depositor()).is_some());
+    assert_eq!(contract.get_deposits(account(ALICE)).get(WRAP), Some(&U128(400)));
+    assert_eq!(contract.get_in_flight(account(WRAP)), U128(0));
+}
+
+#[test]
+fn failed_balance_deposit_is_refunded() {
+    let mut contract = setup_balance_zap();
+    call_with(VAULT, 0, vec![PromiseResult::Failed]);
+    assert!(contract.callback_post_token_deposit(WRAP.to_string(), U128(300), depositor()).is_none());
+    assert_eq!(contract.get_deposits(account(ALICE)).get(WRAP), Some(&U128(500)));
+}
+
+#[test]
+fn balance_without_routes_is_not_debited() {
+    let mut contract = setup();
+    call(OWNER);
+    let change_id = contract.extend_whitelisted_tokens(vec![account(DAI), account(TOKEN)]);
+    apply_change(&mut contract, change_id);
+    call_with(ALICE, NEAR, vec![]);
+    contract.storage_deposit(None, None);
+    // DAI has no route to ETH, TOKEN none at all
+    for token_id in [DAI, TOKEN] {
+        call(token_id);
+        contract.ft_on_transfer(account(ALICE), U128(500), "".to_string());
+        call_with(ALICE, 1, vec![]);
+        let failure = catch_unwind(AssertUnwindSafe(|| {
+            contract.deposit_from_balance(account(token_id), U128(300), U128(0));
+        }));
+        let message = failure.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.contains("E76: no route"), "{}", message);
+        assert_eq!(contract.get_deposits(account(ALICE)).get(token_id), Some(&U128(500)));
+        assert_eq!(contract.get_in_flight(account(token_id)), U128(0));
+    }
+}